@@ -1,66 +1,122 @@
-
-pub struct BitField{
+/// One bit per piece, in wire order: piece 0 is the high bit of byte 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitField {
     inner: Vec<u8>,
     size: usize,
 }
 
 impl BitField {
-    pub fn new(size) -> BitField {
+    pub fn new(size: usize) -> BitField {
         BitField {
             inner: vec![0; (size + 7) / 8],
-            size
+            size,
         }
     }
 
-    pub get_first_unset_from(idx: usize) -> usize {
-        let start = idx / 8;
-        let offset = idx % 8;
-        let byte = self.inner[start..];
-        if self.inner[start] != 0xff {
-            for x in 0..(8 - offset) {
-                if self.get(idx + x) {
-                    return Some(idx + x);
-                }
-            }
-        }
-        for byte in start..self.inner.len() {
-            if let Some(x) = self.get_unset_in_byte(byte) {
-                return Some(8 * byte + x)
-            }
-        }
-        // Special handling for final partial byte
-        for byte in 0..start {
-            self.get_unset_in_byte(byte)
-            if let Some(x) = self.get_unset_in_byte(byte) {
-                return Some(8 * byte + x)
-            }
+    /// Reads a bitfield as sent in a `bitfield` message.  Fails if it is
+    /// the wrong length or has spare bits set.
+    pub fn from_bytes(bytes: &[u8], size: usize) -> Option<BitField> {
+        let field = BitField {
+            inner: bytes.to_vec(),
+            size,
+        };
+        if bytes.len() != (size + 7) / 8 || (size..bytes.len() * 8).any(|idx| field.get(idx)) {
+            None
+        } else {
+            Some(field)
         }
     }
 
-    pub get(&self, idx: usize) -> bool {
+    pub fn full(size: usize) -> BitField {
+        let mut field = BitField::new(size);
+        field.set_all(true);
+        field
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The first unset bit at or after `idx`, wrapping around to the
+    /// start.  An `idx` past the end starts from the start.
+    pub fn get_first_unset_from(&self, idx: usize) -> Option<usize> {
+        let idx = if idx < self.size { idx } else { 0 };
+        (idx..self.size)
+            .chain(0..idx)
+            .find(|&x| self.inner[x / 8] != 0xff && !self.get(x))
+    }
+
+    pub fn get(&self, idx: usize) -> bool {
         let byte = idx / 8;
         self.inner[byte] & self.get_mask(idx) > 0
     }
 
-    pub set(&mut self, idx: usize, value: bool) {
+    pub fn set(&mut self, idx: usize, value: bool) {
         let byte = idx / 8;
-        self.inner[byte] |= self.get_mask(idx)
+        if value {
+            self.inner[byte] |= self.get_mask(idx)
+        } else {
+            self.inner[byte] &= !self.get_mask(idx)
+        }
     }
 
-    fn get_unset_in_byte(&self, byte: usize) -> Option<usize> {
-        if byte == 0xff {
-            None
-        } else {
-            for offset in 0..8 {
-                if byte & (1 << offset) {
-                    return Some(offset);
-                }
-            }
-            unreachable!()
+    pub fn set_all(&mut self, value: bool) {
+        for idx in 0..self.size {
+            self.set(idx, value);
         }
     }
 
+    /// The number of set bits.
+    pub fn count(&self) -> usize {
+        self.inner.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn all(&self) -> bool {
+        self.count() == self.size
+    }
+
+    pub fn none(&self) -> bool {
+        self.count() == 0
+    }
+
     fn get_mask(&self, idx: usize) -> u8 {
-        1 << (idx % 8) 
+        0x80 >> (idx % 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_order() {
+        let mut field = BitField::new(10);
+        field.set(0, true);
+        field.set(9, true);
+        assert_eq!(field.as_bytes(), &[0x80, 0x40]);
+        assert_eq!(field.count(), 2);
+        field.set(0, false);
+        assert_eq!(field.get_first_unset_from(9), Some(0));
+        assert_eq!(BitField::from_bytes(&[0x80, 0x40], 10), Some({
+            let mut f = field.clone();
+            f.set(0, true);
+            f
+        }));
+        // Spare bits at the end must be clear.
+        assert_eq!(BitField::from_bytes(&[0x80, 0x20], 10), None);
+        assert!(BitField::full(10).all());
+        assert_eq!(BitField::full(10).get_first_unset_from(3), None);
+        // Past the end, but not past the spare bits.
+        assert_eq!(field.get_first_unset_from(14), Some(0));
+        assert_eq!(BitField::full(10).get_first_unset_from(14), None);
+        assert_eq!(BitField::new(0).get_first_unset_from(3), None);
     }
 }
@@ -0,0 +1,201 @@
+//! Deciding whom to upload to.
+//!
+//! Every `CHOKE_INTERVAL`, the interested peers that give us the most are
+//! unchoked: those we download fastest from while we're leeching, or those
+//! we upload fastest to once we're seeding.  One more slot goes to a
+//! random peer, rotated every `OPTIMISTIC_INTERVAL`, so that new peers get
+//! a chance to show what they can do.
+
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use peer::Peer;
+
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Unchoked peers, not counting the optimistic unchoke.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+// Picks the optimistic unchoke, as an index among `n` candidates.
+fn random_pick(n: usize) -> usize {
+    rand::thread_rng().gen_range(0, n)
+}
+
+pub struct Choker {
+    slots: usize,
+    last_run: Option<Instant>,
+    optimistic: Option<SocketAddr>,
+    last_optimistic: Option<Instant>,
+    pick: fn(usize) -> usize,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Choker {
+        Choker {
+            slots,
+            last_run: None,
+            optimistic: None,
+            last_optimistic: None,
+            pick: random_pick,
+        }
+    }
+
+    /// The peer holding the optimistic unchoke slot, if any.
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// Runs the choker if it's due.  Returns the messages to send, as
+    /// indexes into `peers` with the messages for each.
+    pub fn tick(
+        &mut self,
+        peers: &mut [Peer],
+        seeding: bool,
+        now: Instant,
+    ) -> Vec<(usize, Vec<Vec<u8>>)> {
+        match self.last_run {
+            Some(last_run) if now.duration_since(last_run) < CHOKE_INTERVAL => Vec::new(),
+            _ => self.run(peers, seeding, now),
+        }
+    }
+
+    /// Runs the choker now.
+    pub fn run(
+        &mut self,
+        peers: &mut [Peer],
+        seeding: bool,
+        now: Instant,
+    ) -> Vec<(usize, Vec<Vec<u8>>)> {
+        self.last_run = Some(now);
+        let mut interested: Vec<(usize, f64)> = peers
+            .iter_mut()
+            .enumerate()
+            .filter(|&(_, ref peer)| peer.peer_interested)
+            .map(|(i, peer)| {
+                let rate = if seeding {
+                    peer.upload_rate.rate(now)
+                } else {
+                    peer.download_rate.rate(now)
+                };
+                (i, rate)
+            })
+            .collect();
+        interested.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        let mut unchoke: Vec<usize> = interested
+            .iter()
+            .take(self.slots)
+            .map(|&(i, _)| i)
+            .collect();
+
+        // Everyone who's interested and didn't make the cut has a shot at
+        // the optimistic slot.
+        let candidates: Vec<usize> = interested
+            .iter()
+            .skip(self.slots)
+            .map(|&(i, _)| i)
+            .collect();
+        let current = self.optimistic
+            .and_then(|addr| candidates.iter().find(|&&i| peers[i].addr == addr).cloned());
+        let due = self.last_optimistic
+            .map_or(true, |last| now.duration_since(last) >= OPTIMISTIC_INTERVAL);
+        let optimistic = match current {
+            Some(i) if !due => Some(i),
+            _ if candidates.is_empty() => None,
+            _ => {
+                self.last_optimistic = Some(now);
+                Some(candidates[(self.pick)(candidates.len())])
+            }
+        };
+        self.optimistic = optimistic.map(|i| peers[i].addr);
+        unchoke.extend(optimistic);
+
+        let mut messages = Vec::new();
+        for (i, peer) in peers.iter_mut().enumerate() {
+            let sent = if unchoke.contains(&i) {
+                peer.unchoke()
+            } else {
+                peer.choke()
+            };
+            if !sent.is_empty() {
+                messages.push((i, sent));
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer::RATE_WINDOW;
+    use peermsg::{self, Handshake};
+
+    fn peers(rates: &[u64], now: Instant) -> Vec<Peer> {
+        rates
+            .iter()
+            .enumerate()
+            .map(|(i, &rate)| {
+                let handshake = Handshake {
+                    reserved: [0; 8],
+                    info_hash: vec![0xaa; 20],
+                    peer_id: vec![i as u8; 20],
+                };
+                let addr = format!("10.0.0.{}:6881", i + 1).parse().unwrap();
+                let mut peer = Peer::new(addr, &handshake, 10);
+                peer.peer_interested = true;
+                peer.download_rate.add(rate, now);
+                peer.upload_rate.add(1000 - rate, now);
+                peer
+            })
+            .collect()
+    }
+
+    fn unchoked(peers: &[Peer]) -> Vec<usize> {
+        (0..peers.len()).filter(|&i| !peers[i].am_choking).collect()
+    }
+
+    #[test]
+    fn tit_for_tat() {
+        let now = Instant::now();
+        let mut peers = peers(&[100, 500, 300, 0, 200], now);
+        peers[4].peer_interested = false;
+        let mut choker = Choker::new(2);
+        choker.pick = |_| 0;
+
+        // 1 and 2 are fastest; 0 and 3 are left for the optimistic slot.
+        let messages = choker.tick(&mut peers, false, now);
+        let unchoke = vec![peermsg::unchoke()];
+        assert_eq!(
+            messages,
+            vec![(0, unchoke.clone()), (1, unchoke.clone()), (2, unchoke.clone())]
+        );
+        assert_eq!(choker.optimistic(), Some(peers[0].addr));
+        assert_eq!(unchoked(&peers), vec![0, 1, 2]);
+
+        // Too soon to run again.
+        assert!(choker.tick(&mut peers, false, now + Duration::from_secs(5)).is_empty());
+
+        // Seeding ranks by upload rate instead, which here runs the other
+        // way: 3 and 0 are now the fastest, and 0 loses its optimistic slot
+        // to 2, the first of the rest.
+        let later = now + CHOKE_INTERVAL;
+        let messages = choker.tick(&mut peers, true, later);
+        assert_eq!(messages, vec![(1, vec![peermsg::choke()]), (3, unchoke)]);
+        assert_eq!(choker.optimistic(), Some(peers[2].addr));
+        assert_eq!(unchoked(&peers), vec![0, 2, 3]);
+
+        // The new optimistic unchoke keeps its slot until its time is up,
+        // though another pick would now land on 1.
+        choker.pick = |n| n - 1;
+        for peer in &mut peers {
+            let rate = peer.upload_rate.rate(later) as u64;
+            peer.upload_rate.add(rate * RATE_WINDOW.as_secs(), later);
+        }
+        assert!(choker.tick(&mut peers, true, later + CHOKE_INTERVAL).is_empty());
+        assert_eq!(choker.optimistic(), Some(peers[2].addr));
+    }
+}
@@ -0,0 +1,60 @@
+//! Compact peer addresses: 4 bytes of IPv4 address or 16 of IPv6, then a
+//! 2 byte port, all big-endian.  Used by trackers, PEX and the DHT.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub fn push_addr(out: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => out.extend(&ip.octets()),
+        IpAddr::V6(ip) => out.extend(&ip.octets()),
+    }
+    out.push((addr.port() >> 8) as u8);
+    out.push(addr.port() as u8);
+}
+
+pub fn read_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let port = |b: &[u8]| (b[0] as u16) << 8 | b[1] as u16;
+    match bytes.len() {
+        6 => Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+            port(&bytes[4..]),
+        )),
+        18 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(&bytes[16..])))
+        }
+        _ => None,
+    }
+}
+
+/// Splits a string of compact IPv4 addresses.  Trailing junk is ignored.
+pub fn read_v4_list(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks(6).filter_map(read_addr).collect()
+}
+
+/// Splits a string of compact IPv6 addresses.  Trailing junk is ignored.
+pub fn read_v6_list(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks(18).filter_map(read_addr).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let v4: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let mut buf = vec![];
+        push_addr(&mut buf, &v4);
+        assert_eq!(buf, b"\x0a\x01\x02\x03\x1a\xe1".to_vec());
+        push_addr(&mut buf, &v4);
+        assert_eq!(read_v4_list(&buf), vec![v4, v4]);
+
+        let mut buf = vec![];
+        push_addr(&mut buf, &v6);
+        assert_eq!(buf.len(), 18);
+        assert_eq!(read_v6_list(&buf), vec![v6]);
+    }
+}
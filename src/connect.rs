@@ -0,0 +1,56 @@
+//! The queue of peers we've heard about and might connect to.
+
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Addresses waiting for an outbound connection.
+///
+/// Clones share the same queue, so trackers, PEX and the DHT can each hold
+/// one and feed it.  An address is only queued once, however many sources
+/// mention it.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    queue: VecDeque<SocketAddr>,
+    seen: HashSet<SocketAddr>,
+}
+
+impl ConnectQueue {
+    pub fn new() -> ConnectQueue {
+        ConnectQueue::default()
+    }
+
+    /// Queues an address, unless it has been queued before.  Returns
+    /// whether it was new.
+    pub fn push(&self, addr: SocketAddr) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.seen.insert(addr) {
+            inner.queue.push_back(addr);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn pop(&self) -> Option<SocketAddr> {
+        self.inner.lock().unwrap().queue.pop_front()
+    }
+
+    /// Lets an address be queued again, as when a connection to it closes.
+    pub fn forget(&self, addr: &SocketAddr) {
+        self.inner.lock().unwrap().seen.remove(addr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
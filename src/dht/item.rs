@@ -0,0 +1,258 @@
+//! Arbitrary data in the DHT (BEP 44).
+//!
+//! Immutable items are stored under the SHA-1 of their bencoded value.
+//! Mutable items are stored under the SHA-1 of an ed25519 public key and an
+//! optional salt, and carry a signature over the value and a sequence
+//! number, so only the key's owner can update them.
+
+use std::convert::TryFrom;
+use std::io;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand;
+use serde_bencode::de::from_bytes;
+use serde_bencode::value::Value;
+use sha1::Sha1;
+
+use dht::NodeId;
+
+/// The largest bencoded value a node will store.
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+// Error codes for rejected puts.
+pub const ERROR_TOO_BIG: i64 = 205;
+pub const ERROR_BAD_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_OLD_SEQ: i64 = 302;
+
+fn sha1(bytes: &[u8]) -> NodeId {
+    let mut sha = Sha1::new();
+    sha.update(bytes);
+    NodeId::from_bytes(&sha.digest().bytes()).expect("20 bytes")
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+/// Checks that `value` is one bencoded value, and re-encodes it the
+/// canonical way, with dict keys sorted.
+pub fn canonical_value(value: &[u8]) -> io::Result<Vec<u8>> {
+    let value: Value = from_bytes(value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "value is not bencoded"))?;
+    Ok(::serde_bencode::ser::to_bytes(&value).expect("value always serializes"))
+}
+
+/// A new random signing key.
+pub fn generate_keypair() -> Keypair {
+    let seed: [u8; 32] = rand::random();
+    keypair_from_seed(&seed)
+}
+
+/// The signing key for a 32-byte ed25519 seed.
+pub fn keypair_from_seed(seed: &[u8; 32]) -> Keypair {
+    let secret = SecretKey::from_bytes(seed).expect("seed is 32 bytes");
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+/// The DHT target for a mutable item.
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut buf = key.to_vec();
+    buf.extend(salt);
+    sha1(&buf)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    /// The bencoded value.
+    pub value: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl MutableItem {
+    /// Signs `value`, which must already be bencoded.
+    pub fn sign(keypair: &Keypair, salt: &[u8], seq: i64, value: Vec<u8>) -> MutableItem {
+        let signature = keypair
+            .sign(&MutableItem::signed_bytes(salt, seq, &value))
+            .to_bytes()
+            .to_vec();
+        MutableItem {
+            key: keypair.public.to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature,
+        }
+    }
+
+    /// What the signature covers: the salt, seq and value, bencoded as if
+    /// they were the body of a dict.
+    fn signed_bytes(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if !salt.is_empty() {
+            buf.extend(b"4:salt");
+            push_bytes(&mut buf, salt);
+        }
+        buf.extend(format!("3:seqi{}e1:v", seq).as_bytes());
+        buf.extend(value);
+        buf
+    }
+
+    pub fn verify(&self) -> bool {
+        let key = match PublicKey::from_bytes(&self.key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::try_from(&self.signature[..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        key.verify(&MutableItem::signed_bytes(&self.salt, self.seq, &self.value), &signature)
+            .is_ok()
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.key, &self.salt)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    /// A bencoded value.
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    pub fn target(&self) -> NodeId {
+        match *self {
+            Item::Immutable(ref value) => sha1(value),
+            Item::Mutable(ref item) => item.target(),
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match *self {
+            Item::Immutable(ref value) => value,
+            Item::Mutable(ref item) => &item.value,
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        match *self {
+            Item::Immutable(_) => None,
+            Item::Mutable(ref item) => Some(item.seq),
+        }
+    }
+
+    /// Checks an item before storing it, returning the KRPC error to send
+    /// if it's no good.
+    pub fn check(&self) -> Result<(), (i64, &'static str)> {
+        if self.value().len() > MAX_VALUE_SIZE {
+            return Err((ERROR_TOO_BIG, "message (v field) too big"));
+        }
+        if let Item::Mutable(ref item) = *self {
+            if item.salt.len() > MAX_SALT_SIZE {
+                return Err((ERROR_SALT_TOO_BIG, "salt (salt field) too big"));
+            }
+            if !item.verify() {
+                return Err((ERROR_BAD_SIGNATURE, "invalid signature"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::ExpandedSecretKey;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2)
+            .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn bep_44_key() -> [u8; 32] {
+        let mut key = [0; 32];
+        key.copy_from_slice(&hex(
+            "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548",
+        ));
+        key
+    }
+
+    #[test]
+    fn bep_44_immutable_vector() {
+        let item = Item::Immutable(b"12:Hello World!".to_vec());
+        assert_eq!(
+            item.target().as_bytes(),
+            &hex("e5f96f6f38320f0f33959cb4d3d656452117aadb")[..]
+        );
+    }
+
+    #[test]
+    fn bep_44_mutable_vectors() {
+        // The test vectors give the secret key in expanded form.
+        let secret = ExpandedSecretKey::from_bytes(&hex(
+            "e06d3183d14159228433ed599221b80bd0a5ce8352e4bdf0262f76786ef1c74d\
+             b7e7a9fea2c0eb269d61e3b38e450a22e754941ac78479d6c54e1faf6037881d",
+        )).unwrap();
+        let public = PublicKey::from_bytes(&bep_44_key()).unwrap();
+        let value = b"12:Hello World!".to_vec();
+
+        let signed = MutableItem::signed_bytes(b"", 1, &value);
+        let item = MutableItem {
+            key: bep_44_key(),
+            salt: Vec::new(),
+            seq: 1,
+            value: value.clone(),
+            signature: secret.sign(&signed, &public).to_bytes().to_vec(),
+        };
+        assert_eq!(
+            item.signature,
+            hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                 1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01")
+        );
+        assert!(item.verify());
+        assert_eq!(
+            item.target().as_bytes(),
+            &hex("4a533d47ec9c7d95b1ad75f576cffc641853b750")[..]
+        );
+
+        let salted = MutableItem {
+            salt: b"foobar".to_vec(),
+            signature: hex(
+                "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                 df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+            ),
+            ..item.clone()
+        };
+        assert!(salted.verify());
+        assert_eq!(
+            salted.target().as_bytes(),
+            &hex("411eba73b6f087ca51a3795d9c8c938d365e32c1")[..]
+        );
+
+        let tampered = MutableItem { seq: 2, ..item };
+        assert!(!tampered.verify());
+        assert_eq!(Item::Mutable(tampered).check().unwrap_err().0, ERROR_BAD_SIGNATURE);
+    }
+
+    #[test]
+    fn sign_and_check() {
+        let keypair = generate_keypair();
+        let item = MutableItem::sign(&keypair, b"salt", 7, b"i42e".to_vec());
+        assert!(Item::Mutable(item).check().is_ok());
+        assert_eq!(canonical_value(b"d1:bi1e1:ai2ee").unwrap(), b"d1:ai2e1:bi1ee".to_vec());
+        assert!(canonical_value(b"3:ab").is_err());
+    }
+}
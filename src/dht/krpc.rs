@@ -0,0 +1,532 @@
+//! KRPC, the DHT's RPC protocol: bencoded dicts over UDP.
+//!
+//! Every message has a transaction id `t` and a type `y`: a query (`q`), a
+//! response (`r`) or an error (`e`).
+
+use std::io;
+use std::net::SocketAddr;
+
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use compact;
+use dht::NodeId;
+use dht::item::{Item, MutableItem};
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A node's id and address, as sent in `nodes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers(NodeId),
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        /// Use the port the query came from, rather than `port`.
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// Fetches a stored item (BEP 44).  With `seq`, the value is only sent
+    /// back if the stored item is newer.
+    Get { target: NodeId, seq: Option<i64> },
+    /// Stores an item.  `cas` makes a mutable put conditional on the
+    /// current `seq`.
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match *self {
+            Query::Ping => "ping",
+            Query::FindNode(_) => "find_node",
+            Query::GetPeers(_) => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // A stored item, in answer to `get`.  Mutable items also have a key,
+    // signature and seq; their salt is up to the asker to know.
+    pub value: Option<Vec<u8>>,
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<Vec<u8>>,
+    pub seq: Option<i64>,
+}
+
+impl Response {
+    /// The item in a `get` response, given the salt it was looked up with.
+    pub fn item(&self, salt: &[u8]) -> Option<Item> {
+        let value = self.value.clone()?;
+        match (self.key, self.signature.as_ref(), self.seq) {
+            (Some(key), Some(signature), Some(seq)) => Some(Item::Mutable(MutableItem {
+                key,
+                salt: salt.to_vec(),
+                seq,
+                value,
+                signature: signature.clone(),
+            })),
+            (None, None, None) => Some(Item::Immutable(value)),
+            _ => None,
+        }
+    }
+
+    pub fn set_item(&mut self, item: &Item) {
+        self.value = Some(item.value().to_vec());
+        if let Item::Mutable(ref item) = *item {
+            self.key = Some(item.key);
+            self.signature = Some(item.signature.clone());
+            self.seq = Some(item.seq);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Body {
+    Query(NodeId, Query),
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+    /// In responses, the address the query came from, so the querying node
+    /// can learn its external address (BEP 42).
+    pub ip: Option<SocketAddr>,
+}
+
+// The wire format.  Every key that might be missing is optional here, and
+// the typed form above decides what is actually required.
+
+#[derive(Serialize, Deserialize, Default)]
+struct RawArgs {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cas: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sig: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RawResponse {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sig: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawMessage {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<ByteBuf>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn node_id(bytes: &ByteBuf) -> io::Result<NodeId> {
+    NodeId::from_bytes(bytes).ok_or_else(|| invalid("node id is not 20 bytes"))
+}
+
+fn public_key(bytes: &ByteBuf) -> io::Result<[u8; 32]> {
+    if bytes.len() != 32 {
+        return Err(invalid("public key is not 32 bytes"));
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+// Values travel as bencoded data inside the message, but we keep them as
+// the encoded bytes, since that's what signatures cover.
+fn to_value(bytes: &[u8]) -> Value {
+    from_bytes(bytes).expect("item values are checked bencode")
+}
+
+fn from_value(value: &Value) -> Vec<u8> {
+    to_bytes(value).expect("value always serializes")
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(26 * nodes.len());
+    for node in nodes.iter().filter(|node| node.addr.is_ipv4()) {
+        out.extend(node.id.as_bytes());
+        compact::push_addr(&mut out, &node.addr);
+    }
+    out
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes
+        .chunks(26)
+        .filter_map(|chunk| {
+            if chunk.len() != 26 {
+                return None;
+            }
+            let id = NodeId::from_bytes(&chunk[..20])?;
+            let addr = compact::read_addr(&chunk[20..])?;
+            Some(NodeInfo { id, addr })
+        })
+        .collect()
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = RawMessage {
+            t: ByteBuf::from(self.transaction.clone()),
+            y: String::new(),
+            q: None,
+            a: None,
+            r: None,
+            e: None,
+            ip: self.ip.map(|addr| {
+                let mut ip = Vec::with_capacity(18);
+                compact::push_addr(&mut ip, &addr);
+                ByteBuf::from(ip)
+            }),
+        };
+        match self.body {
+            Body::Query(ref id, ref query) => {
+                raw.y = "q".to_string();
+                raw.q = Some(query.method().to_string());
+                let mut args = RawArgs {
+                    id: ByteBuf::from(id.as_bytes().to_vec()),
+                    ..RawArgs::default()
+                };
+                match *query {
+                    Query::Ping => {}
+                    Query::FindNode(ref target) => {
+                        args.target = Some(ByteBuf::from(target.as_bytes().to_vec()))
+                    }
+                    Query::GetPeers(ref info_hash) => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.as_bytes().to_vec()))
+                    }
+                    Query::AnnouncePeer {
+                        ref info_hash,
+                        port,
+                        implied_port,
+                        ref token,
+                    } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.as_bytes().to_vec()));
+                        args.port = Some(port);
+                        args.implied_port = if implied_port { Some(1) } else { None };
+                        args.token = Some(ByteBuf::from(token.clone()));
+                    }
+                    Query::Get { ref target, seq } => {
+                        args.target = Some(ByteBuf::from(target.as_bytes().to_vec()));
+                        args.seq = seq;
+                    }
+                    Query::Put {
+                        ref token,
+                        ref item,
+                        cas,
+                    } => {
+                        args.token = Some(ByteBuf::from(token.clone()));
+                        args.v = Some(to_value(item.value()));
+                        if let Item::Mutable(ref item) = *item {
+                            args.k = Some(ByteBuf::from(item.key.to_vec()));
+                            args.sig = Some(ByteBuf::from(item.signature.clone()));
+                            args.seq = Some(item.seq);
+                            args.cas = cas;
+                            if !item.salt.is_empty() {
+                                args.salt = Some(ByteBuf::from(item.salt.clone()));
+                            }
+                        }
+                    }
+                }
+                raw.a = Some(args);
+            }
+            Body::Response(ref response) => {
+                raw.y = "r".to_string();
+                raw.r = Some(RawResponse {
+                    id: ByteBuf::from(response.id.as_bytes().to_vec()),
+                    nodes: if response.nodes.is_empty() {
+                        None
+                    } else {
+                        Some(ByteBuf::from(encode_nodes(&response.nodes)))
+                    },
+                    values: if response.values.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            response
+                                .values
+                                .iter()
+                                .map(|addr| {
+                                    let mut value = Vec::with_capacity(18);
+                                    compact::push_addr(&mut value, addr);
+                                    ByteBuf::from(value)
+                                })
+                                .collect(),
+                        )
+                    },
+                    token: response.token.clone().map(ByteBuf::from),
+                    k: response.key.map(|key| ByteBuf::from(key.to_vec())),
+                    sig: response.signature.clone().map(ByteBuf::from),
+                    seq: response.seq,
+                    v: response.value.as_ref().map(|value| to_value(value)),
+                });
+            }
+            Body::Error(code, ref msg) => {
+                raw.y = "e".to_string();
+                raw.e = Some((code, msg.clone()));
+            }
+        }
+        to_bytes(&raw).expect("krpc message always serializes")
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<KrpcMessage> {
+        let raw: RawMessage = from_bytes(buf).map_err(|_| invalid("bad krpc message"))?;
+        let body = match &raw.y[..] {
+            "q" => {
+                let args = raw.a.ok_or_else(|| invalid("query without arguments"))?;
+                let id = node_id(&args.id)?;
+                let query = match raw.q.as_ref().map(|q| &q[..]) {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode(node_id(
+                        args.target.as_ref().ok_or_else(|| invalid("find_node without target"))?,
+                    )?),
+                    Some("get_peers") => Query::GetPeers(node_id(
+                        args.info_hash
+                            .as_ref()
+                            .ok_or_else(|| invalid("get_peers without info_hash"))?,
+                    )?),
+                    Some("announce_peer") => {
+                        let implied_port = args.implied_port.unwrap_or(0) != 0;
+                        // Without a port, there's nothing to tell anyone.
+                        let port = match args.port {
+                            Some(port) if port != 0 => port,
+                            _ if implied_port => 0,
+                            _ => return Err(invalid("announce_peer without port")),
+                        };
+                        Query::AnnouncePeer {
+                            info_hash: node_id(
+                                args.info_hash
+                                    .as_ref()
+                                    .ok_or_else(|| invalid("announce_peer without info_hash"))?,
+                            )?,
+                            port,
+                            implied_port,
+                            token: args.token
+                                .ok_or_else(|| invalid("announce_peer without token"))?
+                                .to_vec(),
+                        }
+                    }
+                    Some("get") => Query::Get {
+                        target: node_id(
+                            args.target.as_ref().ok_or_else(|| invalid("get without target"))?,
+                        )?,
+                        seq: args.seq,
+                    },
+                    Some("put") => {
+                        let value = from_value(
+                            args.v.as_ref().ok_or_else(|| invalid("put without value"))?,
+                        );
+                        let item = match args.k {
+                            Some(ref key) => Item::Mutable(MutableItem {
+                                key: public_key(key)?,
+                                salt: args.salt.map(|salt| salt.to_vec()).unwrap_or_default(),
+                                seq: args.seq.ok_or_else(|| invalid("mutable put without seq"))?,
+                                value,
+                                signature: args.sig
+                                    .ok_or_else(|| invalid("mutable put without signature"))?
+                                    .to_vec(),
+                            }),
+                            None => Item::Immutable(value),
+                        };
+                        Query::Put {
+                            token: args.token
+                                .ok_or_else(|| invalid("put without token"))?
+                                .to_vec(),
+                            item,
+                            cas: args.cas,
+                        }
+                    }
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown method")),
+                };
+                Body::Query(id, query)
+            }
+            "r" => {
+                let r = raw.r.ok_or_else(|| invalid("response without body"))?;
+                Body::Response(Response {
+                    id: node_id(&r.id)?,
+                    nodes: r.nodes.map(|nodes| decode_nodes(&nodes)).unwrap_or_default(),
+                    values: r.values
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|value| compact::read_addr(value))
+                        .collect(),
+                    token: r.token.map(|token| token.to_vec()),
+                    value: r.v.as_ref().map(from_value),
+                    key: match r.k {
+                        Some(ref key) => Some(public_key(key)?),
+                        None => None,
+                    },
+                    signature: r.sig.map(|sig| sig.to_vec()),
+                    seq: r.seq,
+                })
+            }
+            "e" => {
+                let (code, msg) = raw.e.ok_or_else(|| invalid("error without body"))?;
+                Body::Error(code, msg)
+            }
+            _ => return Err(invalid("unknown message type")),
+        };
+        Ok(KrpcMessage {
+            transaction: raw.t.to_vec(),
+            body,
+            ip: raw.ip.and_then(|ip| compact::read_addr(&ip)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dht::item;
+
+    #[test]
+    fn decode_bep_5_examples() {
+        let ping = KrpcMessage::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+            .unwrap();
+        assert_eq!(ping.transaction, b"aa".to_vec());
+        assert_eq!(
+            ping.body,
+            Body::Query(NodeId::from_bytes(b"abcdefghij0123456789").unwrap(), Query::Ping)
+        );
+        assert_eq!(
+            ping.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec()
+        );
+
+        let error = KrpcMessage::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee")
+            .unwrap();
+        assert_eq!(
+            error.body,
+            Body::Error(ERROR_GENERIC, "A Generic Error Ocurred".to_string())
+        );
+        assert_eq!(
+            error.encode(),
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee".to_vec()
+        );
+
+        let announce = |implied_port: &str, port: &str| {
+            let msg = format!(
+                "d1:ad2:id20:abcdefghij0123456789{}9:info_hash20:mnopqrstuvwxyz123456{}\
+                 5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+                implied_port, port
+            );
+            KrpcMessage::decode(msg.as_bytes())
+        };
+        assert!(announce("", "4:porti6881e").is_ok());
+        assert!(announce("12:implied_porti1e", "").is_ok());
+        // With neither, we'd have to make up a port.
+        assert!(announce("", "").is_err());
+        assert!(announce("", "4:porti0e").is_err());
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let node = NodeInfo {
+            id: NodeId::from_bytes(&[7; 20]).unwrap(),
+            addr: "127.0.0.1:6881".parse().unwrap(),
+        };
+        let msg = KrpcMessage {
+            transaction: vec![0, 1],
+            body: Body::Response(Response {
+                id: NodeId::from_bytes(b"mnopqrstuvwxyz123456").unwrap(),
+                nodes: vec![node],
+                values: vec!["10.0.0.1:51413".parse().unwrap()],
+                token: Some(b"aoeusnth".to_vec()),
+                ..Response::default()
+            }),
+            ip: Some("203.0.113.5:6881".parse().unwrap()),
+        };
+        assert_eq!(KrpcMessage::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn put_roundtrip() {
+        let keypair = item::generate_keypair();
+        let put = KrpcMessage {
+            transaction: vec![0, 2],
+            body: Body::Query(
+                NodeId::from_bytes(&[1; 20]).unwrap(),
+                Query::Put {
+                    token: b"token".to_vec(),
+                    item: Item::Mutable(MutableItem::sign(&keypair, b"s", 3, b"li1ei2ee".to_vec())),
+                    cas: Some(2),
+                },
+            ),
+            ip: None,
+        };
+        let encoded = put.encode();
+        assert!(encoded.starts_with(b"d1:ad3:casi2e2:id20:"));
+        assert_eq!(KrpcMessage::decode(&encoded).unwrap(), put);
+    }
+}
@@ -0,0 +1,1307 @@
+//! The mainline DHT (BEP 5), for finding peers without a tracker.
+//!
+//! `Dht` runs one node on a UDP socket.  Register it with the same mio
+//! `Poll` as everything else, call `ready` when the socket is readable and
+//! `tick` every second or so, and it takes care of the rest: answering
+//! queries, keeping the routing table fresh, and running lookups.
+//!
+//! It can also store and fetch small items (BEP 44), which is how mutable
+//! torrents (BEP 46) are published and followed.
+
+pub mod item;
+pub mod krpc;
+pub mod routing;
+pub mod security;
+pub mod state;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Poll, PollOpt, Ready, Token};
+use ed25519_dalek::Keypair;
+use mio::net::UdpSocket;
+use rand;
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bytes::ByteBuf;
+use sha1::Sha1;
+
+use connect::ConnectQueue;
+use metainfo::Sha1Hash;
+use self::item::{Item, MutableItem};
+use self::krpc::{Body, KrpcMessage, NodeInfo, Query, Response};
+use self::routing::{RoutingTable, K};
+use self::state::DhtState;
+
+/// The well-known nodes new clients start from.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(60);
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// BEP 44 asks publishers to re-put items at least every hour, so two hours
+// is plenty.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Nodes that must agree on our address before we believe them.
+const EXTERNAL_IP_VOTES: usize = 3;
+const MAX_VOTERS: usize = 100;
+
+// Queries in flight per lookup.
+const ALPHA: usize = 3;
+
+// Peers to hand out per get_peers response; 50 compact addresses keep the
+// packet well under a typical MTU.
+const MAX_VALUES: usize = 50;
+
+// What other nodes can have us keep, so a flood of announces or puts can't
+// grow without bound.  Past these, the oldest goes.
+const MAX_TORRENTS: usize = 2000;
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const MAX_ITEMS: usize = 1000;
+
+/// A 160-bit node id or info hash.  Distances between them are XOR.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; 20]);
+
+impl NodeId {
+    pub fn from_bytes(bytes: &[u8]) -> Option<NodeId> {
+        if bytes.len() == 20 {
+            let mut id = [0; 20];
+            id.copy_from_slice(bytes);
+            Some(NodeId(id))
+        } else {
+            None
+        }
+    }
+
+    pub fn random() -> NodeId {
+        NodeId(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut out = [0; 20];
+        for (out, (a, b)) in out.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *out = a ^ b;
+        }
+        NodeId(out)
+    }
+
+    pub fn leading_zeros(&self) -> usize {
+        let mut zeros = 0;
+        for byte in &self.0 {
+            zeros += byte.leading_zeros() as usize;
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DhtConfig {
+    pub bind: SocketAddr,
+    /// `host:port` strings, resolved on a helper thread each time we
+    /// bootstrap.
+    pub bootstrap: Vec<String>,
+    /// Our node id.  If this is None, we use the one in the state file, or
+    /// generate one, from `external_ip` if we know it.
+    pub id: Option<NodeId>,
+    /// Our public address, if known up front.  Otherwise we learn it from
+    /// the `ip` other nodes send back.
+    pub external_ip: Option<IpAddr>,
+    /// Where to keep the node id and routing table between runs.
+    pub state_file: Option<PathBuf>,
+}
+
+impl DhtConfig {
+    pub fn new(bind: SocketAddr) -> DhtConfig {
+        DhtConfig {
+            bind,
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            id: None,
+            external_ip: None,
+            state_file: None,
+        }
+    }
+}
+
+/// Tokens prove to us that a node announcing itself really is at the
+/// address it claims.  They're a hash of the address and a secret that
+/// changes every few minutes; the previous secret is still accepted.
+struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new(now: Instant) -> Tokens {
+        let secret = rand::random();
+        Tokens {
+            secret,
+            previous: secret,
+            rotated: now,
+        }
+    }
+
+    fn make(secret: &[u8], ip: &IpAddr) -> Vec<u8> {
+        let mut sha = Sha1::new();
+        sha.update(secret);
+        match *ip {
+            IpAddr::V4(ip) => sha.update(&ip.octets()),
+            IpAddr::V6(ip) => sha.update(&ip.octets()),
+        }
+        sha.digest().bytes()[..8].to_vec()
+    }
+
+    fn token(&self, ip: &IpAddr) -> Vec<u8> {
+        Tokens::make(&self.secret, ip)
+    }
+
+    fn check(&self, ip: &IpAddr, token: &[u8]) -> bool {
+        token == &Tokens::make(&self.secret, ip)[..]
+            || token == &Tokens::make(&self.previous, ip)[..]
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Candidate {
+    Fresh,
+    Queried,
+    Responded(Option<Vec<u8>>),
+    Failed,
+}
+
+enum LookupKind {
+    FindNode,
+    GetPeers {
+        queue: ConnectQueue,
+        announce: Option<u16>,
+    },
+    /// Fetches an item, and then stores `put` with the closest nodes, if
+    /// there is one.  `salt` is needed to check mutable items we find.
+    Get {
+        salt: Vec<u8>,
+        seq: Option<i64>,
+        put: Option<(Item, Option<i64>)>,
+    },
+}
+
+/// An iterative search for the nodes closest to a target.
+struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    // Keyed by distance to the target, so the closest come first.
+    candidates: BTreeMap<NodeId, (NodeInfo, Candidate)>,
+    outstanding: usize,
+}
+
+impl Lookup {
+    fn add(&mut self, info: NodeInfo) {
+        self.candidates
+            .entry(info.id.distance(&self.target))
+            .or_insert((info, Candidate::Fresh));
+    }
+
+    /// The K closest nodes that haven't failed us.
+    fn closest(&self) -> Vec<&(NodeInfo, Candidate)> {
+        self.candidates
+            .values()
+            .filter(|&&(_, ref state)| *state != Candidate::Failed)
+            .take(K)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TxKind {
+    Ping,
+    Bootstrap,
+    Lookup(usize),
+    Announce,
+    Put,
+}
+
+struct Transaction {
+    addr: SocketAddr,
+    kind: TxKind,
+    sent: Instant,
+}
+
+/// The key whose value is oldest, by `when`.
+fn oldest<K, V, F>(map: &HashMap<K, V>, when: F) -> Option<K>
+where
+    K: Clone + Eq + Hash,
+    F: Fn(&V) -> Instant,
+{
+    map.iter()
+        .min_by_key(|&(_, value)| when(value))
+        .map(|(key, _)| key.clone())
+}
+
+/// The network a voter is in, a /24 or a /64, so that one host, or one
+/// network, only gets one say in what our address is.
+fn voter_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(o[0], o[1], o[2], 0))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+pub struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    table: RoutingTable,
+    tokens: Tokens,
+    // When each info hash was last announced, and each of its peers.
+    peers: HashMap<NodeId, (Instant, HashMap<SocketAddr, Instant>)>,
+    // Items other nodes have put with us.
+    stored: HashMap<NodeId, (Item, Instant)>,
+    // The newest item our own gets have found for each target.
+    found: HashMap<NodeId, Item>,
+    transactions: HashMap<Vec<u8>, Transaction>,
+    lookups: HashMap<usize, Lookup>,
+    next_lookup: usize,
+    next_transaction: u16,
+    bootstrap: Vec<String>,
+    last_bootstrap: Option<Instant>,
+    // Bootstrap addresses looked up off the event loop, as they come in.
+    resolver: Sender<Vec<SocketAddr>>,
+    resolved: Receiver<Vec<SocketAddr>>,
+    state_file: Option<PathBuf>,
+    last_save: Instant,
+    // Whether the id was configured, rather than ours to change.
+    fixed_id: bool,
+    external_ip: Option<IpAddr>,
+    // What each network we've heard from says our address is, and when.
+    ip_votes: HashMap<IpAddr, (IpAddr, Instant)>,
+}
+
+impl Dht {
+    pub fn new(config: DhtConfig) -> io::Result<Dht> {
+        let socket = UdpSocket::bind(&config.bind)?;
+        // A missing or broken state file just means starting afresh.
+        let saved = config
+            .state_file
+            .as_ref()
+            .and_then(|path| DhtState::load(path).ok());
+        let external_ip = config.external_ip;
+        let id = config
+            .id
+            .or_else(|| {
+                saved
+                    .as_ref()
+                    .map(|state| state.id)
+                    .filter(|id| external_ip.map_or(true, |ip| security::is_secure(id, &ip)))
+            })
+            .unwrap_or_else(|| match external_ip {
+                Some(ip) => security::generate_id(&ip),
+                None => NodeId::random(),
+            });
+        let now = Instant::now();
+        let mut table = RoutingTable::new(id);
+        // Saved nodes are only worth keeping if our id hasn't changed, since
+        // the buckets they belong in depend on it.
+        if let Some(state) = saved {
+            if state.id == id {
+                for info in state.nodes {
+                    table.insert(info, now);
+                }
+            }
+        }
+        let (resolver, resolved) = mpsc::channel();
+        Ok(Dht {
+            id,
+            socket,
+            table,
+            tokens: Tokens::new(now),
+            peers: HashMap::new(),
+            stored: HashMap::new(),
+            found: HashMap::new(),
+            transactions: HashMap::new(),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            next_transaction: 0,
+            bootstrap: config.bootstrap,
+            last_bootstrap: None,
+            resolver,
+            resolved,
+            state_file: config.state_file,
+            last_save: now,
+            fixed_id: config.id.is_some(),
+            external_ip,
+            ip_votes: HashMap::new(),
+        })
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    /// Our public address, once enough nodes agree on it.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Our id and routing table, for saving.
+    pub fn state(&self) -> DhtState {
+        DhtState {
+            id: self.id,
+            nodes: self.table.nodes().map(|node| node.info).collect(),
+        }
+    }
+
+    /// Saves our state to the configured state file, if there is one.
+    pub fn save_state(&self) -> io::Result<()> {
+        match self.state_file {
+            Some(ref path) => self.state().save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&self.socket, token, Ready::readable(), PollOpt::edge())
+    }
+
+    fn send(&self, addr: &SocketAddr, msg: &KrpcMessage) {
+        // UDP is lossy anyway; a failed send looks like a lost packet, and
+        // the query will time out.
+        let _ = self.socket.send_to(&msg.encode(), addr);
+    }
+
+    fn query(&mut self, addr: SocketAddr, query: Query, kind: TxKind, now: Instant) {
+        let transaction = vec![
+            (self.next_transaction >> 8) as u8,
+            self.next_transaction as u8,
+        ];
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let msg = KrpcMessage {
+            transaction: transaction.clone(),
+            body: Body::Query(self.id, query),
+            ip: None,
+        };
+        self.send(&addr, &msg);
+        self.transactions.insert(
+            transaction,
+            Transaction {
+                addr,
+                kind,
+                sent: now,
+            },
+        );
+    }
+
+    pub fn ping(&mut self, addr: SocketAddr, now: Instant) {
+        self.query(addr, Query::Ping, TxKind::Ping, now);
+    }
+
+    /// Asks the bootstrap nodes for the nodes closest to us.
+    pub fn bootstrap(&mut self, now: Instant) {
+        self.last_bootstrap = Some(now);
+        let hosts = self.bootstrap.clone();
+        self.contact(hosts, now);
+        let target = self.id;
+        self.start_lookup(target, LookupKind::FindNode, now);
+    }
+
+    /// Adds `host:port` contacts to bootstrap from, such as the `nodes` of
+    /// a trackerless torrent, and asks them for our neighbours right away.
+    pub fn add_bootstrap(&mut self, hosts: &[String], now: Instant) {
+        let new: Vec<String> = hosts
+            .iter()
+            .filter(|host| !self.bootstrap.contains(host))
+            .cloned()
+            .collect();
+        self.bootstrap.extend(new.iter().cloned());
+        self.contact(new, now);
+    }
+
+    // Asks bootstrap hosts for our neighbours.  Addresses are asked at
+    // once; names are looked up on a helper thread, since that can block
+    // for seconds, and asked from `tick` once they're resolved.
+    fn contact(&mut self, hosts: Vec<String>, now: Instant) {
+        let mut names = Vec::new();
+        for host in hosts {
+            match host.parse() {
+                Ok(addr) => self.find_ourselves(addr, now),
+                Err(_) => names.push(host),
+            }
+        }
+        if names.is_empty() {
+            return;
+        }
+        let resolver = self.resolver.clone();
+        thread::spawn(move || {
+            let addrs = names
+                .iter()
+                .filter_map(|host| host.to_socket_addrs().ok())
+                .flatten()
+                .collect();
+            let _ = resolver.send(addrs);
+        });
+    }
+
+    fn find_ourselves(&mut self, addr: SocketAddr, now: Instant) {
+        if addr.is_ipv4() {
+            let target = self.id;
+            self.query(addr, Query::FindNode(target), TxKind::Bootstrap, now);
+        }
+    }
+
+    /// Looks for peers of a torrent, pushing any we find onto `queue`.  If
+    /// `announce` is a port, we announce ourselves on it to the closest
+    /// nodes once the lookup is done.
+    pub fn get_peers(
+        &mut self,
+        info_hash: NodeId,
+        queue: ConnectQueue,
+        announce: Option<u16>,
+        now: Instant,
+    ) {
+        self.start_lookup(info_hash, LookupKind::GetPeers { queue, announce }, now);
+    }
+
+    /// Stores a bencoded value in the DHT, and returns its target.
+    pub fn put_immutable(&mut self, value: &[u8], now: Instant) -> io::Result<NodeId> {
+        let item = Item::Immutable(item::canonical_value(value)?);
+        self.put(item, None, now)
+    }
+
+    /// Stores a signed item in the DHT, and returns its target.  With `cas`,
+    /// nodes only take it if the item they have has that `seq`.
+    pub fn put_mutable(
+        &mut self,
+        item: MutableItem,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> io::Result<NodeId> {
+        let item = Item::Mutable(MutableItem {
+            value: item::canonical_value(&item.value)?,
+            ..item
+        });
+        self.put(item, cas, now)
+    }
+
+    fn put(&mut self, item: Item, cas: Option<i64>, now: Instant) -> io::Result<NodeId> {
+        item.check()
+            .map_err(|(_, msg)| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        let target = item.target();
+        let salt = match item {
+            Item::Mutable(ref item) => item.salt.clone(),
+            Item::Immutable(_) => Vec::new(),
+        };
+        self.found.insert(target, item.clone());
+        let kind = LookupKind::Get {
+            salt,
+            seq: None,
+            put: Some((item, cas)),
+        };
+        self.start_lookup(target, kind, now);
+        Ok(target)
+    }
+
+    /// Looks up an immutable item.  Once found, it's available from `item`.
+    pub fn get_immutable(&mut self, target: NodeId, now: Instant) {
+        let kind = LookupKind::Get {
+            salt: Vec::new(),
+            seq: None,
+            put: None,
+        };
+        self.start_lookup(target, kind, now);
+    }
+
+    /// Looks up the newest version of a mutable item, and returns its
+    /// target, for `item`.
+    pub fn get_mutable(&mut self, key: &[u8; 32], salt: &[u8], now: Instant) -> NodeId {
+        let target = item::mutable_target(key, salt);
+        let kind = LookupKind::Get {
+            salt: salt.to_vec(),
+            // Nodes needn't send us what we already have.
+            seq: self.found.get(&target).and_then(|item| item.seq()),
+            put: None,
+        };
+        self.start_lookup(target, kind, now);
+        target
+    }
+
+    /// The newest item found for a target so far.
+    pub fn item(&self, target: &NodeId) -> Option<&Item> {
+        self.found.get(target)
+    }
+
+    /// Points a mutable torrent (BEP 46) at `info_hash`.  `seq` has to go
+    /// up with each new version.
+    pub fn publish_torrent(
+        &mut self,
+        keypair: &Keypair,
+        salt: &[u8],
+        seq: i64,
+        info_hash: &Sha1Hash,
+        now: Instant,
+    ) -> io::Result<NodeId> {
+        let item = MutableItem::sign(keypair, salt, seq, torrent_value(info_hash));
+        self.put_mutable(item, None, now)
+    }
+
+    /// Starts resolving a mutable torrent.  Call it again to check for
+    /// updates, and `torrent` for the result.
+    pub fn follow_torrent(&mut self, key: &[u8; 32], salt: &[u8], now: Instant) -> NodeId {
+        self.get_mutable(key, salt, now)
+    }
+
+    /// The newest info hash found for a mutable torrent, and its `seq`.
+    pub fn torrent(&self, key: &[u8; 32], salt: &[u8]) -> Option<(i64, Sha1Hash)> {
+        match self.found.get(&item::mutable_target(key, salt)) {
+            Some(&Item::Mutable(ref item)) => {
+                info_hash_from_value(&item.value).map(|info_hash| (item.seq, info_hash))
+            }
+            _ => None,
+        }
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind, now: Instant) -> usize {
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        let mut lookup = Lookup {
+            target,
+            kind,
+            candidates: BTreeMap::new(),
+            outstanding: 0,
+        };
+        for info in self.table.closest(&target, K) {
+            lookup.add(info);
+        }
+        self.lookups.insert(id, lookup);
+        self.step_lookup(id, now);
+        id
+    }
+
+    /// Sends more queries for a lookup, or finishes it.
+    fn step_lookup(&mut self, id: usize, now: Instant) {
+        let (to_query, query) = match self.lookups.get_mut(&id) {
+            Some(lookup) => {
+                let fresh: Vec<NodeInfo> = lookup
+                    .closest()
+                    .into_iter()
+                    .filter(|&&(_, ref state)| *state == Candidate::Fresh)
+                    .map(|&(info, _)| info)
+                    .take(ALPHA.saturating_sub(lookup.outstanding))
+                    .collect();
+                for info in &fresh {
+                    let distance = info.id.distance(&lookup.target);
+                    lookup.candidates.get_mut(&distance).unwrap().1 = Candidate::Queried;
+                }
+                lookup.outstanding += fresh.len();
+                let query = match lookup.kind {
+                    LookupKind::FindNode => Query::FindNode(lookup.target),
+                    LookupKind::GetPeers { .. } => Query::GetPeers(lookup.target),
+                    LookupKind::Get { seq, .. } => Query::Get {
+                        target: lookup.target,
+                        seq,
+                    },
+                };
+                (fresh, query)
+            }
+            None => return,
+        };
+        for info in to_query {
+            self.query(info.addr, query.clone(), TxKind::Lookup(id), now);
+        }
+        if self.lookups[&id].outstanding == 0 {
+            self.finish_lookup(id, now);
+        }
+    }
+
+    fn finish_lookup(&mut self, id: usize, now: Instant) {
+        let lookup = match self.lookups.remove(&id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        for &(info, ref state) in lookup.closest() {
+            let token = match *state {
+                Candidate::Responded(Some(ref token)) => token.clone(),
+                _ => continue,
+            };
+            match lookup.kind {
+                LookupKind::GetPeers {
+                    announce: Some(port),
+                    ..
+                } => {
+                    let query = Query::AnnouncePeer {
+                        info_hash: lookup.target,
+                        port,
+                        implied_port: false,
+                        token,
+                    };
+                    self.query(info.addr, query, TxKind::Announce, now);
+                }
+                LookupKind::Get {
+                    put: Some((ref item, cas)),
+                    ..
+                } => {
+                    let query = Query::Put {
+                        token,
+                        item: item.clone(),
+                        cas,
+                    };
+                    self.query(info.addr, query, TxKind::Put, now);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads and handles everything waiting on the socket.
+    pub fn ready(&mut self, now: Instant) -> io::Result<()> {
+        let mut buf = [0; 2048];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, addr)) => self.handle(&buf[..n], addr, now),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Expires queries, rotates tokens and re-bootstraps if we've lost
+    /// touch with the network.
+    pub fn tick(&mut self, now: Instant) {
+        let resolved: Vec<SocketAddr> = self.resolved.try_iter().flatten().collect();
+        for addr in resolved {
+            self.find_ourselves(addr, now);
+        }
+        let expired: Vec<Vec<u8>> = self.transactions
+            .iter()
+            .filter(|&(_, tx)| now.duration_since(tx.sent) >= QUERY_TIMEOUT)
+            .map(|(t, _)| t.clone())
+            .collect();
+        for t in expired {
+            let tx = self.transactions.remove(&t).unwrap();
+            self.query_failed(&tx, now);
+        }
+        self.tokens.rotate(now);
+        for &mut (_, ref mut peers) in self.peers.values_mut() {
+            peers.retain(|_, &mut seen| now.duration_since(seen) < PEER_TTL);
+        }
+        self.peers.retain(|_, &mut (_, ref peers)| !peers.is_empty());
+        self.stored
+            .retain(|_, &mut (_, stored)| now.duration_since(stored) < ITEM_TTL);
+        let due = self.last_bootstrap
+            .map_or(true, |last| now.duration_since(last) >= BOOTSTRAP_INTERVAL);
+        if self.table.is_empty() && due {
+            self.bootstrap(now);
+        }
+        if now.duration_since(self.last_save) >= SAVE_INTERVAL {
+            self.last_save = now;
+            // Losing a save only costs a slower start next time.
+            let _ = self.save_state();
+        }
+    }
+
+    fn query_failed(&mut self, tx: &Transaction, now: Instant) {
+        let failed: Vec<NodeId> = self.table
+            .nodes()
+            .filter(|node| node.info.addr == tx.addr)
+            .map(|node| node.info.id)
+            .collect();
+        for id in &failed {
+            self.table.failed(id);
+        }
+        if let TxKind::Lookup(lookup_id) = tx.kind {
+            if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                lookup.outstanding -= 1;
+                for candidate in lookup.candidates.values_mut() {
+                    if candidate.0.addr == tx.addr {
+                        candidate.1 = Candidate::Failed;
+                    }
+                }
+            }
+            self.step_lookup(lookup_id, now);
+        }
+    }
+
+    fn handle(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) {
+        let msg = match KrpcMessage::decode(buf) {
+            Ok(msg) => msg,
+            // Not much we can say to someone who sent us garbage.
+            Err(_) => return,
+        };
+        match msg.body {
+            Body::Query(id, query) => {
+                self.table.insert(NodeInfo { id, addr }, now);
+                let body = self.answer(query, addr, now);
+                self.send(
+                    &addr,
+                    &KrpcMessage {
+                        transaction: msg.transaction,
+                        body,
+                        ip: Some(addr),
+                    },
+                );
+            }
+            Body::Response(response) => {
+                // A reply from anyone but the node we asked is ignored, and
+                // the query left to time out.
+                if !self.is_reply_from(&msg.transaction, addr) {
+                    return;
+                }
+                let tx = self.transactions.remove(&msg.transaction).unwrap();
+                if let Some(ip) = msg.ip {
+                    self.vote_external_ip(addr, ip.ip(), now);
+                }
+                self.table.insert(
+                    NodeInfo {
+                        id: response.id,
+                        addr,
+                    },
+                    now,
+                );
+                self.handle_response(tx, response, addr, now);
+            }
+            Body::Error(_, _) => {
+                if !self.is_reply_from(&msg.transaction, addr) {
+                    return;
+                }
+                if let Some(tx) = self.transactions.remove(&msg.transaction) {
+                    if let TxKind::Lookup(lookup_id) = tx.kind {
+                        if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                            lookup.outstanding -= 1;
+                            for candidate in lookup.candidates.values_mut() {
+                                if candidate.0.addr == addr {
+                                    candidate.1 = Candidate::Failed;
+                                }
+                            }
+                        }
+                        self.step_lookup(lookup_id, now);
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_reply_from(&self, transaction: &[u8], addr: SocketAddr) -> bool {
+        self.transactions
+            .get(transaction)
+            .map_or(false, |tx| tx.addr == addr)
+    }
+
+    /// Counts a node's opinion of our address.  Once enough agree on a new
+    /// one, we adopt it, and pick a new id if ours isn't valid for it.
+    fn vote_external_ip(&mut self, voter: SocketAddr, ip: IpAddr, now: Instant) {
+        let voter = voter_network(voter.ip());
+        if self.ip_votes.len() >= MAX_VOTERS && !self.ip_votes.contains_key(&voter) {
+            if let Some(oldest) = oldest(&self.ip_votes, |&(_, voted)| voted) {
+                self.ip_votes.remove(&oldest);
+            }
+        }
+        self.ip_votes.insert(voter, (ip, now));
+        if self.external_ip == Some(ip) {
+            return;
+        }
+        let votes = self.ip_votes.values().filter(|&&(vote, _)| vote == ip).count();
+        if votes < EXTERNAL_IP_VOTES {
+            return;
+        }
+        self.external_ip = Some(ip);
+        if !self.fixed_id && !security::is_secure(&self.id, &ip) {
+            self.id = security::generate_id(&ip);
+            let mut table = RoutingTable::new(self.id);
+            for node in self.table.nodes() {
+                table.insert(node.info, now);
+            }
+            self.table = table;
+        }
+    }
+
+    fn answer(&mut self, query: Query, addr: SocketAddr, now: Instant) -> Body {
+        let mut response = Response {
+            id: self.id,
+            ..Response::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode(target) => response.nodes = self.table.closest(&target, K),
+            Query::GetPeers(info_hash) => {
+                response.token = Some(self.tokens.token(&addr.ip()));
+                match self.peers.get(&info_hash) {
+                    Some(&(_, ref peers)) => {
+                        response.values = peers.keys().cloned().take(MAX_VALUES).collect()
+                    }
+                    None => response.nodes = self.table.closest(&info_hash, K),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.check(&addr.ip(), &token) {
+                    return Body::Error(krpc::ERROR_PROTOCOL, "bad token".to_string());
+                }
+                let port = if implied_port { addr.port() } else { port };
+                let peer = SocketAddr::new(addr.ip(), port);
+                if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_TORRENTS {
+                    if let Some(oldest) = oldest(&self.peers, |&(last, _)| last) {
+                        self.peers.remove(&oldest);
+                    }
+                }
+                let entry = self.peers
+                    .entry(info_hash)
+                    .or_insert_with(|| (now, HashMap::new()));
+                entry.0 = now;
+                let peers = &mut entry.1;
+                if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS_PER_TORRENT {
+                    if let Some(oldest) = oldest(peers, |&seen| seen) {
+                        peers.remove(&oldest);
+                    }
+                }
+                peers.insert(peer, now);
+            }
+            Query::Get { target, seq } => {
+                response.token = Some(self.tokens.token(&addr.ip()));
+                response.nodes = self.table.closest(&target, K);
+                if let Some(&(ref item, _)) = self.stored.get(&target) {
+                    match (item.seq(), seq) {
+                        // They already have this one.
+                        (Some(stored), Some(seq)) if stored <= seq => response.seq = Some(stored),
+                        _ => response.set_item(item),
+                    }
+                }
+            }
+            Query::Put { token, item, cas } => {
+                if !self.tokens.check(&addr.ip(), &token) {
+                    return Body::Error(krpc::ERROR_PROTOCOL, "bad token".to_string());
+                }
+                if let Err((code, msg)) = item.check() {
+                    return Body::Error(code, msg.to_string());
+                }
+                let target = item.target();
+                if let (Some(&(ref old, _)), Some(seq)) = (self.stored.get(&target), item.seq()) {
+                    let old_seq = old.seq().unwrap_or(0);
+                    if cas.map_or(false, |cas| cas != old_seq) {
+                        return Body::Error(item::ERROR_CAS_MISMATCH, "CAS mismatch".to_string());
+                    }
+                    // The same seq is only a refresh of the same value.
+                    if seq < old_seq || (seq == old_seq && item.value() != old.value()) {
+                        return Body::Error(
+                            item::ERROR_OLD_SEQ,
+                            "sequence number less than current".to_string(),
+                        );
+                    }
+                }
+                if !self.stored.contains_key(&target) && self.stored.len() >= MAX_ITEMS {
+                    if let Some(oldest) = oldest(&self.stored, |&(_, stored)| stored) {
+                        self.stored.remove(&oldest);
+                    }
+                }
+                self.stored.insert(target, (item, now));
+            }
+        }
+        Body::Response(response)
+    }
+
+    fn handle_response(
+        &mut self,
+        tx: Transaction,
+        response: Response,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        match tx.kind {
+            TxKind::Ping | TxKind::Announce | TxKind::Put => {}
+            TxKind::Bootstrap => {
+                for info in response.nodes {
+                    self.table.insert(info, now);
+                }
+                // Now there's someone to ask, look for our neighbours.
+                let target = self.id;
+                let neighbours = self.table.closest(&target, K);
+                let existing = self.lookups
+                    .iter()
+                    .find(|&(_, lookup)| lookup.target == target)
+                    .map(|(&id, _)| id);
+                match existing {
+                    Some(id) => {
+                        for info in neighbours {
+                            self.lookups.get_mut(&id).unwrap().add(info);
+                        }
+                        self.step_lookup(id, now);
+                    }
+                    None => {
+                        self.start_lookup(target, LookupKind::FindNode, now);
+                    }
+                }
+            }
+            TxKind::Lookup(lookup_id) => {
+                if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                    lookup.outstanding -= 1;
+                    if let LookupKind::Get { ref salt, .. } = lookup.kind {
+                        if let Some(item) = response.item(salt) {
+                            let newer = match self.found.get(&lookup.target) {
+                                Some(known) => item.seq() > known.seq(),
+                                None => true,
+                            };
+                            if newer && item.target() == lookup.target && item.check().is_ok() {
+                                self.found.insert(lookup.target, item);
+                            }
+                        }
+                    }
+                    let distance = response.id.distance(&lookup.target);
+                    lookup
+                        .candidates
+                        .entry(distance)
+                        .or_insert((NodeInfo { id: response.id, addr }, Candidate::Queried))
+                        .1 = Candidate::Responded(response.token.clone());
+                    for info in response.nodes {
+                        if info.id != self.id {
+                            lookup.add(info);
+                        }
+                    }
+                    if let LookupKind::GetPeers { ref queue, .. } = lookup.kind {
+                        for peer in response.values {
+                            queue.push(peer);
+                        }
+                    }
+                }
+                self.step_lookup(lookup_id, now);
+            }
+        }
+    }
+}
+
+/// The value of a mutable torrent's item: a dict with its info hash.
+pub fn torrent_value(info_hash: &Sha1Hash) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    dict.insert("ih", ByteBuf::from(info_hash.as_bytes().to_vec()));
+    to_bytes(&dict).expect("torrent value always serializes")
+}
+
+pub fn info_hash_from_value(value: &[u8]) -> Option<Sha1Hash> {
+    let dict: HashMap<String, ByteBuf> = from_bytes(value).ok()?;
+    dict.get("ih").and_then(|ih| Sha1Hash::new(ih.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, thread};
+    use testutil::TempDir;
+
+    fn local_node(bootstrap: Option<SocketAddr>) -> Dht {
+        let mut config = DhtConfig::new("127.0.0.1:0".parse().unwrap());
+        config.bootstrap = bootstrap.into_iter().map(|addr| addr.to_string()).collect();
+        Dht::new(config).expect("bind")
+    }
+
+    fn pump(nodes: &mut [Dht], rounds: usize) {
+        for _ in 0..rounds {
+            thread::sleep(Duration::from_millis(2));
+            let now = Instant::now();
+            for node in nodes.iter_mut() {
+                node.ready(now).expect("ready");
+                node.tick(now);
+            }
+        }
+    }
+
+    #[test]
+    fn distance_and_leading_zeros() {
+        let a = NodeId::from_bytes(&[0xff; 20]).unwrap();
+        let mut bytes = [0xff; 20];
+        bytes[1] = 0xf0;
+        let b = NodeId::from_bytes(&bytes).unwrap();
+        assert_eq!(a.distance(&b).leading_zeros(), 12);
+        assert_eq!(a.distance(&a).leading_zeros(), 160);
+    }
+
+    #[test]
+    fn only_the_node_asked_can_answer() {
+        let mut node = local_node(None);
+        let asked: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let id = NodeId::from_bytes(&[7; 20]).unwrap();
+        let now = Instant::now();
+        node.table.insert(NodeInfo { id, addr: asked }, now);
+        let lookup = node.start_lookup(id, LookupKind::FindNode, now);
+        assert_eq!(node.lookups[&lookup].outstanding, 1);
+        let transaction = node.transactions.keys().next().unwrap().clone();
+        let reply = |body| {
+            KrpcMessage {
+                transaction: transaction.clone(),
+                body,
+                ip: None,
+            }.encode()
+        };
+        let response = Body::Response(Response {
+            id,
+            ..Response::default()
+        });
+
+        // Someone else echoing the transaction id changes nothing.
+        let stranger = "127.0.0.1:10".parse().unwrap();
+        node.handle(&reply(response.clone()), stranger, now);
+        node.handle(&reply(Body::Error(201, "no".to_string())), stranger, now);
+        assert_eq!(node.lookups[&lookup].outstanding, 1);
+        assert!(node.transactions.contains_key(&transaction));
+
+        node.handle(&reply(response), asked, now);
+        assert!(node.transactions.is_empty());
+        assert!(!node.lookups.contains_key(&lookup));
+    }
+
+    #[test]
+    fn keep_only_so_many_announces_and_items() {
+        let mut node = local_node(None);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let start = Instant::now();
+        let token = node.tokens.token(&addr.ip());
+        let announce = |node: &mut Dht, info_hash: NodeId, port: u16, at: Instant| {
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token: token.clone(),
+            };
+            node.answer(query, addr, at);
+        };
+
+        // Too many peers for one torrent: the first to announce goes.
+        let first = NodeId::from_bytes(&[0; 20]).unwrap();
+        for n in 0..MAX_PEERS_PER_TORRENT + 1 {
+            let at = start + Duration::from_millis(n as u64);
+            announce(&mut node, first, 1000 + n as u16, at);
+        }
+        let peers = &node.peers[&first].1;
+        assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+        assert!(!peers.contains_key(&"127.0.0.1:1000".parse().unwrap()));
+
+        // Too many torrents: the one announced longest ago goes.
+        for n in 1..MAX_TORRENTS + 1 {
+            let mut bytes = [1; 20];
+            bytes[0] = (n >> 8) as u8;
+            bytes[1] = n as u8;
+            let info_hash = NodeId::from_bytes(&bytes).unwrap();
+            announce(&mut node, info_hash, 7000, start + Duration::from_secs(n as u64));
+        }
+        assert_eq!(node.peers.len(), MAX_TORRENTS);
+        assert!(!node.peers.contains_key(&first));
+
+        // And too many items.
+        let put = |n: usize| Query::Put {
+            token: token.clone(),
+            item: Item::Immutable(format!("i{}e", n).into_bytes()),
+            cas: None,
+        };
+        for n in 0..MAX_ITEMS + 1 {
+            node.answer(put(n), addr, start + Duration::from_millis(n as u64));
+        }
+        assert_eq!(node.stored.len(), MAX_ITEMS);
+        assert!(!node.stored.contains_key(&Item::Immutable(b"i0e".to_vec()).target()));
+    }
+
+    #[test]
+    fn refuse_another_value_at_the_same_seq() {
+        let mut node = local_node(None);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let token = node.tokens.token(&addr.ip());
+        let keypair = item::generate_keypair();
+        let mut put = |seq, value: &[u8]| {
+            let item = MutableItem::sign(&keypair, b"", seq, value.to_vec());
+            let query = Query::Put {
+                token: token.clone(),
+                item: Item::Mutable(item),
+                cas: None,
+            };
+            match node.answer(query, addr, Instant::now()) {
+                Body::Error(code, _) => Some(code),
+                _ => None,
+            }
+        };
+        assert_eq!(put(1, b"1:a"), None);
+        assert_eq!(put(1, b"1:a"), None);
+        assert_eq!(put(1, b"1:b"), Some(item::ERROR_OLD_SEQ));
+        assert_eq!(put(0, b"1:a"), Some(item::ERROR_OLD_SEQ));
+        assert_eq!(put(2, b"1:b"), None);
+    }
+
+    #[test]
+    fn one_vote_per_network_for_our_address() {
+        let mut node = local_node(None);
+        let ours: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut now = Instant::now();
+        let mut vote = |node: &mut Dht, voter: &str, ip: IpAddr| {
+            now += Duration::from_secs(1);
+            node.vote_external_ip(voter.parse().unwrap(), ip, now);
+        };
+
+        // A crowd of voters, each with its own idea.
+        for n in 0..MAX_VOTERS - 2 {
+            let voter = format!("10.0.{}.1:1000", n);
+            let ip = voter.parse::<SocketAddr>().unwrap().ip();
+            vote(&mut node, &voter, ip);
+        }
+        // Many ports on one host, or hosts on one /24, are one vote.
+        for n in 0..EXTERNAL_IP_VOTES {
+            vote(&mut node, &format!("198.51.100.1:{}", 1000 + n), ours);
+            vote(&mut node, &format!("198.51.100.{}:1000", 2 + n), ours);
+        }
+        vote(&mut node, "192.0.2.1:1000", ours);
+        assert_eq!(node.external_ip(), None);
+        // Room for a new voter is made by dropping the oldest vote, not
+        // everyone's.
+        vote(&mut node, "192.0.3.1:1000", ours);
+        assert_eq!(node.external_ip(), Some(ours));
+        assert_eq!(node.ip_votes.len(), MAX_VOTERS);
+    }
+
+    #[test]
+    fn look_up_bootstrap_names_off_the_loop() {
+        let first = local_node(None);
+        let host = format!("localhost:{}", first.local_addr().unwrap().port());
+        let mut nodes = vec![local_node(None), first];
+        nodes[0].add_bootstrap(&[host], Instant::now());
+        // Nothing is sent until the name has been looked up.
+        assert!(nodes[0].transactions.is_empty());
+        for _ in 0..100 {
+            pump(&mut nodes, 1);
+            if nodes[0].routing_table().get(nodes[1].id()).is_some() {
+                return;
+            }
+        }
+        panic!("never heard from the bootstrap node");
+    }
+
+    #[test]
+    fn restart_from_state_file() {
+        let first = local_node(None);
+        let mut nodes = vec![local_node(Some(first.local_addr().unwrap())), first];
+        pump(&mut nodes, 20);
+
+        let port = nodes[0].local_addr().unwrap().port();
+        let dir = TempDir::new("dht");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("dht-{}.dat", port));
+        let mut config = DhtConfig::new("127.0.0.1:0".parse().unwrap());
+        config.state_file = Some(path.clone());
+        nodes[0].state_file = Some(path.clone());
+        nodes[0].save_state().unwrap();
+        let restarted = Dht::new(config).expect("bind");
+        assert_eq!(restarted.id(), nodes[0].id());
+        assert!(restarted.routing_table().get(nodes[1].id()).is_some());
+    }
+
+    #[test]
+    fn loopback_network_announce_and_get_peers() {
+        let first = local_node(None);
+        let first_addr = first.local_addr().unwrap();
+        let mut nodes = vec![first];
+        for _ in 0..5 {
+            nodes.push(local_node(Some(first_addr)));
+        }
+        pump(&mut nodes, 50);
+        for node in &nodes {
+            assert!(node.routing_table().len() >= 3);
+        }
+        // All of them are one host, so they can't vote us an address.
+        assert_eq!(nodes[5].external_ip(), None);
+
+        let info_hash = NodeId::from_bytes(&[0x42; 20]).unwrap();
+        let now = Instant::now();
+        nodes[4].get_peers(info_hash, ConnectQueue::new(), Some(7000), now);
+        pump(&mut nodes, 50);
+
+        let queue = ConnectQueue::new();
+        nodes[2].get_peers(info_hash, queue.clone(), None, Instant::now());
+        pump(&mut nodes, 50);
+        assert_eq!(queue.pop(), Some("127.0.0.1:7000".parse().unwrap()));
+    }
+
+    #[test]
+    fn publish_and_follow_a_mutable_torrent() {
+        let first = local_node(None);
+        let first_addr = first.local_addr().unwrap();
+        let mut nodes = vec![first];
+        for _ in 0..5 {
+            nodes.push(local_node(Some(first_addr)));
+        }
+        pump(&mut nodes, 50);
+
+        let keypair = item::generate_keypair();
+        let key = keypair.public.to_bytes();
+        let v1 = Sha1Hash::new(vec![1; 20]).unwrap();
+        nodes[1]
+            .publish_torrent(&keypair, b"nightly", 1, &v1, Instant::now())
+            .unwrap();
+        pump(&mut nodes, 50);
+        nodes[3].follow_torrent(&key, b"nightly", Instant::now());
+        pump(&mut nodes, 50);
+        assert_eq!(nodes[3].torrent(&key, b"nightly"), Some((1, v1)));
+
+        let v2 = Sha1Hash::new(vec![2; 20]).unwrap();
+        nodes[1]
+            .publish_torrent(&keypair, b"nightly", 2, &v2, Instant::now())
+            .unwrap();
+        pump(&mut nodes, 50);
+        nodes[3].follow_torrent(&key, b"nightly", Instant::now());
+        pump(&mut nodes, 50);
+        assert_eq!(nodes[3].torrent(&key, b"nightly"), Some((2, v2)));
+
+        let target = nodes[2].put_immutable(b"12:Hello World!", Instant::now()).unwrap();
+        pump(&mut nodes, 50);
+        nodes[4].get_immutable(target, Instant::now());
+        pump(&mut nodes, 50);
+        assert_eq!(
+            nodes[4].item(&target),
+            Some(&Item::Immutable(b"12:Hello World!".to_vec()))
+        );
+    }
+}
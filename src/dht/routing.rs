@@ -0,0 +1,247 @@
+//! The Kademlia routing table.
+//!
+//! Nodes are sorted into buckets by how many leading bits their id shares
+//! with ours, and each bucket holds up to `K` nodes.  Nodes that stop
+//! answering are replaced by new ones; good nodes are only pushed out by
+//! nodes whose ids pass the BEP 42 check when their own don't.  Each bucket
+//! holds at most one node per public IP.
+
+use std::time::{Duration, Instant};
+
+use dht::NodeId;
+use dht::krpc::NodeInfo;
+use dht::security;
+
+pub const K: usize = 8;
+
+/// A node we haven't heard from in this long might have gone away.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+// Queries in a row a node can fail before we give up on it.
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub info: NodeInfo,
+    pub last_seen: Instant,
+    pub failures: u32,
+    /// Whether the id is valid for the node's address (BEP 42).
+    pub secure: bool,
+}
+
+impl Node {
+    pub fn is_good(&self, now: Instant) -> bool {
+        self.failures == 0 && now.duration_since(self.last_seen) < QUESTIONABLE_AFTER
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> RoutingTable {
+        RoutingTable {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let shared = self.id.distance(id).leading_zeros();
+        if shared >= 160 {
+            None
+        } else {
+            Some(shared)
+        }
+    }
+
+    /// Adds a node we've just heard from, or refreshes it if we know it.
+    /// Returns false if its bucket is full of good nodes.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> bool {
+        let index = match self.bucket_index(&info.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let secure = security::is_secure(&info.id, &info.addr.ip());
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.iter_mut().find(|node| node.info.id == info.id) {
+            node.info.addr = info.addr;
+            node.last_seen = now;
+            node.failures = 0;
+            node.secure = secure;
+            return true;
+        }
+        let node = Node {
+            info,
+            last_seen: now,
+            failures: 0,
+            secure,
+        };
+        // Someone else at the same public IP can only take over a slot that
+        // has gone bad or quiet.
+        let ip = info.addr.ip();
+        if !security::is_local(&ip) {
+            if let Some(i) = bucket.iter().position(|node| node.info.addr.ip() == ip) {
+                if bucket[i].is_good(now) {
+                    return false;
+                }
+                bucket[i] = node;
+                return true;
+            }
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        // Replace the worst node, if any has gone bad or quiet, or if we're
+        // secure and it isn't.
+        let worst = bucket
+            .iter()
+            .enumerate()
+            .filter(|&(_, old)| !old.is_good(now) || (secure && !old.secure))
+            .max_by_key(|&(_, old)| {
+                (!old.secure, old.failures, now.duration_since(old.last_seen))
+            })
+            .map(|(i, _)| i);
+        match worst {
+            Some(i) => {
+                bucket[i] = node;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a query to a node that went unanswered.
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            let bucket = &mut self.buckets[index];
+            if let Some(node) = bucket.iter_mut().find(|node| node.info.id == *id) {
+                node.failures += 1;
+            }
+            bucket.retain(|node| !node.is_bad());
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|node| node.info.id != *id);
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&Node> {
+        self.bucket_index(id)
+            .and_then(|index| self.buckets[index].iter().find(|node| node.info.id == *id))
+    }
+
+    /// The `count` nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes().map(|node| node.info).collect();
+        nodes.sort_by_key(|info| info.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes<'a>(&'a self) -> Box<Iterator<Item = &'a Node> + 'a> {
+        Box::new(self.buckets.iter().flat_map(|bucket| bucket.iter()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, SocketAddr};
+
+    fn info(first: u8, last: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        NodeInfo {
+            id: NodeId::from_bytes(&id).unwrap(),
+            addr: format!("127.0.0.1:{}", 6000 + last as u16).parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn buckets_hold_k_good_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId::from_bytes(&[0; 20]).unwrap());
+        // All of these share no leading bits with us, so share a bucket.
+        for i in 0..K as u8 {
+            assert!(table.insert(info(0x80, i), now));
+        }
+        assert!(!table.insert(info(0x80, 100), now));
+        assert_eq!(table.len(), K);
+
+        // Once a node fails enough, there's room again.
+        table.failed(&info(0x80, 3).id);
+        table.failed(&info(0x80, 3).id);
+        assert!(table.insert(info(0x80, 100), now));
+        assert!(table.get(&info(0x80, 3).id).is_none());
+
+        // Our own id never goes in the table.
+        assert!(!table.insert(info(0, 0), now));
+    }
+
+    #[test]
+    fn secure_ids_win_and_one_node_per_ip() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId::from_bytes(&[0; 20]).unwrap());
+        let public = |first: u8, ip: &str| NodeInfo {
+            id: {
+                let mut id = [0; 20];
+                id[0] = first;
+                NodeId::from_bytes(&id).unwrap()
+            },
+            addr: format!("{}:6881", ip).parse().unwrap(),
+        };
+        assert!(table.insert(public(0x80, "203.0.113.1"), now));
+        // A second id at the same address doesn't get its own slot.
+        assert!(!table.insert(public(0x81, "203.0.113.1"), now));
+        for i in 1..K as u8 {
+            assert!(table.insert(public(0x80 + i, &format!("203.0.113.{}", i + 1)), now));
+        }
+        assert!(!table.insert(public(0x90, "203.0.113.100"), now));
+
+        // None of those ids pass BEP 42, so a node whose does can push one
+        // out even though they're all good.
+        let ip: IpAddr = "203.0.113.100".parse().unwrap();
+        let mut id = security::generate_id(&ip);
+        while id.leading_zeros() != 0 {
+            id = security::generate_id(&ip);
+        }
+        assert!(table.insert(NodeInfo { id, addr: SocketAddr::new(ip, 6881) }, now));
+        assert!(table.get(&id).unwrap().secure);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn closest_by_xor_distance() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId::from_bytes(&[0; 20]).unwrap());
+        for &first in &[0x01, 0x02, 0x40, 0x80, 0xc0] {
+            table.insert(info(first, 1), now);
+        }
+        let target = info(0x81, 1).id;
+        let closest: Vec<_> = table.closest(&target, 2).iter().map(|n| n.id).collect();
+        assert_eq!(closest, vec![info(0x80, 1).id, info(0xc0, 1).id]);
+    }
+}
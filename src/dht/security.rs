@@ -0,0 +1,120 @@
+//! The DHT security extension (BEP 42).
+//!
+//! A node's id has to be derived from its IP address: the first 21 bits
+//! are a CRC32-C of the masked address and a small random number, which is
+//! also stored in the id's last byte.  That stops a single host from
+//! picking ids next to any target it likes.
+
+use std::net::IpAddr;
+
+use rand;
+
+use dht::NodeId;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// CRC32-C (Castagnoli), as BEP 42 asks for.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Addresses that can't be checked, because many hosts share them.
+pub fn is_local(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_private() || ip.is_loopback() || ip.is_link_local()
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
+
+fn id_prefix(ip: &IpAddr, r: u8) -> u32 {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let mut masked = ip.octets();
+            for (byte, mask) in masked.iter_mut().zip(V4_MASK.iter()) {
+                *byte &= mask;
+            }
+            masked[0] |= (r & 0x07) << 5;
+            crc32c(&masked)
+        }
+        IpAddr::V6(ip) => {
+            let mut masked = [0; 8];
+            masked.copy_from_slice(&ip.octets()[..8]);
+            for (byte, mask) in masked.iter_mut().zip(V6_MASK.iter()) {
+                *byte &= mask;
+            }
+            masked[0] |= (r & 0x07) << 5;
+            crc32c(&masked)
+        }
+    }
+}
+
+/// A random node id that is valid for `ip`.
+pub fn generate_id(ip: &IpAddr) -> NodeId {
+    let mut id: [u8; 20] = rand::random();
+    let r = id[19];
+    let crc = id_prefix(ip, r);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    NodeId::from_bytes(&id).expect("20 bytes")
+}
+
+/// Whether `id` is a valid id for a node at `ip`.  Local addresses are
+/// always allowed.
+pub fn is_secure(id: &NodeId, ip: &IpAddr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let id = id.as_bytes();
+    let crc = id_prefix(ip, id[19]);
+    id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(hex: &str) -> NodeId {
+        let bytes: Vec<u8> = (0..20)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect();
+        NodeId::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn bep_42_vectors() {
+        let vectors = [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ];
+        for &(ip, hex) in &vectors {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(is_secure(&id(hex), &ip));
+            assert!(is_secure(&generate_id(&ip), &ip));
+        }
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        assert!(!is_secure(&id("5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"), &ip));
+        let local: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(is_secure(&id("5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"), &local));
+    }
+}
@@ -0,0 +1,83 @@
+//! Saving the DHT between runs, so a restart doesn't have to bootstrap
+//! from scratch.
+//!
+//! The state file is a bencoded dict with our node `id` and the routing
+//! table's `nodes`, in the same compact form `find_node` responses use.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bytes::ByteBuf;
+
+use dht::NodeId;
+use dht::krpc::{decode_nodes, encode_nodes, NodeInfo};
+
+#[derive(Serialize, Deserialize)]
+struct RawState {
+    id: ByteBuf,
+    #[serde(default)]
+    nodes: ByteBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl DhtState {
+    pub fn encode(&self) -> Vec<u8> {
+        let raw = RawState {
+            id: ByteBuf::from(self.id.as_bytes().to_vec()),
+            nodes: ByteBuf::from(encode_nodes(&self.nodes)),
+        };
+        to_bytes(&raw).expect("dht state always serializes")
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<DhtState> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let raw: RawState = from_bytes(bytes).map_err(|_| invalid("bad dht state"))?;
+        Ok(DhtState {
+            id: NodeId::from_bytes(&raw.id).ok_or_else(|| invalid("node id is not 20 bytes"))?,
+            nodes: decode_nodes(&raw.nodes),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<DhtState> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        DhtState::decode(&bytes)
+    }
+
+    /// Writes the state to a temporary file first, so a crash mid-write
+    /// leaves the old state intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)?.write_all(&self.encode())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let state = DhtState {
+            id: NodeId::from_bytes(&[7; 20]).unwrap(),
+            nodes: vec![NodeInfo {
+                id: NodeId::from_bytes(&[9; 20]).unwrap(),
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            }],
+        };
+        let encoded = state.encode();
+        assert!(encoded.starts_with(b"d2:id20:\x07"));
+        assert_eq!(DhtState::decode(&encoded).unwrap(), state);
+        assert!(DhtState::decode(b"d2:id3:abce").is_err());
+    }
+}
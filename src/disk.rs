@@ -0,0 +1,294 @@
+//! Disk work, off the network thread.
+//!
+//! Reads, writes, piece checks, file allocation and moves go to a pool of
+//! worker threads, so a slow disk holds up only the disk.  Results come
+//! back over a channel, and a `mio::Registration` wakes the session's
+//! `Poll` when they do.  The queue is bounded: once it's full, the session stops
+//! reading from sockets until the disk catches up.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
+use storage::{Block, Storage};
+use verify::PieceVerifier;
+
+pub const DEFAULT_DISK_THREADS: usize = 4;
+pub const DEFAULT_DISK_QUEUE: usize = 256;
+
+pub enum DiskJob {
+    /// Stores a block that came in for a torrent.
+    Write {
+        info_hash: Vec<u8>,
+        storage: Arc<Storage>,
+        piece: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    /// Reads a block for the peer on the connection with this token.
+    Read {
+        token: Token,
+        storage: Arc<Storage>,
+        piece: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// Checks a piece whose blocks are all written, and tells the storage
+    /// how it went.
+    Check {
+        info_hash: Vec<u8>,
+        storage: Arc<Storage>,
+        verifier: Arc<PieceVerifier>,
+        piece: u32,
+    },
+    Allocate {
+        info_hash: Vec<u8>,
+        storage: Arc<Storage>,
+        file: usize,
+    },
+    /// Moves a torrent's files to a new download directory.
+    Move {
+        info_hash: Vec<u8>,
+        storage: Arc<Storage>,
+        path: PathBuf,
+    },
+}
+
+pub enum DiskResult {
+    Written {
+        info_hash: Vec<u8>,
+        piece: u32,
+        begin: u32,
+        result: io::Result<()>,
+    },
+    Read {
+        token: Token,
+        piece: u32,
+        begin: u32,
+        result: io::Result<Block>,
+    },
+    Checked {
+        info_hash: Vec<u8>,
+        piece: u32,
+        result: io::Result<bool>,
+    },
+    Allocated {
+        info_hash: Vec<u8>,
+        result: io::Result<()>,
+    },
+    Moved {
+        info_hash: Vec<u8>,
+        result: io::Result<()>,
+    },
+}
+
+impl DiskJob {
+    fn run(self) -> DiskResult {
+        match self {
+            DiskJob::Write {
+                info_hash,
+                storage,
+                piece,
+                begin,
+                data,
+            } => DiskResult::Written {
+                info_hash,
+                piece,
+                begin,
+                result: storage.write_block(piece, begin, &data),
+            },
+            DiskJob::Read {
+                token,
+                storage,
+                piece,
+                begin,
+                length,
+            } => DiskResult::Read {
+                token,
+                piece,
+                begin,
+                result: storage.read_shared(piece, begin, length),
+            },
+            DiskJob::Check {
+                info_hash,
+                storage,
+                verifier,
+                piece,
+            } => {
+                let result = verifier.check(&*storage, piece).and_then(|good| {
+                    storage.piece_checked(piece, good)?;
+                    Ok(good)
+                });
+                DiskResult::Checked {
+                    info_hash,
+                    piece,
+                    result,
+                }
+            }
+            DiskJob::Allocate {
+                info_hash,
+                storage,
+                file,
+            } => DiskResult::Allocated {
+                info_hash,
+                result: storage.allocate(file),
+            },
+            DiskJob::Move {
+                info_hash,
+                storage,
+                path,
+            } => DiskResult::Moved {
+                info_hash,
+                result: storage.move_to(&path),
+            },
+        }
+    }
+}
+
+pub struct DiskPool {
+    // None once we're shutting down, so the workers see the channel close.
+    jobs: Option<Sender<DiskJob>>,
+    results: Receiver<DiskResult>,
+    registration: Registration,
+    readiness: SetReadiness,
+    workers: Vec<JoinHandle<()>>,
+    // Jobs handed out whose results we haven't collected.
+    pending: usize,
+    max_pending: usize,
+}
+
+impl DiskPool {
+    /// Starts `threads` workers, and holds at most `max_pending` jobs.
+    pub fn new(threads: usize, max_pending: usize) -> DiskPool {
+        let (jobs, queue) = mpsc::channel::<DiskJob>();
+        let (done, results) = mpsc::channel();
+        let (registration, readiness) = Registration::new2();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let done = done.clone();
+                let readiness = readiness.clone();
+                thread::spawn(move || loop {
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if done.send(job.run()).is_err() {
+                        return;
+                    }
+                    let _ = readiness.set_readiness(Ready::readable());
+                })
+            })
+            .collect();
+        DiskPool {
+            jobs: Some(jobs),
+            results,
+            registration,
+            readiness,
+            workers,
+            pending: 0,
+            max_pending,
+        }
+    }
+
+    /// Has `poll` report `token` readable when results are in.
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&self.registration, token, Ready::readable(), PollOpt::edge())
+    }
+
+    pub fn submit(&mut self, job: DiskJob) {
+        if let Some(ref jobs) = self.jobs {
+            if jobs.send(job).is_ok() {
+                self.pending += 1;
+            }
+        }
+    }
+
+    /// Whether the queue is full, and new work should wait.
+    pub fn is_full(&self) -> bool {
+        self.pending >= self.max_pending
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// The results that are in.  Readiness is cleared before looking, so a
+    /// job that finishes meanwhile wakes the poll again.
+    pub fn results(&mut self) -> Vec<DiskResult> {
+        let _ = self.readiness.set_readiness(Ready::empty());
+        let results: Vec<DiskResult> = self.results.try_iter().collect();
+        self.pending -= results.len();
+        results
+    }
+}
+
+impl Drop for DiskPool {
+    /// Finishes the jobs already handed out, so no write is lost.
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use mio::Events;
+    use std::time::Duration;
+    use storage::MemoryStorage;
+    use testutil::read_torrent;
+
+    #[test]
+    fn results_wake_the_poll() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let storage: Arc<Storage> = Arc::new(MemoryStorage::new(&mi.info).unwrap());
+        let poll = Poll::new().unwrap();
+        let mut pool = DiskPool::new(2, 2);
+        pool.register(&poll, Token(7)).unwrap();
+
+        pool.submit(DiskJob::Write {
+            info_hash: vec![1; 20],
+            storage: storage.clone(),
+            piece: 2,
+            begin: 0,
+            data: vec![b'x'; 100],
+        });
+        pool.submit(DiskJob::Write {
+            info_hash: vec![1; 20],
+            storage: storage.clone(),
+            piece: 2,
+            begin: 100,
+            data: vec![b'x'; 1],
+        });
+        assert!(pool.is_full());
+
+        let mut events = Events::with_capacity(4);
+        let mut results = Vec::new();
+        while results.len() < 2 {
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(events.iter().next().map(|e| e.token()), Some(Token(7)));
+            results.extend(pool.results());
+        }
+        assert!(pool.is_idle());
+        let failed = results
+            .iter()
+            .filter(|result| match **result {
+                DiskResult::Written { ref result, .. } => result.is_err(),
+                _ => false,
+            })
+            .count();
+        // The second write ran past the end of the piece.
+        assert_eq!(failed, 1);
+        assert_eq!(storage.read_block(2, 0, 100).unwrap(), vec![b'x'; 100]);
+    }
+}
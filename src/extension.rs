@@ -0,0 +1,409 @@
+//! The extension protocol (BEP 10).
+//!
+//! Peers that set the extension bit in their handshake exchange an
+//! extended handshake, whose `m` dict maps extension names to the message
+//! ids each side wants to receive them under.  Extensions register with an
+//! `ExtensionRegistry`, which assigns our ids, routes incoming payloads to
+//! the right extension, and frames replies with the peer's ids.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::time::Instant;
+
+use serde::de::{Deserialize, Deserializer};
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use peermsg;
+
+/// The client name we send in `v`.
+pub const CLIENT_VERSION: &str = concat!("RottenBrit ", env!("CARGO_PKG_VERSION"));
+
+/// How many outstanding requests we let a peer queue up.
+pub const REQUEST_QUEUE: u32 = 250;
+
+/// The handshake is read leniently, since clients disagree about the
+/// types of its fields: a field that makes no sense is left out, rather
+/// than failing the whole handshake.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names, and the ids the sender wants them sent with.  An
+    /// id of 0 means the extension is disabled.
+    #[serde(default, deserialize_with = "lenient_ids")]
+    pub m: BTreeMap<String, u8>,
+    #[serde(default, deserialize_with = "lenient_string", skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// The sender's listen port.
+    #[serde(default, deserialize_with = "lenient_int", skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, deserialize_with = "lenient_int", skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// Our address as the sender sees it, in compact form.
+    #[serde(default, deserialize_with = "lenient_bytes", skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(default, deserialize_with = "lenient_int", skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+// An integer, or a string of one.
+fn int_value(value: &Value) -> Option<i64> {
+    match *value {
+        Value::Int(n) => Some(n),
+        Value::Bytes(ref bytes) => str::from_utf8(bytes).ok()?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn lenient_int<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(int_value(&value).and_then(|n| T::try_from(n).ok()))
+}
+
+fn lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bytes(bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        Value::Int(n) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+fn lenient_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ByteBuf>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bytes(bytes) => Some(ByteBuf::from(bytes)),
+        _ => None,
+    })
+}
+
+fn lenient_ids<'de, D>(deserializer: D) -> Result<BTreeMap<String, u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let dict = match Value::deserialize(deserializer)? {
+        Value::Dict(dict) => dict,
+        _ => return Ok(BTreeMap::new()),
+    };
+    Ok(dict.into_iter()
+        .filter_map(|(name, id)| {
+            let name = String::from_utf8(name).ok()?;
+            let id = int_value(&id).and_then(|id| u8::try_from(id).ok())?;
+            Some((name, id))
+        })
+        .collect())
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        to_bytes(self).expect("handshake always serializes")
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<ExtendedHandshake> {
+        from_bytes(payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad extended handshake"))
+    }
+
+    pub fn set_yourip(&mut self, ip: IpAddr) {
+        self.yourip = Some(ByteBuf::from(match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }));
+    }
+
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let bytes = self.yourip.as_ref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+            16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A protocol extension, like ut_metadata.
+///
+/// `peer` identifies the connection, so that an extension can keep state
+/// across all the peers of a torrent.  Payloads returned from these methods
+/// are sent back to the same peer, under this extension's name.
+pub trait Extension {
+    /// The name the extension goes by in the `m` dict.
+    fn name(&self) -> &'static str;
+
+    /// Adds this extension's fields to the handshake we send.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when a peer's extended handshake arrives, whether or not the
+    /// peer supports this extension.
+    fn peer_handshake(
+        &mut self,
+        _peer: usize,
+        _handshake: &ExtendedHandshake,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    /// Handles a payload sent to this extension.
+    fn handle(&mut self, peer: usize, payload: &[u8]) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Called when a connection to a peer is set up.  `outgoing` is true if
+    /// we connected to it, rather than it to us.
+    fn peer_connected(&mut self, _peer: usize, _addr: SocketAddr, _outgoing: bool) {}
+
+    fn peer_gone(&mut self, _peer: usize) {}
+
+    /// Called periodically for each peer, for extensions that send messages
+    /// on a timer.
+    fn tick(&mut self, _peer: usize, _now: Instant) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// What one peer told us in its extended handshake.
+#[derive(Clone, Debug, Default)]
+pub struct PeerExtensions {
+    handshake: Option<ExtendedHandshake>,
+}
+
+impl PeerExtensions {
+    pub fn new() -> PeerExtensions {
+        PeerExtensions::default()
+    }
+
+    pub fn handshake(&self) -> Option<&ExtendedHandshake> {
+        self.handshake.as_ref()
+    }
+
+    /// The id the peer wants `name` messages sent with, if it supports it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.handshake
+            .as_ref()
+            .and_then(|handshake| handshake.m.get(name))
+            .cloned()
+            .and_then(|id| if id == 0 { None } else { Some(id) })
+    }
+}
+
+/// The extensions we support, with the ids we've assigned them.
+pub struct ExtensionRegistry {
+    // Our id for each extension is its index here, plus one.
+    extensions: Vec<Box<Extension>>,
+    port: Option<u16>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(port: Option<u16>) -> ExtensionRegistry {
+        ExtensionRegistry {
+            extensions: Vec::new(),
+            port,
+        }
+    }
+
+    /// Claims a name for an extension, and returns the id peers should use
+    /// to send us its messages.
+    pub fn register(&mut self, extension: Box<Extension>) -> io::Result<u8> {
+        if self.extensions.iter().any(|ext| ext.name() == extension.name()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "extension name already registered",
+            ));
+        }
+        if self.extensions.len() >= 255 {
+            return Err(io::Error::new(io::ErrorKind::Other, "out of extension ids"));
+        }
+        self.extensions.push(extension);
+        Ok(self.extensions.len() as u8)
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|ext| ext.name() == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Extension> {
+        match self.extensions.iter_mut().find(|ext| ext.name() == name) {
+            Some(ext) => Some(&mut **ext),
+            None => None,
+        }
+    }
+
+    /// The extended handshake message to send to a peer at `peer_ip`.
+    pub fn handshake(&self, peer_ip: Option<IpAddr>) -> Vec<u8> {
+        let mut handshake = ExtendedHandshake {
+            v: Some(CLIENT_VERSION.to_string()),
+            p: self.port,
+            reqq: Some(REQUEST_QUEUE),
+            ..ExtendedHandshake::default()
+        };
+        if let Some(ip) = peer_ip {
+            handshake.set_yourip(ip);
+        }
+        for (index, ext) in self.extensions.iter().enumerate() {
+            handshake.m.insert(ext.name().to_string(), index as u8 + 1);
+            ext.extend_handshake(&mut handshake);
+        }
+        peermsg::extended(0, &handshake.encode())
+    }
+
+    /// Handles an extended message from `peer`, and returns any messages
+    /// to send back, already framed.
+    pub fn handle(
+        &mut self,
+        peer: usize,
+        remote: &mut PeerExtensions,
+        id: u8,
+        payload: &[u8],
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let mut replies = Vec::new();
+        if id == 0 {
+            let handshake = ExtendedHandshake::decode(payload)?;
+            for ext in &mut self.extensions {
+                let payloads = ext.peer_handshake(peer, &handshake)?;
+                replies.push((ext.name(), payloads));
+            }
+            remote.handshake = Some(handshake);
+        } else {
+            // BEP 10 says to ignore ids we never handed out.
+            let ext = match self.extensions.get_mut(id as usize - 1) {
+                Some(ext) => ext,
+                None => return Ok(Vec::new()),
+            };
+            let payloads = ext.handle(peer, payload)?;
+            replies.push((ext.name(), payloads));
+        }
+        Ok(replies
+            .into_iter()
+            .filter_map(|(name, payloads)| remote.remote_id(name).map(|id| (id, payloads)))
+            .flat_map(|(id, payloads)| {
+                payloads
+                    .into_iter()
+                    .map(move |payload| peermsg::extended(id, &payload))
+            })
+            .collect())
+    }
+
+    /// Frames a payload for `name`, if the peer supports it.
+    pub fn message(&self, remote: &PeerExtensions, name: &str, payload: &[u8]) -> Option<Vec<u8>> {
+        remote.remote_id(name).map(|id| peermsg::extended(id, payload))
+    }
+
+    pub fn peer_connected(&mut self, peer: usize, addr: SocketAddr, outgoing: bool) {
+        for ext in &mut self.extensions {
+            ext.peer_connected(peer, addr, outgoing);
+        }
+    }
+
+    pub fn peer_gone(&mut self, peer: usize) {
+        for ext in &mut self.extensions {
+            ext.peer_gone(peer);
+        }
+    }
+
+    /// Gives each extension a chance to send timed messages to `peer`.
+    pub fn tick(&mut self, peer: usize, remote: &PeerExtensions, now: Instant) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for ext in &mut self.extensions {
+            if let Some(id) = remote.remote_id(ext.name()) {
+                for payload in ext.tick(peer, now) {
+                    messages.push(peermsg::extended(id, &payload));
+                }
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peermsg::{parse, Message};
+
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "rb_echo"
+        }
+
+        fn handle(&mut self, _peer: usize, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    fn extended_payload(msg: &[u8]) -> (u8, Vec<u8>) {
+        match parse(msg).unwrap().unwrap().0 {
+            Message::Extended(id, payload) => (id, payload),
+            other => panic!("Expected an extended message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_roundtrip() {
+        let mut registry = ExtensionRegistry::new(Some(6881));
+        assert_eq!(registry.register(Box::new(Echo)).unwrap(), 1);
+        assert!(registry.register(Box::new(Echo)).is_err());
+
+        let msg = registry.handshake(Some("192.168.1.2".parse().unwrap()));
+        let (id, payload) = extended_payload(&msg);
+        assert_eq!(id, 0);
+        let handshake = ExtendedHandshake::decode(&payload).unwrap();
+        assert_eq!(handshake.m.get("rb_echo"), Some(&1));
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, Some(REQUEST_QUEUE));
+        assert_eq!(handshake.yourip(), Some("192.168.1.2".parse().unwrap()));
+        assert!(handshake.v.unwrap().starts_with("RottenBrit"));
+    }
+
+    #[test]
+    fn route_with_remote_ids() {
+        let mut registry = ExtensionRegistry::new(None);
+        registry.register(Box::new(Echo)).unwrap();
+        let mut remote = PeerExtensions::new();
+
+        // Before the handshake, we don't know what id the peer wants.
+        assert!(registry.handle(0, &mut remote, 1, b"hi").unwrap().is_empty());
+
+        let replies = registry
+            .handle(0, &mut remote, 0, b"d1:md7:rb_echoi7eee")
+            .unwrap();
+        assert!(replies.is_empty());
+        assert_eq!(remote.remote_id("rb_echo"), Some(7));
+        assert_eq!(remote.remote_id("ut_metadata"), None);
+
+        let replies = registry.handle(0, &mut remote, 1, b"hi").unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(extended_payload(&replies[0]), (7, b"hi".to_vec()));
+        assert!(registry.handle(0, &mut remote, 2, b"hi").unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_sloppy_handshakes() {
+        let handshake = ExtendedHandshake::decode(
+            b"d1:md11:ut_metadatai3e6:ut_pex1:27:too_bigi300ee1:p4:68814:reqq3:5001:vi7e\
+              6:yourip3:abce",
+        ).unwrap();
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(handshake.m.get("ut_pex"), Some(&2));
+        assert_eq!(handshake.m.get("too_big"), None);
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.v, Some("7".to_string()));
+        // Not four or sixteen bytes, so no address.
+        assert_eq!(handshake.yourip(), None);
+        let handshake = ExtendedHandshake::decode(b"d1:mi0e1:p2:no4:reqqle1:vdee").unwrap();
+        assert_eq!(handshake, ExtendedHandshake::default());
+        assert!(ExtendedHandshake::decode(b"li1ee").is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Importing resume data from libtorrent-based clients, such as qBittorrent
+//! and Deluge, so their torrents can move over without a recheck.
+//!
+//! libtorrent's `.fastresume` files are bencoded dicts.  Pieces are one byte
+//! each, with the low bit set for pieces the client has; unfinished pieces
+//! list their 16 KiB blocks as a bitmask, low bit first.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use serde_bencode::de::from_bytes;
+use serde_bytes::ByteBuf;
+
+use bitfield::BitField;
+use compact;
+use metainfo::Sha1Hash;
+use peer::MAX_BLOCK;
+use picker::Priority;
+use resume::{FileState, ResumeData};
+use storage::Storage;
+
+#[derive(Deserialize)]
+struct RawUnfinished {
+    piece: u32,
+    bitmask: ByteBuf,
+}
+
+#[derive(Deserialize)]
+struct RawFastResume {
+    #[serde(rename = "file-format")]
+    file_format: Option<String>,
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    #[serde(default)]
+    pieces: ByteBuf,
+    #[serde(default)]
+    unfinished: Vec<RawUnfinished>,
+    save_path: Option<String>,
+    // qBittorrent keeps its own copy of the save path.
+    #[serde(rename = "qBt-savePath")]
+    qbt_save_path: Option<String>,
+    #[serde(default)]
+    file_priority: Vec<i64>,
+    #[serde(rename = "file sizes", default)]
+    file_sizes: Vec<(i64, i64)>,
+    #[serde(default)]
+    total_uploaded: i64,
+    #[serde(default)]
+    total_downloaded: i64,
+    #[serde(default)]
+    peers: ByteBuf,
+    #[serde(default)]
+    peers6: ByteBuf,
+}
+
+/// The parts of a libtorrent resume file we can use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FastResume {
+    pub info_hash: Sha1Hash,
+    /// One entry per piece: whether the client had it.
+    pub pieces: Vec<bool>,
+    /// Finished blocks of unfinished pieces, one entry per block.
+    pub unfinished: BTreeMap<u32, Vec<bool>>,
+    pub save_path: Option<PathBuf>,
+    /// libtorrent's priorities, 0 (skip) to 7 (top), one per file.
+    pub file_priority: Vec<u8>,
+    /// Size and modification time of each file, when the file was saved.
+    pub file_sizes: Vec<FileState>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FastResume {
+    pub fn decode(bytes: &[u8]) -> io::Result<FastResume> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let raw: RawFastResume = from_bytes(bytes).map_err(|_| invalid("bad fastresume file"))?;
+        match raw.file_format {
+            Some(ref format) if format != "libtorrent resume file" => {
+                return Err(invalid("not a libtorrent resume file"));
+            }
+            _ => {}
+        }
+        let mut unfinished = BTreeMap::new();
+        for piece in raw.unfinished {
+            let blocks = (0..piece.bitmask.len() * 8)
+                .map(|i| piece.bitmask[i / 8] & (1 << (i % 8)) != 0)
+                .collect();
+            unfinished.insert(piece.piece, blocks);
+        }
+        let mut peers = compact::read_v4_list(&raw.peers);
+        peers.extend(compact::read_v6_list(&raw.peers6));
+        let to_u64 = |n: i64| if n < 0 { 0 } else { n as u64 };
+        Ok(FastResume {
+            info_hash: Sha1Hash::new(raw.info_hash.to_vec())
+                .ok_or_else(|| invalid("info hash is not 20 bytes"))?,
+            pieces: raw.pieces.iter().map(|&piece| piece & 1 != 0).collect(),
+            unfinished,
+            save_path: raw.save_path.or(raw.qbt_save_path).map(PathBuf::from),
+            file_priority: raw.file_priority
+                .iter()
+                .map(|&priority| if priority < 0 { 0 } else { priority.min(7) as u8 })
+                .collect(),
+            file_sizes: raw.file_sizes
+                .iter()
+                .map(|&(size, mtime)| FileState {
+                    mtime: to_u64(mtime),
+                    size: to_u64(size),
+                })
+                .collect(),
+            uploaded: to_u64(raw.total_uploaded),
+            downloaded: to_u64(raw.total_downloaded),
+            peers,
+        })
+    }
+
+    /// Converts to our own resume data, for a torrent kept in `storage`.
+    /// Without `file sizes`, the files are taken as they are on disk now,
+    /// so the pieces are trusted without a recheck, as long as every file
+    /// they're in is there at full length.  If one isn't, the resume data
+    /// won't match the files, and the torrent is rechecked.
+    pub fn to_resume_data(&self, storage: &Storage) -> io::Result<ResumeData> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let layout = storage.layout();
+        let num_pieces = layout.num_pieces();
+        if self.pieces.len() != num_pieces {
+            return Err(invalid("resume file has the wrong number of pieces"));
+        }
+        let mut pieces = BitField::new(num_pieces);
+        for (i, &have) in self.pieces.iter().enumerate() {
+            pieces.set(i, have);
+        }
+        let mut partial = BTreeMap::new();
+        for (&piece, finished) in &self.unfinished {
+            if piece as usize >= num_pieces || pieces.get(piece as usize) {
+                continue;
+            }
+            let size = layout.piece_size(piece);
+            let mut blocks = BitField::new(((size + MAX_BLOCK - 1) / MAX_BLOCK) as usize);
+            for block in 0..blocks.len() {
+                blocks.set(block, finished.get(block).cloned().unwrap_or(false));
+            }
+            if !blocks.none() {
+                partial.insert(piece, blocks);
+            }
+        }
+        let files = if self.file_sizes.len() == layout.files().len() {
+            self.file_sizes.clone()
+        } else {
+            let states = storage.file_states()?;
+            let short = (0..num_pieces)
+                .filter(|&piece| pieces.get(piece))
+                .flat_map(|piece| layout.piece_files(piece as u32))
+                .any(|file| states[file].size < layout.files()[file].length);
+            if short {
+                Vec::new()
+            } else {
+                states
+            }
+        };
+        Ok(ResumeData {
+            info_hash: self.info_hash.clone(),
+            pieces,
+            partial,
+            files,
+            file_priority: self.file_priority
+                .iter()
+                .map(|&level| Priority::from_libtorrent(level))
+                .collect(),
+            save_path: self.save_path.clone(),
+            renamed: BTreeMap::new(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            peers: self.peers.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use storage::FileStorage;
+    use testutil::read_torrent;
+
+    /// A resume file like qBittorrent 4.0 writes, for `num_pieces` pieces
+    /// of which all but the first two are done, and the second is half
+    /// there.
+    fn qbittorrent_resume(info_hash: &[u8], num_pieces: usize) -> Vec<u8> {
+        let mut pieces = vec![1; num_pieces];
+        pieces[0] = 0;
+        pieces[1] = 0;
+        let mut b = b"d11:file-format22:libtorrent resume file12:file-versioni1e".to_vec();
+        b.extend(format!("13:file_priorityli0ei4ei7ee9:info-hash{}:", info_hash.len()).bytes());
+        b.extend(info_hash);
+        b.extend(b"5:peers6:\x0a\x00\x00\x01\x1a\xe1");
+        b.extend(format!("6:pieces{}:", num_pieces).bytes());
+        b.extend(&pieces);
+        b.extend(&b"12:qBt-savePath10:/downloads"[..]);
+        b.extend(&b"16:total_downloadedi2048e14:total_uploadedi4096e"[..]);
+        b.extend(&b"10:unfinishedld7:bitmask2:\x05\x005:piecei1eeee"[..]);
+        b
+    }
+
+    #[test]
+    fn import_qbittorrent_resume_file() {
+        let b = read_torrent("data/redox-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let storage = FileStorage::new(&mi.info, "/downloads").unwrap();
+        let num_pieces = storage.layout().num_pieces();
+        let fastresume = FastResume::decode(&qbittorrent_resume(&[3; 20], num_pieces)).unwrap();
+        assert_eq!(fastresume.save_path, Some(PathBuf::from("/downloads")));
+        assert_eq!(fastresume.file_priority, vec![0, 4, 7]);
+        assert_eq!(fastresume.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(fastresume.uploaded, 4096);
+
+        let resume = fastresume.to_resume_data(&storage).unwrap();
+        assert_eq!(resume.pieces.count(), num_pieces - 2);
+        // None of the files are in /downloads, so they're left to check.
+        assert!(resume.files.is_empty());
+        assert!(!resume.pieces.get(1) && resume.pieces.get(2));
+        // Blocks 0 and 2 of piece 1, low bit first.
+        let blocks = &resume.partial[&1];
+        assert!(blocks.get(0) && !blocks.get(1) && blocks.get(2));
+        assert_eq!(blocks.count(), 2);
+        assert_eq!(resume.downloaded, 2048);
+        assert_eq!(
+            resume.file_priority,
+            vec![Priority::Skip, Priority::Normal, Priority::High]
+        );
+
+        assert!(FastResume::decode(b"d11:file-format3:fooe").is_err());
+    }
+}
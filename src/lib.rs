@@ -1,116 +1,65 @@
 #[macro_use]
 extern crate serde_derive;
 
+extern crate ed25519_dalek;
+extern crate memmap;
 extern crate mio;
+extern crate rand;
 extern crate serde;
 extern crate serde_bencode;
 extern crate serde_bytes;
 extern crate sha1;
-
+extern crate sha2;
+extern crate url;
+
+pub mod bitfield;
+pub mod choker;
+pub mod compact;
+pub mod connect;
+pub mod dht;
+pub mod disk;
+pub mod extension;
+pub mod fastresume;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
 pub mod metainfo;
+pub mod peer;
 pub mod peermsg;
+pub mod picker;
+pub mod pex;
+pub mod ratelimit;
+pub mod reader;
+pub mod resume;
+pub mod session;
+pub mod storage;
+#[cfg(test)]
+mod testutil;
+pub mod verify;
 
-use std::collections::HashMap;
 use std::error::Error;
-use std::io;
-use std::io::prelude::*;
 use std::net::SocketAddr;
 
-use mio::*;
-use mio::net::{TcpListener, TcpStream};
-
-// Setup some tokens to allow us to identify which event is
-// for which socket.
-const LISTENER: Token = Token(0);
-
-enum State {
-    New,
-    Connected,
-}
-
-struct Piece;
-
-struct Torrent<'a> {
-    metainfo: metainfo::MetaInfo<'a>,
-    pieces: Vec<Piece>,
-    peers: Vec<Peer>,
-}
+const DHT_STATE_FILE: &str = "dht.dat";
 
-struct Peer {
-    peer_id: Vec<u8>,
-    choked: bool,
-    interested: bool,
-    bitfield: Vec<u8>,
+pub fn serve<T: Into<SocketAddr>>(addr: T) -> Result<(), Box<Error>> {
+    serve_with_limits(addr, ratelimit::TransferLimits::unlimited())
 }
 
-pub fn serve<T: Into<SocketAddr>>(addr: T) -> Result<(), Box<Error>> {
+/// Like `serve`, but keeps to `limits`.  Change them through the handles
+/// at any time.
+pub fn serve_with_limits<T: Into<SocketAddr>>(
+    addr: T,
+    limits: ratelimit::TransferLimits,
+) -> Result<(), Box<Error>> {
     let addr = addr.into();
-
-    // Setup the server socket
-    let listener = TcpListener::bind(&addr).unwrap();
-
-    // Create a poll instance
-    let poll = Poll::new().unwrap();
-
-    // Start listening for incoming connections
-    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge())
-        .unwrap();
-
-    // Create storage for events
-    let mut events = Events::with_capacity(1024);
-    let mut next_token_index = 1;
-    let mut sockets = HashMap::new();
-    let mut buf = [0; 1024];
-
-    loop {
-        poll.poll(&mut events, None).unwrap();
-
-        for event in events.iter() {
-            match event.token() {
-                LISTENER => loop {
-                    match listener.accept() {
-                        Ok((socket, _)) => {
-                            let token = Token(next_token_index);
-                            poll.register(&socket, token, Ready::readable(), PollOpt::edge())?;
-                            next_token_index += 1;
-                            sockets.insert(token, socket);
-                        }
-                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                            break;
-                        }
-                        err => panic!("err={:?}", err),
-                    }
-                },
-                token => {
-                    // Loop to drain the event buffer, because we are edge polling
-                    loop {
-                        match sockets.get_mut(&token).unwrap().read(&mut buf) {
-                            Ok(0) => {
-                                // Socket is closed; remove it from the hashmap
-                                sockets.remove(&token);
-                                break;
-                            }
-                            Ok(n) => {
-                                if buf[n - 1] == b'\n' {
-                                    println!("got a newline");
-                                    &buf[..(n - 1)].reverse();
-                                } else {
-                                    println!("got {}", buf[n - 1]);
-                                    &buf[..n].reverse();
-                                }
-                                sockets.get_mut(&token).unwrap().write(&buf[..n])?;
-                                // handle data
-                            }
-                            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                                // Socket is no longer ready; stop reading
-                                break;
-                            }
-                            err => panic!("err={:?}", err),
-                        }
-                    }
-                }
-            }
-        }
-    }
+    let mut config = session::SessionConfig::new(addr);
+    config.limits = limits;
+    // The DHT listens on the same port, over UDP.
+    let mut dht_config = dht::DhtConfig::new(addr);
+    dht_config.state_file = Some(DHT_STATE_FILE.into());
+    config.dht = Some(dht_config);
+    let mut session = session::Session::new(config)?;
+    session.run()?;
     Ok(())
 }
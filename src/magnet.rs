@@ -0,0 +1,299 @@
+//! Magnet URIs (BEP 9, BEP 53, and the v2 `btmh` form from BEP 52).
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use url::Url;
+use url::form_urlencoded::byte_serialize;
+
+use metainfo::{MetaInfo, Sha1Hash, Sha256Hash};
+
+// A multihash header: sha2-256, 32 bytes.
+const SHA256_MULTIHASH: &[u8] = b"\x12\x20";
+
+// The most file indices an `so` value may select, so a hostile range can't
+// make us allocate without limit.
+const MAX_SELECT_ONLY: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Magnet {
+    /// The v1 info hash, from `xt=urn:btih:`.
+    pub info_hash: Option<Sha1Hash>,
+    /// The v2 info hash, from `xt=urn:btmh:`.
+    pub info_hash_v2: Option<Sha256Hash>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+    /// File indices to download (`so`), in order.  Empty means all files.
+    pub select_only: Vec<usize>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn from_hex(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| input.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn to_hex(input: &[u8]) -> String {
+    input.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes RFC 4648 base32, as used by old 32-character `btih` hashes.
+fn from_base32(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Parses a BEP 53 `so` value, like `0,2,4-6`.  Values selecting more than
+/// `MAX_SELECT_ONLY` indices are refused.
+fn parse_select_only(input: &str) -> Option<Vec<usize>> {
+    let mut indices = Vec::new();
+    for part in input.split(',') {
+        let mut range = part.splitn(2, '-');
+        let start: usize = range.next()?.parse().ok()?;
+        let end: usize = match range.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        if end < start {
+            return None;
+        }
+        let count = (end - start).checked_add(1)?;
+        if count > MAX_SELECT_ONLY - indices.len() {
+            return None;
+        }
+        indices.extend((0..count).map(|i| start + i));
+    }
+    Some(indices)
+}
+
+fn format_select_only(indices: &[usize]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < indices.len() {
+        let start = indices[i];
+        while i + 1 < indices.len() && indices[i + 1] == indices[i] + 1 {
+            i += 1;
+        }
+        if indices[i] == start {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}-{}", start, indices[i]));
+        }
+        i += 1;
+    }
+    parts.join(",")
+}
+
+impl Magnet {
+    pub fn parse(input: &str) -> io::Result<Magnet> {
+        let url = Url::parse(input).map_err(|_| invalid("not a URI"))?;
+        if url.scheme() != "magnet" {
+            return Err(invalid("not a magnet URI"));
+        }
+        let mut magnet = Magnet {
+            info_hash: None,
+            info_hash_v2: None,
+            display_name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        };
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" if value.starts_with("urn:btih:") => {
+                    let hash = &value["urn:btih:".len()..];
+                    let bytes = match hash.len() {
+                        40 => from_hex(hash),
+                        32 => from_base32(hash),
+                        _ => None,
+                    };
+                    let hash = bytes.and_then(Sha1Hash::new).ok_or_else(|| invalid("bad btih"))?;
+                    magnet.info_hash = Some(hash);
+                }
+                "xt" if value.starts_with("urn:btmh:") => {
+                    let bytes = from_hex(&value["urn:btmh:".len()..])
+                        .ok_or_else(|| invalid("bad btmh"))?;
+                    if !bytes.starts_with(SHA256_MULTIHASH) {
+                        return Err(invalid("btmh is not a sha2-256 multihash"));
+                    }
+                    let hash = Sha256Hash::new(bytes[SHA256_MULTIHASH.len()..].to_vec())
+                        .ok_or_else(|| invalid("bad btmh"))?;
+                    magnet.info_hash_v2 = Some(hash);
+                }
+                "dn" => magnet.display_name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                // Host names would need a lookup, and a peer we can't use is
+                // no reason to refuse the rest, so those are skipped.
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>().ok()),
+                "so" => {
+                    magnet.select_only =
+                        parse_select_only(&value).ok_or_else(|| invalid("bad so"))?
+                }
+                // Other `xt` namespaces and unknown keys are ignored.
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(invalid("magnet URI has no info hash"));
+        }
+        Ok(magnet)
+    }
+
+    /// Builds a magnet link for a torrent, given its v1 info hash.
+    pub fn from_metainfo(metainfo: &MetaInfo, info_hash: &Sha1Hash) -> Magnet {
+        let mut trackers = Vec::new();
+        if !metainfo.announce.is_empty() {
+            trackers.push(metainfo.announce.to_string());
+        }
+        if let Some(ref tiers) = metainfo.announce_list {
+            for tracker in tiers.iter().flat_map(|tier| tier.iter()) {
+                if !trackers.iter().any(|t| t == tracker) {
+                    trackers.push(tracker.to_string());
+                }
+            }
+        }
+        Magnet {
+            info_hash: Some(info_hash.clone()),
+            info_hash_v2: None,
+            display_name: Some(metainfo.info.name().to_string()),
+            trackers,
+            web_seeds: metainfo
+                .url_list
+                .as_ref()
+                .map(|urls| urls.iter().map(|url| url.to_string()).collect())
+                .unwrap_or_default(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(ref hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", to_hex(hash.as_bytes())));
+        }
+        if let Some(ref hash) = self.info_hash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{}{}",
+                to_hex(SHA256_MULTIHASH),
+                to_hex(hash.as_bytes())
+            ));
+        }
+        let encode = |value: &str| byte_serialize(value.as_bytes()).collect::<String>();
+        if let Some(ref name) = self.display_name {
+            params.push(format!("dn={}", encode(name)));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", encode(tracker)));
+        }
+        for seed in &self.web_seeds {
+            params.push(format!("ws={}", encode(seed)));
+        }
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", peer));
+        }
+        if !self.select_only.is_empty() {
+            params.push(format!("so={}", format_select_only(&self.select_only)));
+        }
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::get_info_hash;
+    use testutil::read_torrent;
+
+    #[test]
+    fn parse_v1_hex() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+             &dn=Some+Name&tr=http%3A%2F%2Ftracker.example.com%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example.org%3A6969&x.pe=10.0.0.1:6881\
+             &x.pe=peer.example.com:6881&so=0,2,4-6",
+        ).expect("parse");
+        assert_eq!(
+            magnet.info_hash.unwrap().as_bytes()[..4],
+            [0xc1, 0x2f, 0xe1, 0xc0]
+        );
+        assert_eq!(magnet.display_name.unwrap(), "Some Name");
+        assert_eq!(magnet.trackers.len(), 2);
+        assert_eq!(magnet.trackers[1], "udp://tracker.example.org:6969");
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(magnet.select_only, vec![0, 2, 4, 5, 6]);
+        assert_eq!(parse_select_only("0-18446744073709551615"), None);
+        assert_eq!(parse_select_only("0-4000000000"), None);
+        assert_eq!(parse_select_only("18446744073709551615").map(|so| so.len()), Some(1));
+    }
+
+    #[test]
+    fn parse_v1_base32() {
+        let hex = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a")
+            .expect("hex");
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK")
+            .expect("base32");
+        assert_eq!(hex.info_hash, base32.info_hash);
+    }
+
+    #[test]
+    fn parse_v2() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e",
+        ).expect("parse");
+        assert!(magnet.info_hash.is_none());
+        assert_eq!(magnet.info_hash_v2.unwrap().as_bytes()[0], 0xca);
+    }
+
+    #[test]
+    fn reject_missing_hash() {
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        assert!(Magnet::parse("http://example.com/?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").is_err());
+    }
+
+    #[test]
+    fn from_metainfo_roundtrip() {
+        let b = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
+        let info_hash = get_info_hash(b.clone()).expect("info hash");
+        let info_hash = Sha1Hash::new(info_hash.digest().bytes().to_vec()).unwrap();
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let mut magnet = Magnet::from_metainfo(&mi, &info_hash);
+        magnet.select_only = vec![1, 2, 3, 7];
+        let link = magnet.to_string();
+        assert!(link.starts_with("magnet:?xt=urn:btih:"));
+        assert!(link.contains("&tr=http%3A%2F%2Ftracker.archlinux.org%3A6969%2Fannounce"));
+        assert!(link.ends_with("&so=1-3,7"));
+        assert_eq!(Magnet::parse(&link).expect("reparse"), magnet);
+    }
+}
@@ -0,0 +1,297 @@
+//! SHA-256 merkle trees for v2 (BEP 52) piece verification.
+//!
+//! Each file in a v2 torrent has its own tree.  The leaves are the hashes
+//! of the file's 16 KiB blocks, padded out with zero hashes to a power of
+//! two.  The `piece layers` entry for a file holds the layer of the tree
+//! where each node covers exactly one piece, so a piece can be checked on
+//! its own, and a block can be checked once we know its leaf hash.
+
+use sha2::{Digest, Sha256};
+
+use metainfo::Sha256Hash;
+
+pub const BLOCK_SIZE: usize = 0x4000;
+
+pub fn hash_block(data: &[u8]) -> Sha256Hash {
+    let mut sha = Sha256::default();
+    sha.input(data);
+    Sha256Hash::new(sha.result().to_vec()).unwrap()
+}
+
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut sha = Sha256::default();
+    sha.input(left.as_bytes());
+    sha.input(right.as_bytes());
+    Sha256Hash::new(sha.result().to_vec()).unwrap()
+}
+
+/// The root of a subtree with `width` leaves, all of them zero hashes.
+pub fn pad_hash(width: usize) -> Sha256Hash {
+    let mut hash = Sha256Hash::new(vec![0; 32]).unwrap();
+    let mut width = width;
+    while width > 1 {
+        hash = hash_pair(&hash, &hash);
+        width /= 2;
+    }
+    hash
+}
+
+/// The root of a tree over `leaves`, padded out to `width` leaves with
+/// `padding`, the root of an all-zero subtree at the level of the leaves.
+/// `width` must be a power of two no smaller than `leaves.len()`.
+fn root_with_padding(leaves: &[Sha256Hash], width: usize, padding: Sha256Hash) -> Sha256Hash {
+    assert!(width.is_power_of_two() && width >= leaves.len());
+    let mut layer = leaves.to_vec();
+    let mut padding = padding;
+    let mut width = width;
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(padding.clone());
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        padding = hash_pair(&padding, &padding);
+        width /= 2;
+    }
+    layer.pop().unwrap_or(padding)
+}
+
+/// The root of a tree whose leaves are `leaves`, padded with zero hashes
+/// out to `width` leaves.
+pub fn merkle_root(leaves: &[Sha256Hash], width: usize) -> Sha256Hash {
+    root_with_padding(leaves, width, pad_hash(1))
+}
+
+/// Climbs from the root of a subtree up to the root of the whole tree.
+///
+/// `index` is the subtree's position in its own layer, and `uncles` are
+/// the sibling hashes on the way up, nearest first, as sent in a `hashes`
+/// message.
+pub fn proof_root(subtree: Sha256Hash, index: usize, uncles: &[Sha256Hash]) -> Sha256Hash {
+    let mut hash = subtree;
+    let mut index = index;
+    for uncle in uncles {
+        hash = if index % 2 == 0 {
+            hash_pair(&hash, uncle)
+        } else {
+            hash_pair(uncle, &hash)
+        };
+        index /= 2;
+    }
+    hash
+}
+
+/// The outcome of checking a piece against its file's merkle tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PieceCheck {
+    Valid,
+    /// The piece is bad, and these blocks (by index within the piece) are
+    /// the ones that don't match their leaf hashes.
+    BadBlocks(Vec<usize>),
+    /// The piece is bad, but we don't know the block hashes yet.  Ask a
+    /// peer for them with a `hash request` to find the bad blocks.
+    Invalid,
+}
+
+/// The merkle tree of one file, as far as we know it.
+#[derive(Clone, Debug)]
+pub struct FileHashes {
+    pieces_root: Sha256Hash,
+    length: u64,
+    piece_length: u64,
+    piece_layer: Vec<Sha256Hash>,
+    block_hashes: Vec<Option<Vec<Sha256Hash>>>,
+}
+
+impl FileHashes {
+    /// Checks a file's piece layer against its `pieces root`.
+    ///
+    /// Files no larger than one piece have no piece layer; pass an empty
+    /// slice for them, and their single piece is checked against the root.
+    /// Lengths no file could have are refused.
+    pub fn new(
+        pieces_root: Sha256Hash,
+        length: u64,
+        piece_length: u64,
+        piece_layer: &[Sha256Hash],
+    ) -> Option<FileHashes> {
+        if piece_length == 0 {
+            return None;
+        }
+        let piece_count = (length.checked_add(piece_length - 1)? / piece_length) as usize;
+        if piece_count > 1 {
+            if piece_layer.len() != piece_count {
+                return None;
+            }
+            let blocks_per_piece = (piece_length / BLOCK_SIZE as u64) as usize;
+            let root = root_with_padding(
+                piece_layer,
+                piece_count.next_power_of_two(),
+                pad_hash(blocks_per_piece),
+            );
+            if root != pieces_root {
+                return None;
+            }
+        } else if !piece_layer.is_empty() {
+            return None;
+        }
+        Some(FileHashes {
+            pieces_root,
+            length,
+            piece_length,
+            piece_layer: piece_layer.to_vec(),
+            block_hashes: vec![None; piece_count],
+        })
+    }
+
+    pub fn pieces_root(&self) -> &Sha256Hash {
+        &self.pieces_root
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.block_hashes.len()
+    }
+
+    /// The number of 16 KiB blocks in a full piece.
+    pub fn blocks_per_piece(&self) -> usize {
+        (self.piece_length / BLOCK_SIZE as u64) as usize
+    }
+
+    fn blocks_in_piece(&self, piece: usize) -> usize {
+        let start = piece as u64 * self.piece_length;
+        let end = ::std::cmp::min(start + self.piece_length, self.length);
+        ((end - start + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64) as usize
+    }
+
+    /// The width of the subtree that a piece's blocks hash into.
+    fn piece_width(&self) -> usize {
+        if self.piece_count() > 1 {
+            self.blocks_per_piece()
+        } else {
+            self.blocks_in_piece(0).next_power_of_two()
+        }
+    }
+
+    fn expected_piece_hash(&self, piece: usize) -> &Sha256Hash {
+        if self.piece_count() > 1 {
+            &self.piece_layer[piece]
+        } else {
+            &self.pieces_root
+        }
+    }
+
+    /// Records the leaf hashes for a piece, from a `hashes` message with
+    /// base layer 0 covering exactly that piece.  Returns false if they
+    /// don't hash up to the piece layer.
+    pub fn add_block_hashes(&mut self, piece: usize, hashes: &[Sha256Hash]) -> bool {
+        if piece >= self.piece_count() || hashes.len() != self.piece_width() {
+            return false;
+        }
+        let root = merkle_root(hashes, self.piece_width());
+        if &root != self.expected_piece_hash(piece) {
+            return false;
+        }
+        let count = self.blocks_in_piece(piece);
+        self.block_hashes[piece] = Some(hashes[..count].to_vec());
+        true
+    }
+
+    /// Checks a whole piece of data.
+    pub fn check_piece(&self, piece: usize, data: &[u8]) -> PieceCheck {
+        let leaves: Vec<_> = data.chunks(BLOCK_SIZE).map(hash_block).collect();
+        if piece < self.piece_count() && leaves.len() == self.blocks_in_piece(piece)
+            && &merkle_root(&leaves, self.piece_width()) == self.expected_piece_hash(piece)
+        {
+            return PieceCheck::Valid;
+        }
+        match self.block_hashes.get(piece) {
+            Some(&Some(ref expected)) => PieceCheck::BadBlocks(
+                (0..expected.len())
+                    .filter(|&i| leaves.get(i) != Some(&expected[i]))
+                    .collect(),
+            ),
+            _ => PieceCheck::Invalid,
+        }
+    }
+
+    /// Checks a single block once the leaf hashes of its piece are known.
+    /// Returns None if they aren't.
+    pub fn check_block(&self, piece: usize, block: usize, data: &[u8]) -> Option<bool> {
+        match self.block_hashes.get(piece) {
+            Some(&Some(ref expected)) => {
+                Some(expected.get(block).map_or(false, |hash| hash == &hash_block(data)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::{Info, MetaInfo};
+    use testutil::read_torrent;
+
+    // The contents of a.txt in data/v2-test.torrent and data/hybrid-test.torrent
+    fn a_txt() -> Vec<u8> {
+        (0..40000).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn a_txt_hashes(b: &[u8]) -> FileHashes {
+        let mi = MetaInfo::from_bytes(b).expect("deserialize");
+        let file = match mi.info {
+            Info::MiV2Info(ref info) => info.file_tree[0].clone(),
+            ref other => panic!("Expected v2 info, got {:?}", other),
+        };
+        let root = file.pieces_root.unwrap();
+        let layer = mi.piece_layer(&root).unwrap().to_vec();
+        FileHashes::new(root, file.length, mi.info.piece_length(), &layer).expect("valid layer")
+    }
+
+    fn read_v2_torrent() -> Vec<u8> {
+        read_torrent("data/v2-test.torrent")
+    }
+
+    #[test]
+    fn verify_pieces_against_layer() {
+        let hashes = a_txt_hashes(&read_v2_torrent());
+        let data = a_txt();
+        assert_eq!(hashes.check_piece(0, &data[..32768]), PieceCheck::Valid);
+        assert_eq!(hashes.check_piece(1, &data[32768..]), PieceCheck::Valid);
+        assert_eq!(hashes.check_piece(1, &data[..32768]), PieceCheck::Invalid);
+    }
+
+    #[test]
+    fn refuse_impossible_lengths() {
+        let root = a_txt_hashes(&read_v2_torrent()).pieces_root().clone();
+        assert!(FileHashes::new(root.clone(), 40000, 0, &[]).is_none());
+        assert!(FileHashes::new(root, u64::MAX, 32768, &[]).is_none());
+    }
+
+    #[test]
+    fn pinpoint_bad_block() {
+        let mut hashes = a_txt_hashes(&read_v2_torrent());
+        let data = a_txt();
+        let leaves: Vec<_> = data[..32768].chunks(BLOCK_SIZE).map(hash_block).collect();
+        assert!(!hashes.add_block_hashes(0, &leaves[..1]));
+        assert!(hashes.add_block_hashes(0, &leaves));
+
+        let mut bad = data[..32768].to_vec();
+        bad[20000] ^= 0xff;
+        assert_eq!(hashes.check_piece(0, &bad), PieceCheck::BadBlocks(vec![1]));
+        assert_eq!(hashes.check_block(0, 0, &bad[..BLOCK_SIZE]), Some(true));
+        assert_eq!(hashes.check_block(0, 1, &bad[BLOCK_SIZE..]), Some(false));
+        assert_eq!(hashes.check_block(1, 0, &data[32768..]), None);
+    }
+
+    #[test]
+    fn proof_climbs_to_root() {
+        let leaves: Vec<_> = (0..4u8).map(|i| hash_block(&[i])).collect();
+        let root = merkle_root(&leaves, 4);
+        let sibling = merkle_root(&leaves[..2], 2);
+        let subtree = merkle_root(&leaves[2..], 2);
+        assert_eq!(proof_root(subtree, 1, &[sibling]), root);
+    }
+}
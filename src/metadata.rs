@@ -0,0 +1,667 @@
+//! The ut_metadata extension (BEP 9): fetching the `info` dict from peers,
+//! so a magnet link can be turned into a full torrent.
+//!
+//! The metadata is split into 16 KiB pieces.  Each message is a bencoded
+//! dict, followed by the piece data for `data` messages.  `UtMetadata`
+//! plugs this into the extension protocol, which takes care of framing.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::Instant;
+
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+
+use extension::{ExtendedHandshake, Extension};
+use magnet::Magnet;
+use metainfo::{get_info_hash, read_element, Sha1Hash};
+
+pub const METADATA_PIECE_SIZE: usize = 0x4000;
+
+// Nobody has a legitimate info dict this big, and we don't want a peer to
+// make us allocate gigabytes.
+const MAX_METADATA_SIZE: usize = 0x100_0000;
+
+// Bad downloads a peer has to have sent pieces of before it's banned.  One
+// isn't enough: any of the other senders may have been the liar.
+const MAX_STRIKES: usize = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data(u32, usize, Vec<u8>),
+    Reject(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece, total_size) = match *self {
+            MetadataMessage::Request(piece) => (0, piece, None),
+            MetadataMessage::Data(piece, total_size, _) => (1, piece, Some(total_size)),
+            MetadataMessage::Reject(piece) => (2, piece, None),
+        };
+        let header = Header {
+            msg_type,
+            piece,
+            total_size,
+        };
+        let mut msg = to_bytes(&header).expect("header always serializes");
+        if let MetadataMessage::Data(_, _, ref data) = *self {
+            msg.extend(data);
+        }
+        msg
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<MetadataMessage> {
+        // The piece data follows the dict directly, so find where it ends.
+        let chunks = read_element(&mut payload.iter().cloned().peekable())?;
+        let dict_length = chunks.iter().fold(0, |sum, chunk| sum + chunk.len());
+        let header: Header =
+            from_bytes(&payload[..dict_length]).map_err(|_| invalid("bad ut_metadata dict"))?;
+        match header.msg_type {
+            0 => Ok(MetadataMessage::Request(header.piece)),
+            1 => {
+                let total_size = header
+                    .total_size
+                    .ok_or_else(|| invalid("ut_metadata data without total_size"))?;
+                Ok(MetadataMessage::Data(
+                    header.piece,
+                    total_size,
+                    payload[dict_length..].to_vec(),
+                ))
+            }
+            2 => Ok(MetadataMessage::Reject(header.piece)),
+            _ => Err(invalid("unknown ut_metadata msg_type")),
+        }
+    }
+}
+
+/// Answers a peer's request for a piece of our own metadata.
+///
+/// `info` is the bencoded info dict, exactly as it was hashed.
+pub fn serve_request(info: &[u8], piece: u32) -> MetadataMessage {
+    let start = piece as usize * METADATA_PIECE_SIZE;
+    if start >= info.len() {
+        return MetadataMessage::Reject(piece);
+    }
+    let end = ::std::cmp::min(start + METADATA_PIECE_SIZE, info.len());
+    MetadataMessage::Data(piece, info.len(), info[start..end].to_vec())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    Requested(usize),
+    /// With the peer that sent it.
+    Received(usize, Vec<u8>),
+}
+
+/// Collects the metadata for one torrent from any number of peers.
+///
+/// Peers are identified by whatever `usize` the caller uses for its
+/// connections.  Call `next_request` for each peer that supports
+/// ut_metadata, and feed the responses back in.
+pub struct MetadataDownload {
+    info_hash: Sha1Hash,
+    total_size: usize,
+    pieces: Vec<PieceState>,
+    // Peers that rejected a request; we don't ask them again.
+    rejecters: HashSet<usize>,
+}
+
+impl MetadataDownload {
+    /// `total_size` is the `metadata_size` a peer sent in its extension
+    /// handshake.
+    pub fn new(info_hash: Sha1Hash, total_size: usize) -> io::Result<MetadataDownload> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            return Err(invalid("unreasonable metadata_size"));
+        }
+        let count = (total_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+        Ok(MetadataDownload {
+            info_hash,
+            total_size,
+            pieces: vec![PieceState::Missing; count],
+            rejecters: HashSet::new(),
+        })
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// The next request to send to `peer`, if there is a piece nobody has
+    /// been asked for yet.  Each peer gets at most one piece at a time, so
+    /// the download is spread across everyone we're connected to.
+    pub fn next_request(&mut self, peer: usize) -> Option<MetadataMessage> {
+        if self.rejecters.contains(&peer)
+            || self.pieces.contains(&PieceState::Requested(peer))
+        {
+            return None;
+        }
+        let index = self.pieces.iter().position(|state| *state == PieceState::Missing)?;
+        self.pieces[index] = PieceState::Requested(peer);
+        Some(MetadataMessage::Request(index as u32))
+    }
+
+    /// Handles a message from `peer`.  Requests should go to
+    /// `serve_request` instead, if we have the metadata to serve.
+    pub fn handle(&mut self, peer: usize, msg: MetadataMessage) -> io::Result<()> {
+        match msg {
+            MetadataMessage::Data(piece, total_size, data) => {
+                let piece = piece as usize;
+                if total_size != self.total_size {
+                    return Err(invalid("peer disagrees about metadata_size"));
+                }
+                let expected = if piece + 1 == self.pieces.len() {
+                    self.total_size - piece * METADATA_PIECE_SIZE
+                } else {
+                    METADATA_PIECE_SIZE
+                };
+                if piece >= self.pieces.len() || data.len() != expected {
+                    return Err(invalid("metadata piece has the wrong size"));
+                }
+                if self.pieces[piece] != PieceState::Requested(peer) {
+                    // Unsolicited, or arriving after the peer was dropped.
+                    // Harmless, but not something we asked this peer for.
+                    return Ok(());
+                }
+                self.pieces[piece] = PieceState::Received(peer, data);
+            }
+            MetadataMessage::Reject(piece) => {
+                self.rejecters.insert(peer);
+                if self.pieces.get(piece as usize) == Some(&PieceState::Requested(peer)) {
+                    self.pieces[piece as usize] = PieceState::Missing;
+                }
+            }
+            MetadataMessage::Request(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Forgets about a disconnected peer, so its pieces can be asked for
+    /// elsewhere.
+    pub fn peer_gone(&mut self, peer: usize) {
+        for state in &mut self.pieces {
+            if *state == PieceState::Requested(peer) {
+                *state = PieceState::Missing;
+            }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|state| match *state {
+            PieceState::Received(..) => true,
+            _ => false,
+        })
+    }
+
+    fn has_received(&self) -> bool {
+        self.pieces.iter().any(|state| match *state {
+            PieceState::Received(..) => true,
+            _ => false,
+        })
+    }
+
+    // The peers that sent the pieces we have.
+    fn senders(&self) -> HashSet<usize> {
+        self.pieces
+            .iter()
+            .filter_map(|state| match *state {
+                PieceState::Received(peer, _) => Some(peer),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Assembles the info dict and checks it against the info hash.
+    ///
+    /// If the hash doesn't match, every piece is thrown away so it can be
+    /// fetched again, and an error is returned.
+    pub fn finish(&mut self) -> io::Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(io::Error::new(io::ErrorKind::Other, "metadata is incomplete"));
+        }
+        let mut info = Vec::with_capacity(self.total_size);
+        for state in &self.pieces {
+            if let PieceState::Received(_, ref data) = *state {
+                info.extend(data);
+            }
+        }
+        // Reuse the torrent-file hashing by wrapping the info dict in one.
+        let mut wrapped = b"d4:info".to_vec();
+        wrapped.extend(&info);
+        wrapped.push(b'e');
+        let hash = get_info_hash(wrapped)?.digest().bytes().to_vec();
+        if hash != self.info_hash.as_bytes() {
+            for state in &mut self.pieces {
+                *state = PieceState::Missing;
+            }
+            return Err(invalid("metadata does not match the info hash"));
+        }
+        Ok(info)
+    }
+}
+
+/// The ut_metadata extension for one torrent.
+///
+/// It serves the info dict to peers if we have it, and otherwise fetches
+/// it from every peer that advertises a `metadata_size`.  Peers may not
+/// agree on the size, so the download goes with what most of them say,
+/// and only asks those.  If the result doesn't match the info hash, the
+/// download starts over, and peers that sent pieces of more than one bad
+/// result are never asked again.
+pub struct UtMetadata {
+    info_hash: Sha1Hash,
+    info: Option<Vec<u8>>,
+    download: Option<MetadataDownload>,
+    // The metadata_size each peer advertised.
+    sizes: HashMap<usize, usize>,
+    // Bad downloads each peer sent pieces of.
+    strikes: HashMap<usize, usize>,
+    banned: HashSet<usize>,
+}
+
+impl UtMetadata {
+    /// For a torrent we already have the info dict for.
+    pub fn serving(info_hash: Sha1Hash, info: Vec<u8>) -> UtMetadata {
+        UtMetadata {
+            info_hash,
+            info: Some(info),
+            download: None,
+            sizes: HashMap::new(),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+        }
+    }
+
+    /// For a torrent we only know the info hash of, as from a magnet link.
+    pub fn fetching(info_hash: Sha1Hash) -> UtMetadata {
+        UtMetadata {
+            info_hash,
+            info: None,
+            download: None,
+            sizes: HashMap::new(),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+        }
+    }
+
+    /// The bencoded info dict, once we have it.
+    pub fn info(&self) -> Option<&[u8]> {
+        self.info.as_ref().map(|info| &info[..])
+    }
+
+    // The metadata_size most peers we still trust agree on.
+    fn best_size(&self) -> Option<usize> {
+        let mut votes = HashMap::new();
+        for (peer, &size) in &self.sizes {
+            if !self.banned.contains(peer) {
+                *votes.entry(size).or_insert(0) += 1;
+            }
+        }
+        votes
+            .into_iter()
+            .max_by_key(|&(size, count)| (count, size))
+            .map(|(size, _)| size)
+    }
+
+    fn requests(&mut self, peer: usize) -> Vec<Vec<u8>> {
+        let mut failed = None;
+        if let Some(ref mut download) = self.download {
+            if download.is_complete() {
+                let senders = download.senders();
+                match download.finish() {
+                    Ok(info) => self.info = Some(info),
+                    // Any of them may have lied, about the data or its size.
+                    Err(_) => failed = Some(senders),
+                }
+            }
+        }
+        if let Some(senders) = failed {
+            for peer in senders {
+                let strikes = self.strikes.entry(peer).or_insert(0);
+                *strikes += 1;
+                if *strikes >= MAX_STRIKES {
+                    self.banned.insert(peer);
+                }
+            }
+            self.download = None;
+        }
+        if self.info.is_some() {
+            self.download = None;
+            return Vec::new();
+        }
+        // Until a piece is in, follow whatever size most peers agree on.
+        let best = self.best_size();
+        let restart = match self.download {
+            Some(ref download) => !download.has_received() && Some(download.total_size) != best,
+            None => true,
+        };
+        if restart {
+            self.download = best.and_then(|size| {
+                MetadataDownload::new(self.info_hash.clone(), size).ok()
+            });
+        }
+        match self.download {
+            Some(ref mut download)
+                if !self.banned.contains(&peer)
+                    && self.sizes.get(&peer) == Some(&download.total_size) =>
+            {
+                download.next_request(peer).map(|msg| msg.encode()).into_iter().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = self.info.as_ref().map(|info| info.len());
+    }
+
+    fn peer_handshake(
+        &mut self,
+        peer: usize,
+        handshake: &ExtendedHandshake,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        if self.info.is_some() || !handshake.m.get(self.name()).map_or(false, |&id| id != 0) {
+            return Ok(Vec::new());
+        }
+        match handshake.metadata_size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => {
+                self.sizes.insert(peer, size);
+            }
+            _ => {}
+        }
+        Ok(self.requests(peer))
+    }
+
+    fn handle(&mut self, peer: usize, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        match MetadataMessage::decode(payload)? {
+            MetadataMessage::Request(piece) => Ok(vec![match self.info {
+                Some(ref info) => serve_request(info, piece).encode(),
+                None => MetadataMessage::Reject(piece).encode(),
+            }]),
+            msg => {
+                if let Some(ref mut download) = self.download {
+                    download.handle(peer, msg)?;
+                }
+                Ok(self.requests(peer))
+            }
+        }
+    }
+
+    fn peer_gone(&mut self, peer: usize) {
+        self.sizes.remove(&peer);
+        self.strikes.remove(&peer);
+        self.banned.remove(&peer);
+        if let Some(ref mut download) = self.download {
+            download.peer_gone(peer);
+        }
+    }
+
+    /// Asks idle peers for pieces, since a restarted download has nobody
+    /// else to ask.
+    fn tick(&mut self, peer: usize, _now: Instant) -> Vec<Vec<u8>> {
+        if self.info.is_some() {
+            return Vec::new();
+        }
+        self.requests(peer)
+    }
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+/// Builds the bytes of a .torrent file from a magnet link and the info
+/// dict fetched for it.  Parse the result with `MetaInfo::from_bytes`.
+pub fn torrent_from_magnet(magnet: &Magnet, info: &[u8]) -> Vec<u8> {
+    let mut torrent = b"d".to_vec();
+    push_bytes(&mut torrent, b"announce");
+    let announce = magnet.trackers.first().map_or("", |tracker| &tracker[..]);
+    push_bytes(&mut torrent, announce.as_bytes());
+    if magnet.trackers.len() > 1 {
+        // One tracker per tier, in the order the magnet link gave them.
+        push_bytes(&mut torrent, b"announce-list");
+        torrent.push(b'l');
+        for tracker in &magnet.trackers {
+            torrent.push(b'l');
+            push_bytes(&mut torrent, tracker.as_bytes());
+            torrent.push(b'e');
+        }
+        torrent.push(b'e');
+    }
+    push_bytes(&mut torrent, b"info");
+    torrent.extend(info);
+    if !magnet.web_seeds.is_empty() {
+        push_bytes(&mut torrent, b"url-list");
+        torrent.push(b'l');
+        for seed in &magnet.web_seeds {
+            push_bytes(&mut torrent, seed.as_bytes());
+        }
+        torrent.push(b'e');
+    }
+    torrent.push(b'e');
+    torrent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::{value_in_dict, MetaInfo};
+    use testutil::read_torrent;
+
+    fn arch_torrent() -> Vec<u8> {
+        read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent")
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let msg = MetadataMessage::Request(3);
+        assert_eq!(msg.encode(), b"d8:msg_typei0e5:piecei3ee".to_vec());
+        assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+
+        let msg = MetadataMessage::Data(1, 20000, vec![b'x'; 3616]);
+        let encoded = msg.encode();
+        assert!(encoded.starts_with(b"d8:msg_typei1e5:piecei1e10:total_sizei20000ee"));
+        assert_eq!(MetadataMessage::decode(&encoded).unwrap(), msg);
+    }
+
+    #[test]
+    fn fetch_from_two_peers() {
+        let torrent = arch_torrent();
+        let info = value_in_dict(torrent.clone(), b"info").unwrap();
+        let hash = get_info_hash(torrent).unwrap().digest().bytes().to_vec();
+        let mut download = MetadataDownload::new(Sha1Hash::new(hash).unwrap(), info.len())
+            .expect("download");
+
+        // Each peer gets its own piece, then nothing until it answers.
+        let mut requests = vec![];
+        for peer in 0..2 {
+            requests.push((peer, download.next_request(peer).expect("request")));
+            assert_eq!(download.next_request(peer), None);
+        }
+        download.handle(1, MetadataMessage::Reject(1)).unwrap();
+        assert_eq!(download.next_request(1), None);
+        for (peer, request) in requests {
+            if let MetadataMessage::Request(piece) = request {
+                if peer == 0 {
+                    download.handle(peer, serve_request(&info, piece)).unwrap();
+                }
+            }
+        }
+        while let Some(MetadataMessage::Request(piece)) = download.next_request(0) {
+            download.handle(0, serve_request(&info, piece)).unwrap();
+        }
+        assert!(download.is_complete());
+        let fetched = download.finish().expect("finish");
+        assert_eq!(fetched, info);
+
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&tr=http%3A%2F%2Fa%2Fannounce",
+        ).unwrap();
+        let torrent = torrent_from_magnet(&magnet, &fetched);
+        let mi = MetaInfo::from_bytes(&torrent).expect("deserialize");
+        assert_eq!(mi.announce, "http://a/announce");
+        assert_eq!(mi.info.name(), "archlinux-2017.12.01-x86_64.iso");
+    }
+
+    #[test]
+    fn fetch_through_extension_protocol() {
+        use extension::{ExtensionRegistry, PeerExtensions};
+        use peermsg::{parse, Message};
+
+        let torrent = arch_torrent();
+        let info = value_in_dict(torrent.clone(), b"info").unwrap();
+        let hash = get_info_hash(torrent).unwrap().digest().bytes().to_vec();
+        let hash = Sha1Hash::new(hash).unwrap();
+
+        let mut seeder = ExtensionRegistry::new(None);
+        seeder.register(Box::new(UtMetadata::serving(hash.clone(), info.clone()))).unwrap();
+        let mut leecher = ExtensionRegistry::new(None);
+        leecher.register(Box::new(UtMetadata::fetching(hash))).unwrap();
+        let mut seeder_view = PeerExtensions::new();
+        let mut leecher_view = PeerExtensions::new();
+
+        // Both sides handshake, then pass messages until it goes quiet.
+        let mut to_seeder = vec![leecher.handshake(None)];
+        let mut to_leecher = vec![seeder.handshake(None)];
+        while !to_seeder.is_empty() || !to_leecher.is_empty() {
+            let mut next_to_seeder = vec![];
+            for msg in to_leecher.drain(..) {
+                if let Message::Extended(id, payload) = parse(&msg).unwrap().unwrap().0 {
+                    next_to_seeder.extend(leecher.handle(1, &mut leecher_view, id, &payload).unwrap());
+                }
+            }
+            for msg in to_seeder.drain(..) {
+                if let Message::Extended(id, payload) = parse(&msg).unwrap().unwrap().0 {
+                    to_leecher.extend(seeder.handle(1, &mut seeder_view, id, &payload).unwrap());
+                }
+            }
+            to_seeder = next_to_seeder;
+        }
+        assert_eq!(
+            leecher_view.handshake().unwrap().metadata_size,
+            Some(info.len())
+        );
+        assert!(seeder_view.handshake().unwrap().metadata_size.is_none());
+
+        // Now the leecher has the metadata, it offers it in its handshake.
+        let msg = leecher.handshake(None);
+        if let Message::Extended(0, payload) = parse(&msg).unwrap().unwrap().0 {
+            let handshake = ExtendedHandshake::decode(&payload).unwrap();
+            assert_eq!(handshake.metadata_size, Some(info.len()));
+        } else {
+            panic!("Expected an extended handshake");
+        }
+    }
+
+    #[test]
+    fn wrong_hash_starts_over() {
+        let info = value_in_dict(arch_torrent(), b"info").unwrap();
+        let mut download = MetadataDownload::new(Sha1Hash::new(vec![0; 20]).unwrap(), info.len())
+            .expect("download");
+        while let Some(MetadataMessage::Request(piece)) = download.next_request(0) {
+            download.handle(0, serve_request(&info, piece)).unwrap();
+        }
+        assert!(download.finish().is_err());
+        assert!(!download.is_complete());
+        assert!(download.next_request(0).is_some());
+    }
+
+    #[test]
+    fn outvote_and_ban_a_peer_lying_about_the_size() {
+        let torrent = arch_torrent();
+        let info = value_in_dict(torrent.clone(), b"info").unwrap();
+        let hash = get_info_hash(torrent).unwrap().digest().bytes().to_vec();
+        let mut ut = UtMetadata::fetching(Sha1Hash::new(hash).unwrap());
+        let handshake = |size| {
+            let mut handshake = ExtendedHandshake::default();
+            handshake.m.insert("ut_metadata".to_string(), 1);
+            handshake.metadata_size = Some(size);
+            handshake
+        };
+        // Answers requests with the given info dict until there are none.
+        fn serve(ut: &mut UtMetadata, peer: usize, info: &[u8], mut requests: Vec<Vec<u8>>) {
+            while let Some(request) = requests.pop() {
+                let msg = MetadataMessage::decode(&request).unwrap();
+                if let MetadataMessage::Request(piece) = msg {
+                    requests = ut.handle(peer, &serve_request(info, piece).encode()).unwrap();
+                }
+            }
+        }
+
+        // The liar is first, and ties with the one honest peer.
+        let lie = vec![0; info.len() + 100];
+        let requests = ut.peer_handshake(1, &handshake(lie.len())).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(ut.peer_handshake(2, &handshake(info.len())).unwrap().is_empty());
+        serve(&mut ut, 1, &lie, requests);
+        assert!(ut.info().is_none());
+
+        // The liar's metadata fails its hash.  Asked again straight away,
+        // it fails a second time and is banned, and the honest size wins.
+        assert!(ut.tick(1, Instant::now()).is_empty());
+        let requests = ut.tick(2, Instant::now());
+        assert_eq!(requests.len(), 1);
+        serve(&mut ut, 2, &info, requests);
+        assert_eq!(ut.info(), Some(&info[..]));
+    }
+
+    #[test]
+    fn keep_asking_an_honest_peer_after_a_bad_download() {
+        let torrent = arch_torrent();
+        let info = value_in_dict(torrent.clone(), b"info").unwrap();
+        let hash = get_info_hash(torrent).unwrap().digest().bytes().to_vec();
+        let mut ut = UtMetadata::fetching(Sha1Hash::new(hash).unwrap());
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert("ut_metadata".to_string(), 1);
+        handshake.metadata_size = Some(info.len());
+        let mut bad = info.clone();
+        bad[0] ^= 1;
+        fn serve(ut: &mut UtMetadata, peer: usize, info: &[u8], mut requests: Vec<Vec<u8>>) {
+            while let Some(request) = requests.pop() {
+                let msg = MetadataMessage::decode(&request).unwrap();
+                if let MetadataMessage::Request(piece) = msg {
+                    requests = ut.handle(peer, &serve_request(info, piece).encode()).unwrap();
+                }
+            }
+        }
+
+        // Each sends one piece, and the liar's spoils the lot.  Then the
+        // liar is asked for both pieces, and its second strike bans it.
+        let to_liar = ut.peer_handshake(1, &handshake).unwrap();
+        let to_honest = ut.peer_handshake(2, &handshake).unwrap();
+        assert_eq!((to_liar.len(), to_honest.len()), (1, 1));
+        serve(&mut ut, 2, &info, to_honest);
+        serve(&mut ut, 1, &bad, to_liar);
+        assert!(ut.info().is_none());
+        assert!(ut.tick(1, Instant::now()).is_empty());
+
+        // The honest peer was in on the first bad download too, but isn't
+        // banned for it.
+        let requests = ut.tick(2, Instant::now());
+        assert_eq!(requests.len(), 1);
+        serve(&mut ut, 2, &info, requests);
+        assert_eq!(ut.info(), Some(&info[..]));
+    }
+}
@@ -1,11 +1,15 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io;
 use std::iter::Peekable;
+use std::ops::Deref;
 use std::str;
 
 use serde_bencode::de::from_bytes;
+use serde_bencode::value::Value;
 use serde_bytes;
 use sha1::Sha1;
+use sha2::{Digest, Sha256};
 
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Sha1Hash(Vec<u8>);
@@ -28,9 +32,39 @@ impl Sha1Hash {
     }
 }
 
+/// A SHA-256 hash, as used by v2 (BEP 52) torrents for merkle roots, piece
+/// layers and the v2 info hash.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Sha256Hash(Vec<u8>);
+
+impl Sha256Hash {
+    pub fn new(input: Vec<u8>) -> Option<Sha256Hash> {
+        if input.len() == 32 {
+            Some(Sha256Hash(input))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The first 20 bytes of the hash.  This is what goes in the handshake
+    /// and in tracker requests for v2 torrents.
+    pub fn truncated(&self) -> Sha1Hash {
+        Sha1Hash(self.0[..20].to_vec())
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct MetaInfo<'a> {
-    #[serde(borrow)]
+    // Trackerless torrents have no announce URL, only `nodes`.
+    #[serde(borrow, default)]
     pub announce: Cow<'a, str>,
     pub info: Info<'a>,
 
@@ -38,8 +72,10 @@ pub struct MetaInfo<'a> {
     #[serde(borrow)]
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<Cow<'a, str>>>>,
-    #[serde(borrow)]
+    // BEP 19 allows a single URL instead of a list; qBittorrent writes an
+    // empty string for none.
     #[serde(rename = "url-list")]
+    #[serde(default, deserialize_with = "url_list_from_bytes")]
     pub url_list: Option<Vec<Cow<'a, str>>>,
     #[serde(borrow)]
     #[serde(rename = "created by")]
@@ -48,6 +84,15 @@ pub struct MetaInfo<'a> {
     pub comment: Option<Cow<'a, str>>,
     #[serde(rename = "creation date")]
     pub creation_date: Option<i64>,
+    /// DHT nodes to bootstrap from, as (host, port) pairs (BEP 5).
+    #[serde(borrow)]
+    pub nodes: Option<Vec<(Cow<'a, str>, u16)>>,
+
+    // v2 only: maps each file's `pieces root` to the hashes of its pieces.
+    // Files no larger than one piece have no entry.
+    #[serde(rename = "piece layers")]
+    #[serde(default, deserialize_with = "piece_layers_from_bytes")]
+    pub piece_layers: Option<HashMap<Sha256Hash, Vec<Sha256Hash>>>,
 }
 
 pub fn get_info_hash(source: Vec<u8>) -> io::Result<Sha1> {
@@ -59,6 +104,16 @@ pub fn get_info_hash(source: Vec<u8>) -> io::Result<Sha1> {
     })
 }
 
+/// The v2 info hash: SHA-256 over the bencoded `info` dict.  Use
+/// `Sha256Hash::truncated` for the 20-byte form.
+pub fn get_info_hash_v2(source: Vec<u8>) -> io::Result<Sha256Hash> {
+    value_in_dict(source, b"info").map(|bytes| {
+        let mut sha = Sha256::default();
+        sha.input(&bytes);
+        Sha256Hash(sha.result().to_vec())
+    })
+}
+
 pub fn value_in_dict(source: Vec<u8>, key: &[u8]) -> io::Result<Vec<u8>> {
     let mut iter = source.into_iter().peekable();
     if iter.next() != Some(b'd') {
@@ -181,6 +236,7 @@ pub fn read_list<I: Iterator<Item = u8>>(source: &mut Peekable<I>) -> io::Result
         {
             buf.extend(read_element(source)?);
         }
+        buf.push(vec![source.next().unwrap()]); // The closing `e`, seen by peek() above.
         Ok(buf)
     }
 }
@@ -204,6 +260,7 @@ pub fn read_dict<I: Iterator<Item = u8>>(source: &mut Peekable<I>) -> io::Result
             buf.extend(read_bytes(source)?);
             buf.extend(read_element(source)?);
         }
+        buf.push(vec![source.next().unwrap()]); // The closing `e`, seen by peek() above.
         Ok(buf)
     }
 }
@@ -213,6 +270,119 @@ impl<'a> MetaInfo<'a> {
         from_bytes(bytes).ok()
     }
 
+    /// The `meta version` of the info dict: 1 for classic torrents, 2 for
+    /// BEP 52 torrents.
+    pub fn meta_version(&self) -> u64 {
+        self.info.meta_version()
+    }
+
+    /// The piece layer for a file, given its `pieces root`.
+    pub fn piece_layer(&self, pieces_root: &Sha256Hash) -> Option<&[Sha256Hash]> {
+        self.piece_layers
+            .as_ref()
+            .and_then(|layers| layers.get(pieces_root))
+            .map(|layer| &layer[..])
+    }
+
+    /// The torrent's DHT `nodes`, as `host:port` strings.
+    pub fn dht_nodes(&self) -> Vec<String> {
+        self.nodes.as_ref().map_or(Vec::new(), |nodes| {
+            nodes
+                .iter()
+                .map(|&(ref host, port)| {
+                    if host.contains(':') {
+                        format!("[{}]:{}", host, port)
+                    } else {
+                        format!("{}:{}", host, port)
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Copies out everything borrowed from the torrent file, so the
+    /// metainfo can outlive it.
+    pub fn into_owned(self) -> MetaInfo<'static> {
+        MetaInfo {
+            announce: owned(self.announce),
+            info: self.info.into_owned(),
+            announce_list: self.announce_list.map(|tiers| {
+                tiers
+                    .into_iter()
+                    .map(|tier| tier.into_iter().map(owned).collect())
+                    .collect()
+            }),
+            url_list: self.url_list.map(|urls| urls.into_iter().map(owned).collect()),
+            created_by: self.created_by.map(owned),
+            comment: self.comment.map(owned),
+            creation_date: self.creation_date,
+            nodes: self.nodes
+                .map(|nodes| nodes.into_iter().map(|(host, port)| (owned(host), port)).collect()),
+            piece_layers: self.piece_layers,
+        }
+    }
+}
+
+fn owned(s: Cow<str>) -> Cow<'static, str> {
+    Cow::Owned(s.into_owned())
+}
+
+fn owned_path(path: Vec<Cow<str>>) -> Vec<Cow<'static, str>> {
+    path.into_iter().map(owned).collect()
+}
+
+/// A torrent's metainfo that owns all its data, with the `info` dict kept
+/// exactly as it was in the torrent file, for hashing and for serving to
+/// peers (BEP 9).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedMetaInfo {
+    pub metainfo: MetaInfo<'static>,
+    info_bytes: Vec<u8>,
+}
+
+impl OwnedMetaInfo {
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<OwnedMetaInfo> {
+        let metainfo = from_bytes::<MetaInfo>(bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+            .into_owned();
+        let info_bytes = value_in_dict(bytes.to_vec(), b"info")?;
+        Ok(OwnedMetaInfo {
+            metainfo,
+            info_bytes,
+        })
+    }
+
+    /// The bencoded `info` dict.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    /// The info hash that goes in handshakes and tracker requests: the
+    /// SHA-1 for v1 and hybrid torrents, the truncated SHA-256 for v2-only
+    /// ones.
+    pub fn info_hash(&self) -> Sha1Hash {
+        if self.metainfo.meta_version() == 2 && !self.metainfo.info.is_hybrid() {
+            self.info_hash_v2().truncated()
+        } else {
+            let mut sha = Sha1::new();
+            sha.update(&self.info_bytes);
+            Sha1Hash(sha.digest().bytes().to_vec())
+        }
+    }
+
+    pub fn info_hash_v2(&self) -> Sha256Hash {
+        let mut sha = Sha256::default();
+        sha.input(&self.info_bytes);
+        Sha256Hash(sha.result().to_vec())
+    }
+}
+
+impl Deref for OwnedMetaInfo {
+    type Target = MetaInfo<'static>;
+
+    fn deref(&self) -> &MetaInfo<'static> {
+        &self.metainfo
+    }
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -220,16 +390,163 @@ impl<'a> MetaInfo<'a> {
 pub enum Info<'a> {
     MiInfo(MiInfo<'a>),
     MiMultiInfo(MiMultiInfo<'a>),
+    MiV2Info(MiV2Info<'a>),
 }
 
 impl <'a> Info<'a> {
+    pub fn into_owned(self) -> Info<'static> {
+        match self {
+            Info::MiInfo(info) => Info::MiInfo(MiInfo {
+                name: owned(info.name),
+                piece_length: info.piece_length,
+                pieces: info.pieces,
+                length: info.length,
+                private: info.private,
+                meta_version: info.meta_version,
+                file_tree: info.file_tree.map(owned_file_tree),
+            }),
+            Info::MiMultiInfo(info) => Info::MiMultiInfo(MiMultiInfo {
+                name: owned(info.name),
+                piece_length: info.piece_length,
+                pieces: info.pieces,
+                files: info.files.into_iter().map(MiFileData::into_owned).collect(),
+                private: info.private,
+                meta_version: info.meta_version,
+                file_tree: info.file_tree.map(owned_file_tree),
+            }),
+            Info::MiV2Info(info) => Info::MiV2Info(MiV2Info {
+                name: owned(info.name),
+                piece_length: info.piece_length,
+                meta_version: info.meta_version,
+                file_tree: owned_file_tree(info.file_tree),
+                private: info.private,
+            }),
+        }
+    }
+
     pub fn length(&self) -> u64 {
         match *self {
             Info::MiInfo(ref info) => info.length,
             Info::MiMultiInfo(ref info) => info.files.iter().fold(0, |sum, filedata| sum + filedata.length),
+            Info::MiV2Info(ref info) => info.file_tree.iter().fold(0, |sum, filedata| sum + filedata.length),
+        }
+    }
+
+    pub fn meta_version(&self) -> u64 {
+        match *self {
+            Info::MiInfo(ref info) => info.meta_version.unwrap_or(1),
+            Info::MiMultiInfo(ref info) => info.meta_version.unwrap_or(1),
+            Info::MiV2Info(ref info) => info.meta_version,
         }
     }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Info::MiInfo(ref info) => &info.name,
+            Info::MiMultiInfo(ref info) => &info.name,
+            Info::MiV2Info(ref info) => &info.name,
+        }
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        match *self {
+            Info::MiInfo(ref info) => info.piece_length,
+            Info::MiMultiInfo(ref info) => info.piece_length,
+            Info::MiV2Info(ref info) => info.piece_length,
+        }
+    }
+
+    /// The v2 file tree, for v2 and hybrid torrents.
+    pub fn file_tree(&self) -> Option<&[MiV2FileData<'a>]> {
+        match *self {
+            Info::MiInfo(ref info) => info.file_tree.as_ref().map(|files| &files[..]),
+            Info::MiMultiInfo(ref info) => info.file_tree.as_ref().map(|files| &files[..]),
+            Info::MiV2Info(ref info) => Some(&info.file_tree),
+        }
+    }
+
+    /// Private torrents (BEP 27) only get peers from their trackers: no
+    /// DHT, no peer exchange.
+    pub fn is_private(&self) -> bool {
+        let private = match *self {
+            Info::MiInfo(ref info) => info.private,
+            Info::MiMultiInfo(ref info) => info.private,
+            Info::MiV2Info(ref info) => info.private,
+        };
+        private == Some(1)
+    }
+
+    /// A hybrid torrent carries both v1 `pieces` and a v2 `file tree`.
+    pub fn is_hybrid(&self) -> bool {
+        match *self {
+            Info::MiV2Info(_) => false,
+            _ => self.file_tree().is_some(),
+        }
+    }
+
+    /// Lines up the v2 files of a hybrid torrent with the v1 piece stream.
+    ///
+    /// In a hybrid torrent every file starts on a piece boundary of the v1
+    /// stream, using padding files to fill out the end of the previous
+    /// file's last piece.  This checks that the v1 file list agrees with
+    /// the file tree, and returns each file's offset into the v1 stream.
+    pub fn hybrid_layout(&self) -> io::Result<Vec<HybridFile>> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let tree = match self.file_tree() {
+            Some(tree) if self.is_hybrid() => tree,
+            _ => return Err(invalid("not a hybrid torrent")),
+        };
+        let v1_files = match *self {
+            Info::MiInfo(ref info) => vec![(vec![info.name.clone()], info.length, false)],
+            Info::MiMultiInfo(ref info) => info.files
+                .iter()
+                .map(|file| (file.path.clone(), file.length, file.is_padding()))
+                .collect(),
+            Info::MiV2Info(_) => unreachable!(),
+        };
+        let piece_length = self.piece_length();
+        let mut tree = tree.iter();
+        let mut layout = Vec::new();
+        let mut offset = 0;
+        for (path, length, padding) in v1_files {
+            if !padding {
+                let file = tree.next().ok_or_else(|| invalid("file tree has too few files"))?;
+                if file.path != path || file.length != length {
+                    return Err(invalid("v1 files and file tree disagree"));
+                }
+                if length > 0 && offset % piece_length != 0 {
+                    return Err(invalid("file is not aligned to a piece boundary"));
+                }
+                layout.push(HybridFile {
+                    offset,
+                    length,
+                    pieces_root: file.pieces_root.clone(),
+                });
+            }
+            offset += length;
+        }
+        if tree.next().is_some() {
+            return Err(invalid("file tree has too many files"));
+        }
+        Ok(layout)
+    }
 }
+
+/// Where a v2 file sits in the v1 piece stream of a hybrid torrent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HybridFile {
+    pub offset: u64,
+    pub length: u64,
+    pub pieces_root: Option<Sha256Hash>,
+}
+
+impl HybridFile {
+    /// The v1 piece that holds this file's first v2 piece.
+    pub fn first_piece(&self, piece_length: u64) -> u64 {
+        self.offset / piece_length
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct MiInfo<'a> {
     pub name: Cow<'a, str>,
@@ -238,6 +555,14 @@ pub struct MiInfo<'a> {
     #[serde(deserialize_with = "pieces_from_bytes")]
     pub pieces: Vec<Sha1Hash>,
     pub length: u64,
+    pub private: Option<u8>,
+
+    // Hybrid v1/v2 torrents also carry the v2 fields.
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u64>,
+    #[serde(rename = "file tree")]
+    #[serde(default, deserialize_with = "opt_file_tree_from_bytes")]
+    pub file_tree: Option<Vec<MiV2FileData<'a>>>,
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -248,6 +573,46 @@ pub struct MiMultiInfo<'a> {
     #[serde(deserialize_with = "pieces_from_bytes")]
     pub pieces: Vec<Sha1Hash>,
     pub files: Vec<MiFileData<'a>>,
+    pub private: Option<u8>,
+
+    // Hybrid v1/v2 torrents also carry the v2 fields.
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u64>,
+    #[serde(rename = "file tree")]
+    #[serde(default, deserialize_with = "opt_file_tree_from_bytes")]
+    pub file_tree: Option<Vec<MiV2FileData<'a>>>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MiV2Info<'a> {
+    pub name: Cow<'a, str>,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    #[serde(rename = "meta version")]
+    pub meta_version: u64,
+    #[serde(rename = "file tree", deserialize_with = "file_tree_from_bytes")]
+    pub file_tree: Vec<MiV2FileData<'a>>,
+    pub private: Option<u8>,
+}
+
+/// One file from a v2 `file tree`, flattened out of the nested dicts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MiV2FileData<'a> {
+    pub length: u64,
+    pub path: Vec<Cow<'a, str>>,
+    /// Absent for empty files.
+    pub pieces_root: Option<Sha256Hash>,
+}
+
+fn owned_file_tree(files: Vec<MiV2FileData>) -> Vec<MiV2FileData<'static>> {
+    files
+        .into_iter()
+        .map(|file| MiV2FileData {
+            length: file.length,
+            path: owned_path(file.path),
+            pieces_root: file.pieces_root,
+        })
+        .collect()
 }
 
 fn pieces_from_bytes<'de, D>(deserializer: D) -> Result<Vec<Sha1Hash>, D::Error>
@@ -263,33 +628,168 @@ where
         .collect()
 }
 
+fn url_list_from_bytes<'de, 'a, D>(
+    deserializer: D,
+) -> Result<Option<Vec<Cow<'a, str>>>, D::Error>
+where
+    D: ::serde::de::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let urls = match ::serde::de::Deserialize::deserialize(deserializer)? {
+        Value::Bytes(url) => vec![Value::Bytes(url)],
+        Value::List(urls) => urls,
+        _ => return Err(D::Error::custom("url-list is not a string or a list")),
+    };
+    let mut list = Vec::with_capacity(urls.len());
+    for url in urls {
+        match url {
+            Value::Bytes(url) => {
+                let url = String::from_utf8(url).map_err(|_| D::Error::custom("url is not utf-8"))?;
+                if !url.is_empty() {
+                    list.push(Cow::Owned(url));
+                }
+            }
+            _ => return Err(D::Error::custom("url is not a string")),
+        }
+    }
+    Ok(Some(list))
+}
+
+fn piece_layers_from_bytes<'de, D>(
+    deserializer: D,
+) -> Result<Option<HashMap<Sha256Hash, Vec<Sha256Hash>>>, D::Error>
+where
+    D: ::serde::de::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let raw: HashMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf> =
+        ::serde::de::Deserialize::deserialize(deserializer)?;
+    let mut layers = HashMap::with_capacity(raw.len());
+    for (root, hashes) in raw {
+        let root = Sha256Hash::new(root.to_vec())
+            .ok_or_else(|| D::Error::custom("pieces root is not 32 bytes"))?;
+        if hashes.len() % 32 != 0 {
+            return Err(D::Error::custom("piece layer is not a multiple of 32 bytes"));
+        }
+        let hashes = hashes.chunks(32).map(|x| Sha256Hash(x.to_vec())).collect();
+        layers.insert(root, hashes);
+    }
+    Ok(Some(layers))
+}
+
+fn file_tree_from_bytes<'de, 'a, D>(deserializer: D) -> Result<Vec<MiV2FileData<'a>>, D::Error>
+where
+    D: ::serde::de::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let tree: Value = ::serde::de::Deserialize::deserialize(deserializer)?;
+    let mut files = Vec::new();
+    walk_file_tree(tree, &mut vec![], &mut files).map_err(D::Error::custom)?;
+    Ok(files)
+}
+
+fn opt_file_tree_from_bytes<'de, 'a, D>(
+    deserializer: D,
+) -> Result<Option<Vec<MiV2FileData<'a>>>, D::Error>
+where
+    D: ::serde::de::Deserializer<'de>,
+{
+    file_tree_from_bytes(deserializer).map(Some)
+}
+
+fn walk_file_tree<'a>(
+    node: Value,
+    path: &mut Vec<Cow<'a, str>>,
+    files: &mut Vec<MiV2FileData<'a>>,
+) -> Result<(), &'static str> {
+    let dict = match node {
+        Value::Dict(dict) => dict,
+        _ => return Err("file tree node is not a dict"),
+    };
+    // Walk children in key order so the file list is stable.
+    let mut entries: Vec<(Vec<u8>, Value)> = dict.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, child) in entries {
+        if name.is_empty() {
+            // A file: the empty key holds its length and pieces root.
+            if path.is_empty() {
+                return Err("file tree has a file with no name");
+            }
+            let mut props = match child {
+                Value::Dict(props) => props,
+                _ => return Err("file entry is not a dict"),
+            };
+            let length = match props.remove(&b"length"[..]) {
+                Some(Value::Int(length)) if length >= 0 => length as u64,
+                _ => return Err("file entry has no valid length"),
+            };
+            let pieces_root = match props.remove(&b"pieces root"[..]) {
+                Some(Value::Bytes(root)) => {
+                    Some(Sha256Hash::new(root).ok_or("pieces root is not 32 bytes")?)
+                }
+                None => None,
+                _ => return Err("pieces root is not a byte string"),
+            };
+            if length > 0 && pieces_root.is_none() {
+                return Err("non-empty file has no pieces root");
+            }
+            files.push(MiV2FileData {
+                length,
+                path: path.clone(),
+                pieces_root,
+            });
+        } else {
+            let name = String::from_utf8(name).map_err(|_| "file name is not utf-8")?;
+            path.push(Cow::Owned(name));
+            walk_file_tree(child, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct MiFileData<'a> {
-    length: u64,
-    path: Vec<Cow<'a, str>>,
+    pub length: u64,
+    pub path: Vec<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<Cow<'a, str>>,
+}
+
+impl<'a> MiFileData<'a> {
+    pub fn into_owned(self) -> MiFileData<'static> {
+        MiFileData {
+            length: self.length,
+            path: owned_path(self.path),
+            attr: self.attr.map(owned),
+        }
+    }
+
+    /// Padding files (BEP 47) hold zeros to align the next file to a piece
+    /// boundary.  They are never written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().map_or(false, |attr| attr.contains('p'))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use std::io::prelude::*;
+    use testutil::read_torrent;
 
     #[test]
     fn into_metainfo() {
-        let mut b = vec![];
-        let filename = "data/archlinux-2017.12.01-x86_64.iso.torrent";
-        let mut f = File::open(&filename).unwrap();
-        f.read_to_end(&mut b).expect("read");
+        let b = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
         let mi = MetaInfo::from_bytes(&b).expect("deserialize");
         assert_eq!(mi.announce, "http://tracker.archlinux.org:6969/announce")
     }
 
     #[test]
     fn into_moby_metainfo() {
-        let mut b = vec![];
-        let mut f = File::open("data/These Systems Are Failing.torrent").unwrap();
-        f.read_to_end(&mut b).expect("read");
+        let b = read_torrent("data/These Systems Are Failing.torrent");
         let mi = MetaInfo::from_bytes(&b).expect("deserialize");
         assert_eq!(
             mi.announce,
@@ -297,13 +797,108 @@ mod tests {
         )
     }
 
+    #[test]
+    fn trackerless_nodes() {
+        let mut b = b"d4:infod6:lengthi100e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        b.extend(&[0; 20]);
+        b.extend(&b"e5:nodesll9:127.0.0.1i6881eel7:dht.orgi6882eel3:::1i6883eeee"[..]);
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        assert_eq!(mi.announce, "");
+        assert_eq!(
+            mi.dht_nodes(),
+            vec!["127.0.0.1:6881", "dht.org:6882", "[::1]:6883"]
+        );
+    }
+
     #[test]
     fn error_if_pieces_not_multiples_of_20_chars() {
-        let mut b = vec![];
-        let mut f = File::open("data/archerror.torrent").unwrap();
-        f.read_to_end(&mut b).expect("read");
+        let b = read_torrent("data/archerror.torrent");
         if let Some(mi) = MetaInfo::from_bytes(&b) {
             panic!("Unexpected success {:?}", mi);
         }
     }
+
+    #[test]
+    fn into_v2_metainfo() {
+        let b = read_torrent("data/v2-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        assert_eq!(mi.meta_version(), 2);
+        assert_eq!(mi.info.length(), 40100);
+        let info = match mi.info {
+            Info::MiV2Info(ref info) => info,
+            ref other => panic!("Expected v2 info, got {:?}", other),
+        };
+        assert_eq!(info.file_tree.len(), 2);
+        assert_eq!(info.file_tree[0].path, vec!["a.txt"]);
+        assert_eq!(info.file_tree[1].length, 100);
+
+        // Only the file larger than a piece has a layer: two 32 KiB pieces.
+        let root = info.file_tree[0].pieces_root.as_ref().unwrap();
+        assert_eq!(mi.piece_layer(root).unwrap().len(), 2);
+        let root = info.file_tree[1].pieces_root.as_ref().unwrap();
+        assert!(mi.piece_layer(root).is_none());
+    }
+
+    #[test]
+    fn into_hybrid_metainfo() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        assert!(mi.info.is_hybrid());
+        assert_eq!(mi.meta_version(), 2);
+        let layout = mi.info.hybrid_layout().expect("layout");
+        assert_eq!(layout.len(), 2);
+        assert_eq!(layout[0].offset, 0);
+        // a.txt is 40000 bytes, padded out to two 32 KiB pieces.
+        assert_eq!(layout[1].offset, 65536);
+        assert_eq!(layout[1].first_piece(mi.info.piece_length()), 2);
+    }
+
+    #[test]
+    fn v1_torrent_has_no_hybrid_layout() {
+        let b = read_torrent("data/These Systems Are Failing.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        assert!(!mi.info.is_hybrid());
+        assert!(mi.info.hybrid_layout().is_err());
+    }
+
+    #[test]
+    fn v2_info_hash() {
+        let b = read_torrent("data/v2-test.torrent");
+        let hash = get_info_hash_v2(b).expect("info hash");
+        assert_eq!(
+            hash.as_bytes(),
+            &b"\x91\xe4\x09\x9d\x1a\xa7\x75\xc6\x11\xfe\x92\x46\x23\xcc\x6f\xa8\
+               \x89\x1a\xd8\x4b\xc4\x82\xc2\xa3\xe2\xfb\x9b\x05\x60\x44\xbb\x63"[..]
+        );
+        assert_eq!(hash.truncated().as_bytes(), &hash.as_bytes()[..20]);
+    }
+
+    #[test]
+    fn owned_metainfo_outlives_the_torrent_file() {
+        let owned = {
+            let b = read_torrent("data/These Systems Are Failing.torrent");
+            let owned = OwnedMetaInfo::from_bytes(&b).expect("deserialize");
+            assert_eq!(owned.metainfo, MetaInfo::from_bytes(&b).unwrap());
+            assert_eq!(
+                owned.info_hash().as_bytes(),
+                get_info_hash(b).unwrap().digest().bytes()
+            );
+            owned
+        };
+        assert_eq!(owned.announce, "http://tracker.bundles.bittorrent.com/announce");
+        assert_eq!(owned.info_bytes()[0], b'd');
+
+        // v2-only torrents go by their truncated v2 hash.
+        let b = read_torrent("data/v2-test.torrent");
+        let owned = OwnedMetaInfo::from_bytes(&b).expect("deserialize");
+        assert_eq!(owned.info_hash(), get_info_hash_v2(b).unwrap().truncated());
+    }
+
+    #[test]
+    fn url_list_may_be_a_string() {
+        let b = read_torrent("data/redox-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        assert_eq!(mi.url_list, Some(vec![]));
+        assert_eq!(mi.created_by, Some("qBittorrent v4.0.3".into()));
+    }
 }
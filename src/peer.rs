@@ -0,0 +1,503 @@
+//! The state of one peer connection, and how it changes with each message.
+//!
+//! `Peer` doesn't do any I/O.  Feed it the messages the peer sends, and it
+//! hands back the messages to send in reply.
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use sha1::Sha1;
+
+use bitfield::BitField;
+use extension::{PeerExtensions, REQUEST_QUEUE};
+use peermsg::{self, Handshake, Message, EXTENSION_PROTOCOL, FAST_EXTENSION};
+use storage::Layout;
+
+/// How many pieces each peer may request while we're choking it.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// The largest block a peer may ask for.
+pub const MAX_BLOCK: u32 = 16 * 1024;
+
+/// How far back transfer rates look.
+pub const RATE_WINDOW: Duration = Duration::from_secs(20);
+
+// Transfers this close together share a sample.
+const RATE_BUCKET: Duration = Duration::from_secs(1);
+
+/// A transfer rate, averaged over the last `RATE_WINDOW`.
+#[derive(Clone, Debug, Default)]
+pub struct Rate {
+    // Bytes transferred, and when each bucket started, oldest first.  At
+    // most one bucket per `RATE_BUCKET` of the window is kept, however
+    // rarely the rate is asked for.
+    samples: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl Rate {
+    pub fn new() -> Rate {
+        Rate::default()
+    }
+
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.forget(now);
+        self.total += bytes;
+        if let Some(&mut (when, ref mut bucket)) = self.samples.back_mut() {
+            if now.duration_since(when) < RATE_BUCKET {
+                *bucket += bytes;
+                return;
+            }
+        }
+        self.samples.push_back((now, bytes));
+    }
+
+    // Drops samples from before the window.
+    fn forget(&mut self, now: Instant) {
+        while let Some(&(when, _)) = self.samples.front() {
+            if now.duration_since(when) < RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.forget(now);
+        let bytes: u64 = self.samples.iter().map(|&(_, bytes)| bytes).sum();
+        bytes as f64 / RATE_WINDOW.as_secs() as f64
+    }
+
+    /// Bytes transferred since the start.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// The allowed fast set for a peer (BEP 6): `k` pieces picked from hashes
+/// of the peer's /24 network and the info hash, so every client works out
+/// the same set for the same peer.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], num_pieces: u32, k: usize) -> Vec<u32> {
+    let mut set = Vec::with_capacity(k);
+    let k = ::std::cmp::min(k, num_pieces as usize);
+    let octets = ip.octets();
+    let mut x = vec![octets[0], octets[1], octets[2], 0];
+    x.extend(info_hash);
+    while set.len() < k {
+        let mut sha = Sha1::new();
+        sha.update(&x);
+        x = sha.digest().bytes().to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = (chunk[0] as u32) << 24 | (chunk[1] as u32) << 16 | (chunk[2] as u32) << 8
+                | chunk[3] as u32;
+            let index = y % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub struct Peer {
+    pub peer_id: Vec<u8>,
+    pub addr: SocketAddr,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// The pieces the peer has.
+    pub bitfield: BitField,
+    /// Whether we both support the fast extension.
+    pub fast: bool,
+    /// Whether we both support the extension protocol, and what the peer
+    /// said in its extended handshake.
+    pub extended: bool,
+    pub extensions: PeerExtensions,
+    /// Pieces the peer may request from us while choked.
+    pub allowed_fast: HashSet<u32>,
+    /// Pieces we may request from the peer while it chokes us.
+    pub peer_allowed_fast: HashSet<u32>,
+    /// Pieces the peer suggested we download, most recent last.
+    pub suggested: Vec<u32>,
+    /// The peer's requests that we have yet to answer.
+    pub requests: VecDeque<(u32, u32, u32)>,
+    /// Our requests that the peer has yet to answer.
+    pub pending: Vec<(u32, u32, u32)>,
+    /// Payload the peer has sent us.
+    pub download_rate: Rate,
+    /// Payload we've sent the peer.
+    pub upload_rate: Rate,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, handshake: &Handshake, num_pieces: usize) -> Peer {
+        Peer {
+            peer_id: handshake.peer_id.clone(),
+            addr,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: BitField::new(num_pieces),
+            fast: handshake.supports(FAST_EXTENSION),
+            extended: handshake.supports(EXTENSION_PROTOCOL),
+            extensions: PeerExtensions::new(),
+            allowed_fast: HashSet::new(),
+            peer_allowed_fast: HashSet::new(),
+            suggested: Vec::new(),
+            requests: VecDeque::new(),
+            pending: Vec::new(),
+            download_rate: Rate::new(),
+            upload_rate: Rate::new(),
+        }
+    }
+
+    /// The messages that open the connection, after the handshake: what
+    /// pieces we have, and which the peer may fetch while choked.
+    pub fn start(&mut self, have: &BitField, info_hash: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        if self.fast && have.all() {
+            messages.push(peermsg::have_all());
+        } else if self.fast && have.none() {
+            messages.push(peermsg::have_none());
+        } else if !have.none() {
+            messages.push(peermsg::bitfield(have));
+        }
+        if self.fast {
+            // BEP 6 only defines the set for IPv4 peers.
+            if let IpAddr::V4(ip) = self.addr.ip() {
+                let set = allowed_fast_set(ip, info_hash, have.len() as u32, ALLOWED_FAST_COUNT);
+                for piece in set {
+                    self.allowed_fast.insert(piece);
+                    messages.push(peermsg::allowed_fast(piece));
+                }
+            }
+        }
+        messages
+    }
+
+    fn check_piece_index(&self, piece: u32) -> io::Result<()> {
+        if (piece as usize) < self.bitfield.len() {
+            Ok(())
+        } else {
+            Err(protocol_error("piece index out of range"))
+        }
+    }
+
+    fn require_fast(&self) -> io::Result<()> {
+        if self.fast {
+            Ok(())
+        } else {
+            Err(protocol_error("fast extension message without the fast extension"))
+        }
+    }
+
+    /// Updates our view of the peer for a message it sent, and returns any
+    /// replies.  `piece` messages only clear the matching request; storing
+    /// the data is up to the caller.
+    pub fn handle(&mut self, msg: &Message) -> io::Result<Vec<Vec<u8>>> {
+        let mut replies = Vec::new();
+        match *msg {
+            Message::Choke => {
+                self.peer_choking = true;
+                // Without the fast extension, a choke silently drops all
+                // our requests.  With it, the peer rejects them explicitly.
+                if !self.fast {
+                    self.pending.clear();
+                }
+            }
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            Message::Have(piece) => {
+                self.check_piece_index(piece)?;
+                self.bitfield.set(piece as usize, true);
+            }
+            Message::Bitfield(ref bytes) => {
+                self.bitfield = BitField::from_bytes(bytes, self.bitfield.len())
+                    .ok_or_else(|| protocol_error("bad bitfield"))?;
+            }
+            Message::Request(piece, begin, length) => {
+                self.check_piece_index(piece)?;
+                let allowed = !self.am_choking || self.allowed_fast.contains(&piece);
+                let valid = length > 0 && length <= MAX_BLOCK;
+                if allowed && valid && self.requests.len() < REQUEST_QUEUE as usize {
+                    if !self.requests.contains(&(piece, begin, length)) {
+                        self.requests.push_back((piece, begin, length));
+                    }
+                } else if self.fast {
+                    replies.push(peermsg::reject_request(piece, begin, length));
+                }
+            }
+            Message::Cancel(piece, begin, length) => {
+                let before = self.requests.len();
+                self.requests.retain(|&req| req != (piece, begin, length));
+                // The fast extension wants every request answered, even
+                // cancelled ones.
+                if self.fast && self.requests.len() < before {
+                    replies.push(peermsg::reject_request(piece, begin, length));
+                }
+            }
+            Message::Piece(piece, begin, ref data) => {
+                let req = (piece, begin, data.len() as u32);
+                self.pending.retain(|&pending| pending != req);
+                self.download_rate.add(data.len() as u64, Instant::now());
+            }
+            Message::SuggestPiece(piece) => {
+                self.require_fast()?;
+                self.check_piece_index(piece)?;
+                self.suggested.retain(|&p| p != piece);
+                self.suggested.push(piece);
+            }
+            Message::HaveAll => {
+                self.require_fast()?;
+                self.bitfield.set_all(true);
+            }
+            Message::HaveNone => {
+                self.require_fast()?;
+                self.bitfield.set_all(false);
+            }
+            Message::RejectRequest(piece, begin, length) => {
+                self.require_fast()?;
+                self.pending.retain(|&req| req != (piece, begin, length));
+            }
+            Message::AllowedFast(piece) => {
+                self.require_fast()?;
+                self.check_piece_index(piece)?;
+                self.peer_allowed_fast.insert(piece);
+            }
+            _ => {}
+        }
+        Ok(replies)
+    }
+
+    /// Takes up to `max` of the peer's queued requests to answer.  Requests
+    /// for pieces we don't have, or for ranges outside the piece, are
+    /// rejected into `rejections`, or with no fast extension, dropped.
+    pub fn take_requests(
+        &mut self,
+        layout: &Layout,
+        have: &BitField,
+        max: usize,
+        rejections: &mut Vec<Vec<u8>>,
+    ) -> Vec<(u32, u32, u32)> {
+        let mut taken = Vec::new();
+        while taken.len() < max {
+            let (piece, begin, length) = match self.requests.pop_front() {
+                Some(req) => req,
+                None => break,
+            };
+            let size = layout.piece_size(piece);
+            let in_piece = begin as u64 + length as u64 <= size as u64;
+            if !have.get(piece as usize) || !in_piece {
+                if self.fast {
+                    rejections.push(peermsg::reject_request(piece, begin, length));
+                }
+                continue;
+            }
+            taken.push((piece, begin, length));
+        }
+        taken
+    }
+
+    /// The header of the message carrying a block we've read for the peer,
+    /// to go out ahead of the block, unless we've choked it since it asked.
+    pub fn send_block(
+        &mut self,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        if self.am_choking && !self.allowed_fast.contains(&piece) {
+            return None;
+        }
+        self.upload_rate.add(data.len() as u64, now);
+        Some(peermsg::piece_header(piece, begin, data.len()))
+    }
+
+    /// Whether we may send the peer a request for `piece` right now.
+    pub fn can_request(&self, piece: u32) -> bool {
+        !self.peer_choking || self.peer_allowed_fast.contains(&piece)
+    }
+
+    /// Records a request we're sending, and returns the message.
+    pub fn request(&mut self, piece: u32, begin: u32, length: u32) -> Vec<u8> {
+        self.pending.push((piece, begin, length));
+        peermsg::request(piece, begin, length)
+    }
+
+    /// Chokes the peer.  Its queued requests are rejected, or with no fast
+    /// extension, dropped, except for pieces in its allowed fast set.
+    pub fn choke(&mut self) -> Vec<Vec<u8>> {
+        if self.am_choking {
+            return Vec::new();
+        }
+        self.am_choking = true;
+        let mut messages = vec![peermsg::choke()];
+        let allowed_fast = &self.allowed_fast;
+        let (keep, drop): (VecDeque<_>, VecDeque<_>) = self.requests
+            .drain(..)
+            .partition(|&(piece, _, _)| allowed_fast.contains(&piece));
+        self.requests = keep;
+        if self.fast {
+            for (piece, begin, length) in drop {
+                messages.push(peermsg::reject_request(piece, begin, length));
+            }
+        }
+        messages
+    }
+
+    pub fn unchoke(&mut self) -> Vec<Vec<u8>> {
+        if !self.am_choking {
+            return Vec::new();
+        }
+        self.am_choking = false;
+        vec![peermsg::unchoke()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use storage::{FileStorage, Storage};
+    use testutil::{read_torrent, TempDir};
+
+    fn peer(fast: bool) -> Peer {
+        let mut reserved = [0; 8];
+        if fast {
+            reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+        }
+        let handshake = Handshake {
+            reserved,
+            info_hash: vec![0xaa; 20],
+            peer_id: vec![b'x'; 20],
+        };
+        Peer::new("80.4.4.200:6881".parse().unwrap(), &handshake, 1313)
+    }
+
+    #[test]
+    fn rate_forgets_old_samples() {
+        let start = Instant::now();
+        let mut rate = Rate::new();
+        rate.add(40_000, start);
+        rate.add(20_000, start + Duration::from_secs(10));
+        assert_eq!(rate.rate(start + Duration::from_secs(10)), 3000.0);
+        assert_eq!(rate.rate(start + RATE_WINDOW), 1000.0);
+        assert_eq!(rate.total(), 60_000);
+
+        // Never asked for, it still only keeps a window's worth.
+        for ms in 0..60_000 {
+            rate.add(16384, start + Duration::from_millis(ms));
+        }
+        assert!(rate.samples.len() <= 20);
+    }
+
+    #[test]
+    fn allowed_fast_matches_bep_6() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn reject_requests_while_choking() {
+        let mut peer = peer(true);
+        let have = BitField::full(1313);
+        let start = peer.start(&have, &[0xaa; 20]);
+        assert_eq!(start[0], peermsg::have_all());
+        assert_eq!(start.len(), 1 + ALLOWED_FAST_COUNT);
+        assert!(peer.allowed_fast.contains(&1059));
+
+        let replies = peer.handle(&Message::Request(1, 0, 0x4000)).unwrap();
+        assert_eq!(replies, vec![peermsg::reject_request(1, 0, 0x4000)]);
+        assert!(peer.handle(&Message::Request(1059, 0, 0x4000)).unwrap().is_empty());
+
+        peer.unchoke();
+        peer.handle(&Message::Request(2, 0, 0x4000)).unwrap();
+        let messages = peer.choke();
+        assert_eq!(
+            messages,
+            vec![peermsg::choke(), peermsg::reject_request(2, 0, 0x4000)]
+        );
+        assert_eq!(peer.requests, vec![(1059, 0, 0x4000)]);
+    }
+
+    #[test]
+    fn serve_queued_requests() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("peer-serve");
+        let storage = FileStorage::new(&mi.info, &dir).unwrap();
+        storage.write_block(2, 0, &[b'x'; 100]).unwrap();
+
+        let mut peer = peer(true);
+        peer.bitfield = BitField::new(3);
+        peer.unchoke();
+        let mut have = BitField::new(3);
+        have.set(2, true);
+        assert_eq!(
+            peer.handle(&Message::Request(2, 0, MAX_BLOCK + 1)).unwrap(),
+            vec![peermsg::reject_request(2, 0, MAX_BLOCK + 1)]
+        );
+        for &(piece, begin, length) in &[(2, 0, 50), (2, 50, 51), (0, 0, 0x4000), (2, 60, 10)] {
+            peer.handle(&Message::Request(piece, begin, length)).unwrap();
+        }
+        peer.handle(&Message::Cancel(2, 60, 10)).unwrap();
+
+        let mut sent = Vec::new();
+        // One at a time, so replies come out in the order they were asked.
+        loop {
+            let taken = peer.take_requests(storage.layout(), &have, 1, &mut sent);
+            let (piece, begin, length) = match taken.first() {
+                Some(&req) => req,
+                None => break,
+            };
+            let data = storage.read_block(piece, begin, length).unwrap();
+            let header = peer.send_block(piece, begin, &data, Instant::now());
+            sent.extend(header.map(|header| [header, data].concat()));
+        }
+        assert_eq!(
+            sent,
+            vec![
+                peermsg::piece(2, 0, &[b'x'; 50]),
+                // Past the end of the last piece.
+                peermsg::reject_request(2, 50, 51),
+                // A piece we don't have.
+                peermsg::reject_request(0, 0, 0x4000),
+            ]
+        );
+        assert_eq!(peer.upload_rate.total(), 50);
+        assert!(peer.requests.is_empty());
+    }
+
+    #[test]
+    fn drop_requests_silently_without_fast() {
+        let mut peer = peer(false);
+        assert!(peer.handle(&Message::Request(1, 0, 0x4000)).unwrap().is_empty());
+        assert!(peer.requests.is_empty());
+        assert!(peer.handle(&Message::HaveAll).is_err());
+
+        peer.request(3, 0, 0x4000);
+        peer.handle(&Message::Choke).unwrap();
+        assert!(peer.pending.is_empty());
+    }
+}
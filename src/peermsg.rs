@@ -1,3 +1,8 @@
+use std::io;
+
+use bitfield::BitField;
+use metainfo::Sha256Hash;
+
 fn push_u32(vec: &mut Vec<u8>, value: u32) {
     // Redo with byteorder crate
     vec.push((value >> 24) as u8);
@@ -6,49 +11,98 @@ fn push_u32(vec: &mut Vec<u8>, value: u32) {
     vec.push(value as u8);
 }
 
+const PROTOCOL: &[u8] = b"\x13BitTorrent protocol";
+
+/// Flags in the reserved bytes of the handshake, as (byte, mask).
+pub const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+pub const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+/// The reserved bytes we send, advertising what we support.
+pub fn reserved() -> [u8; 8] {
+    let mut reserved = [0; 8];
+    for &(byte, mask) in &[EXTENSION_PROTOCOL, FAST_EXTENSION] {
+        reserved[byte] |= mask;
+    }
+    reserved
+}
+
 // This should take a Peer and a Torrent object, and calculate the &[u8]s from them.
-fn _peer_handshake(info_hash: &[u8], peer_id: &[u8]) -> Vec<u8> {
+pub fn peer_handshake(info_hash: &[u8], peer_id: &[u8]) -> Vec<u8> {
     let mut handshake = Vec::with_capacity(68);
-    handshake.extend(b"\x13BitTorrent protocol");
-    handshake.extend(&[0; 8]);
+    handshake.extend(PROTOCOL);
+    handshake.extend(&reserved());
     handshake.extend(info_hash);
     handshake.extend(peer_id);
     handshake
 }
 
-fn keepalive() -> Vec<u8> {
-    vec![0]
+/// A handshake received from a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+}
+
+impl Handshake {
+    /// Parses a handshake from the front of `buf`, or returns None if it
+    /// hasn't all arrived yet.  A handshake is always 68 bytes.
+    pub fn parse(buf: &[u8]) -> io::Result<Option<Handshake>> {
+        let prefix = ::std::cmp::min(buf.len(), PROTOCOL.len());
+        if buf[..prefix] != PROTOCOL[..prefix] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a BitTorrent handshake"));
+        }
+        if buf.len() < 68 {
+            return Ok(None);
+        }
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+        Ok(Some(Handshake {
+            reserved,
+            info_hash: buf[28..48].to_vec(),
+            peer_id: buf[48..68].to_vec(),
+        }))
+    }
+
+    /// Whether the peer set one of the reserved-byte flags.
+    pub fn supports(&self, flag: (usize, u8)) -> bool {
+        self.reserved[flag.0] & flag.1 != 0
+    }
+}
+
+pub fn keepalive() -> Vec<u8> {
+    vec![0; 4]
 }
 
-fn choke() -> Vec<u8> {
+pub fn choke() -> Vec<u8> {
     let mut msg = Vec::with_capacity(5);
     push_u32(&mut msg, 1); // length
     msg.push(0); // choke id
     msg
 }
 
-fn unchoke() -> Vec<u8> {
+pub fn unchoke() -> Vec<u8> {
     let mut msg = Vec::with_capacity(5);
     push_u32(&mut msg, 1); // length
     msg.push(1); // unchoke id
     msg
 }
 
-fn interested() -> Vec<u8> {
+pub fn interested() -> Vec<u8> {
     let mut msg = Vec::with_capacity(5);
     push_u32(&mut msg, 1); // length
     msg.push(2); // interested id
     msg
 }
 
-fn not_interested() -> Vec<u8> {
+pub fn not_interested() -> Vec<u8> {
     let mut msg = Vec::with_capacity(5);
     push_u32(&mut msg, 1); // length
     msg.push(3); // not interested id
     msg
 }
 
-fn have(piece: u32) -> Vec<u8> {
+pub fn have(piece: u32) -> Vec<u8> {
     let mut msg = Vec::with_capacity(6);
     push_u32(&mut msg, 5); // length
     msg.push(4); // have msg_id
@@ -56,11 +110,16 @@ fn have(piece: u32) -> Vec<u8> {
     msg
 }
 
-fn bitfield() -> Vec<u8> {
-    unimplemented!()
+pub fn bitfield(field: &BitField) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut msg = Vec::with_capacity(5 + bytes.len());
+    push_u32(&mut msg, 1 + bytes.len() as u32); // length
+    msg.push(5); // bitfield msg_id
+    msg.extend(bytes);
+    msg
 }
 
-fn request(piece: u32, begin: u32, length: u32) -> Vec<u8> {
+pub fn request(piece: u32, begin: u32, length: u32) -> Vec<u8> {
     let mut msg = Vec::with_capacity(17);
     push_u32(&mut msg, 13); // length
     msg.push(6); // request message id
@@ -70,17 +129,34 @@ fn request(piece: u32, begin: u32, length: u32) -> Vec<u8> {
     msg
 }
 
-fn piece(piece: u32, begin: u32, data: &[u8]) -> Vec<u8> {
-    let mut msg = Vec::with_capacity(13 + data.len());
-    push_u32(&mut msg, 9 + data.len() as u32); // length
+pub fn piece(piece: u32, begin: u32, data: &[u8]) -> Vec<u8> {
+    let mut msg = piece_header(piece, begin, data.len());
+    msg.extend(data);
+    msg
+}
+
+/// The start of a `piece` message, for a block of `length` bytes to follow
+/// it.
+pub fn piece_header(piece: u32, begin: u32, length: usize) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(13 + length);
+    push_u32(&mut msg, 9 + length as u32); // length
     msg.push(7); // piece message id
     push_u32(&mut msg, piece);
     push_u32(&mut msg, begin);
-    msg.extend(data);
     msg
 }
 
-fn cancel(piece: u32, begin: u32, length: u32) -> Vec<u8> {
+/// How much of a framed message is piece data, as opposed to protocol
+/// overhead.  Only `piece` messages carry any.
+pub fn payload_len(msg: &[u8]) -> usize {
+    if msg.len() > 13 && msg[4] == 7 {
+        msg.len() - 13
+    } else {
+        0
+    }
+}
+
+pub fn cancel(piece: u32, begin: u32, length: u32) -> Vec<u8> {
     let mut msg = Vec::with_capacity(17);
     push_u32(&mut msg, 13); // length
     msg.push(8); // cancel msg_id
@@ -90,6 +166,213 @@ fn cancel(piece: u32, begin: u32, length: u32) -> Vec<u8> {
     msg
 }
 
+// The fast extension (BEP 6) messages, only sent to peers that set the
+// fast extension bit in their handshake.
+
+pub fn suggest_piece(piece: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(9);
+    push_u32(&mut msg, 5); // length
+    msg.push(0x0d); // suggest piece msg_id
+    push_u32(&mut msg, piece);
+    msg
+}
+
+pub fn have_all() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(5);
+    push_u32(&mut msg, 1); // length
+    msg.push(0x0e); // have all msg_id
+    msg
+}
+
+pub fn have_none() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(5);
+    push_u32(&mut msg, 1); // length
+    msg.push(0x0f); // have none msg_id
+    msg
+}
+
+pub fn reject_request(piece: u32, begin: u32, length: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(17);
+    push_u32(&mut msg, 13); // length
+    msg.push(0x10); // reject request msg_id
+    push_u32(&mut msg, piece);
+    push_u32(&mut msg, begin);
+    push_u32(&mut msg, length);
+    msg
+}
+
+pub fn allowed_fast(piece: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(9);
+    push_u32(&mut msg, 5); // length
+    msg.push(0x11); // allowed fast msg_id
+    push_u32(&mut msg, piece);
+    msg
+}
+
+/// An extension protocol (BEP 10) message.  Id 0 is the extended
+/// handshake; other ids are whatever the receiving side assigned in its
+/// handshake's `m` dict.
+pub fn extended(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(6 + payload.len());
+    push_u32(&mut msg, 2 + payload.len() as u32); // length
+    msg.push(20); // extended msg_id
+    msg.push(id);
+    msg.extend(payload);
+    msg
+}
+
+/// The fields shared by the v2 `hash request`, `hashes` and `hash reject`
+/// messages (BEP 52).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: Sha256Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+fn push_hash_request(msg: &mut Vec<u8>, req: &HashRequest) {
+    msg.extend(req.pieces_root.as_bytes());
+    push_u32(msg, req.base_layer);
+    push_u32(msg, req.index);
+    push_u32(msg, req.length);
+    push_u32(msg, req.proof_layers);
+}
+
+pub fn hash_request(req: &HashRequest) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(53);
+    push_u32(&mut msg, 49); // length
+    msg.push(21); // hash request msg_id
+    push_hash_request(&mut msg, req);
+    msg
+}
+
+/// `hashes` holds the requested hashes followed by the proof (uncle)
+/// hashes, nearest to the base layer first.
+pub fn hashes(req: &HashRequest, hashes: &[Sha256Hash]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(53 + 32 * hashes.len());
+    push_u32(&mut msg, 49 + 32 * hashes.len() as u32); // length
+    msg.push(22); // hashes msg_id
+    push_hash_request(&mut msg, req);
+    for hash in hashes {
+        msg.extend(hash.as_bytes());
+    }
+    msg
+}
+
+pub fn hash_reject(req: &HashRequest) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(53);
+    push_u32(&mut msg, 49); // length
+    msg.push(23); // hash reject msg_id
+    push_hash_request(&mut msg, req);
+    msg
+}
+
+/// A message received from a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(u32, u32, u32),
+    Piece(u32, u32, Vec<u8>),
+    Cancel(u32, u32, u32),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(u32, u32, u32),
+    AllowedFast(u32),
+    HashRequest(HashRequest),
+    Hashes(HashRequest, Vec<Sha256Hash>),
+    HashReject(HashRequest),
+    Extended(u8, Vec<u8>),
+    /// A message id we don't know, with its payload.
+    Unknown(u8, Vec<u8>),
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+fn read_hash_request(payload: &[u8]) -> io::Result<HashRequest> {
+    if payload.len() < 48 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hash request too short"));
+    }
+    Ok(HashRequest {
+        pieces_root: Sha256Hash::new(payload[..32].to_vec()).unwrap(),
+        base_layer: read_u32(&payload[32..]),
+        index: read_u32(&payload[36..]),
+        length: read_u32(&payload[40..]),
+        proof_layers: read_u32(&payload[44..]),
+    })
+}
+
+/// Parses one length-prefixed message from the front of `buf`.
+///
+/// Returns the message and the number of bytes it took up, or None if
+/// `buf` doesn't hold a whole message yet.
+pub fn parse(buf: &[u8]) -> io::Result<Option<(Message, usize)>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let length = read_u32(buf) as usize;
+    if buf.len() < 4 + length {
+        return Ok(None);
+    }
+    if length == 0 {
+        return Ok(Some((Message::KeepAlive, 4)));
+    }
+    let id = buf[4];
+    let payload = &buf[5..4 + length];
+    let wrong_size = || io::Error::new(io::ErrorKind::InvalidData, "wrong message size");
+    let msg = match id {
+        0..=3 if !payload.is_empty() => return Err(wrong_size()),
+        0 => Message::Choke,
+        1 => Message::Unchoke,
+        2 => Message::Interested,
+        3 => Message::NotInterested,
+        4 if payload.len() != 4 => return Err(wrong_size()),
+        4 => Message::Have(read_u32(payload)),
+        5 => Message::Bitfield(payload.to_vec()),
+        6 | 8 if payload.len() != 12 => return Err(wrong_size()),
+        6 => Message::Request(read_u32(payload), read_u32(&payload[4..]), read_u32(&payload[8..])),
+        7 if payload.len() < 8 => return Err(wrong_size()),
+        7 => Message::Piece(read_u32(payload), read_u32(&payload[4..]), payload[8..].to_vec()),
+        8 => Message::Cancel(read_u32(payload), read_u32(&payload[4..]), read_u32(&payload[8..])),
+        0x0d | 0x11 if payload.len() != 4 => return Err(wrong_size()),
+        0x0d => Message::SuggestPiece(read_u32(payload)),
+        0x0e | 0x0f if !payload.is_empty() => return Err(wrong_size()),
+        0x0e => Message::HaveAll,
+        0x0f => Message::HaveNone,
+        0x10 if payload.len() != 12 => return Err(wrong_size()),
+        0x10 => Message::RejectRequest(
+            read_u32(payload),
+            read_u32(&payload[4..]),
+            read_u32(&payload[8..]),
+        ),
+        0x11 => Message::AllowedFast(read_u32(payload)),
+        20 if payload.is_empty() => return Err(wrong_size()),
+        20 => Message::Extended(payload[0], payload[1..].to_vec()),
+        21 => Message::HashRequest(read_hash_request(payload)?),
+        22 if payload.len() < 48 || (payload.len() - 48) % 32 != 0 => return Err(wrong_size()),
+        22 => Message::Hashes(
+            read_hash_request(payload)?,
+            payload[48..]
+                .chunks(32)
+                .map(|hash| Sha256Hash::new(hash.to_vec()).unwrap())
+                .collect(),
+        ),
+        23 => Message::HashReject(read_hash_request(payload)?),
+        _ => Message::Unknown(id, payload.to_vec()),
+    };
+    Ok(Some((msg, 4 + length)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -103,4 +386,89 @@ mod test {
         );
         assert_eq!(msg.len(), 0x4000 + 13,)
     }
+
+    #[test]
+    fn test_payload_len() {
+        assert_eq!(payload_len(&piece(1, 0, &[0; 100])), 100);
+        assert_eq!(payload_len(&have(1)), 0);
+        assert_eq!(payload_len(&keepalive()), 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = request(1, 0x4000, 0x4000);
+        buf.extend(have(3));
+        let (msg, used) = parse(&buf).unwrap().unwrap();
+        assert_eq!(msg, Message::Request(1, 0x4000, 0x4000));
+        assert_eq!(parse(&buf[used..]).unwrap().unwrap(), (Message::Have(3), 9));
+        assert_eq!(parse(&buf[..used - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let msg = peer_handshake(&[1; 20], b"rbxxxyyyyyzzzzz00000");
+        assert_eq!(Handshake::parse(&msg[..30]).unwrap(), None);
+        let handshake = Handshake::parse(&msg).unwrap().unwrap();
+        assert!(handshake.supports(EXTENSION_PROTOCOL));
+        assert_eq!(handshake.info_hash, vec![1; 20]);
+        assert_eq!(handshake.peer_id, b"rbxxxyyyyyzzzzz00000".to_vec());
+        assert!(Handshake::parse(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_fast_messages() {
+        let mut buf = have_none();
+        buf.extend(reject_request(1, 0x4000, 0x4000));
+        buf.extend(allowed_fast(7));
+        buf.extend(keepalive());
+        let mut messages = vec![];
+        let mut used = 0;
+        while let Some((msg, n)) = parse(&buf[used..]).unwrap() {
+            messages.push(msg);
+            used += n;
+        }
+        assert_eq!(
+            messages,
+            vec![
+                Message::HaveNone,
+                Message::RejectRequest(1, 0x4000, 0x4000),
+                Message::AllowedFast(7),
+                Message::KeepAlive,
+            ]
+        );
+        assert_eq!(used, buf.len());
+    }
+
+    #[test]
+    fn test_extended() {
+        let msg = extended(3, b"d1:ai1ee");
+        assert_eq!(&msg[..6], b"\0\0\0\x0a\x14\x03");
+        assert_eq!(
+            parse(&msg).unwrap().unwrap(),
+            (Message::Extended(3, b"d1:ai1ee".to_vec()), 14)
+        );
+    }
+
+    #[test]
+    fn test_hashes_roundtrip() {
+        let req = HashRequest {
+            pieces_root: Sha256Hash::new(vec![0xab; 32]).unwrap(),
+            base_layer: 0,
+            index: 2,
+            length: 2,
+            proof_layers: 1,
+        };
+        let list = vec![Sha256Hash::new(vec![1; 32]).unwrap(); 3];
+        let msg = hashes(&req, &list);
+        assert_eq!(
+            parse(&msg).unwrap().unwrap(),
+            (Message::Hashes(req.clone(), list), msg.len())
+        );
+        let msg = hash_reject(&req);
+        assert_eq!(&msg[..5], b"\0\0\0\x31\x17");
+        assert_eq!(
+            parse(&msg).unwrap().unwrap(),
+            (Message::HashReject(req), 53)
+        );
+    }
 }
@@ -0,0 +1,309 @@
+//! Peer exchange (ut_pex, BEP 11).
+//!
+//! Every minute or so, each peer is told which peers we've connected to or
+//! dropped since the last message we sent it.  Peers it tells us about go
+//! into the torrent's `ConnectQueue`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bytes::ByteBuf;
+
+use compact;
+use connect::ConnectQueue;
+use extension::{ExtendedHandshake, Extension};
+use metainfo::Info;
+
+/// BEP 11 asks for no more than one message a minute per peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Nor more than 50 added peers per message.
+const MAX_ADDED: usize = 50;
+
+// Flags for `added.f`.
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+fn bytes(buf: Vec<u8>) -> ByteBuf {
+    ByteBuf::from(buf)
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        for &(v6, key, flags_key, dropped_key) in &[
+            (false, "added", "added.f", "dropped"),
+            (true, "added6", "added6.f", "dropped6"),
+        ] {
+            let mut added = Vec::new();
+            let mut flags = Vec::new();
+            for &(addr, flag) in self.added.iter().filter(|&&(addr, _)| addr.is_ipv6() == v6) {
+                compact::push_addr(&mut added, &addr);
+                flags.push(flag);
+            }
+            let mut dropped = Vec::new();
+            for addr in self.dropped.iter().filter(|addr| addr.is_ipv6() == v6) {
+                compact::push_addr(&mut dropped, addr);
+            }
+            if !added.is_empty() {
+                dict.insert(key, bytes(added));
+                dict.insert(flags_key, bytes(flags));
+            }
+            if !dropped.is_empty() {
+                dict.insert(dropped_key, bytes(dropped));
+            }
+        }
+        to_bytes(&dict).expect("pex message always serializes")
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<PexMessage> {
+        let dict: HashMap<String, ByteBuf> = from_bytes(payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad ut_pex message"))?;
+        let empty = ByteBuf::new();
+        let get = |key: &str| dict.get(key).unwrap_or(&empty);
+        let mut msg = PexMessage::default();
+        for &(added, flags) in &[("added", "added.f"), ("added6", "added6.f")] {
+            let addrs = if added == "added" {
+                compact::read_v4_list(get(added))
+            } else {
+                compact::read_v6_list(get(added))
+            };
+            let flags = get(flags);
+            for (i, addr) in addrs.into_iter().enumerate() {
+                msg.added.push((addr, flags.get(i).cloned().unwrap_or(0)));
+            }
+        }
+        msg.dropped.extend(compact::read_v4_list(get("dropped")));
+        msg.dropped.extend(compact::read_v6_list(get("dropped6")));
+        Ok(msg)
+    }
+}
+
+struct PexPeer {
+    // Where the peer takes connections.  For a peer that connected to us,
+    // that's only known once its extended handshake gives its port.
+    addr: SocketAddr,
+    listening: bool,
+    flags: u8,
+    // The peers we've told this one about, and when we last did.
+    told: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+/// The ut_pex extension for one torrent.
+pub struct UtPex {
+    peers: HashMap<usize, PexPeer>,
+    queue: ConnectQueue,
+}
+
+impl UtPex {
+    /// Sets up PEX for a torrent, or returns None if it is private.
+    pub fn new(info: &Info, queue: ConnectQueue) -> Option<UtPex> {
+        if info.is_private() {
+            None
+        } else {
+            Some(UtPex {
+                peers: HashMap::new(),
+                queue,
+            })
+        }
+    }
+
+    /// Marks a connected peer as a seed, for the flags we send.
+    pub fn set_seed(&mut self, peer: usize) {
+        if let Some(pex_peer) = self.peers.get_mut(&peer) {
+            pex_peer.flags |= FLAG_SEED;
+        }
+    }
+
+    fn message_for(&self, peer: usize) -> PexMessage {
+        let told = &self.peers[&peer].told;
+        let current: HashMap<SocketAddr, u8> = self.peers
+            .iter()
+            .filter(|&(&id, pex_peer)| id != peer && pex_peer.listening)
+            .map(|(_, pex_peer)| (pex_peer.addr, pex_peer.flags))
+            .collect();
+        let mut added: Vec<(SocketAddr, u8)> = current
+            .iter()
+            .filter(|&(addr, _)| !told.contains(addr))
+            .map(|(&addr, &flags)| (addr, flags))
+            .collect();
+        added.sort();
+        added.truncate(MAX_ADDED);
+        let mut dropped: Vec<SocketAddr> = told
+            .iter()
+            .filter(|addr| !current.contains_key(addr))
+            .cloned()
+            .collect();
+        dropped.sort();
+        PexMessage { added, dropped }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn peer_handshake(
+        &mut self,
+        peer: usize,
+        handshake: &ExtendedHandshake,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        if let Some(pex_peer) = self.peers.get_mut(&peer) {
+            match handshake.p {
+                Some(port) if port != 0 && !pex_peer.listening => {
+                    pex_peer.addr.set_port(port);
+                    pex_peer.listening = true;
+                }
+                _ => {}
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn handle(&mut self, _peer: usize, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let msg = PexMessage::decode(payload)?;
+        for (addr, _) in msg.added {
+            self.queue.push(addr);
+        }
+        Ok(Vec::new())
+    }
+
+    fn peer_connected(&mut self, peer: usize, addr: SocketAddr, outgoing: bool) {
+        self.peers.insert(
+            peer,
+            PexPeer {
+                addr,
+                // The port of a connection it made is no use to anyone.
+                listening: outgoing,
+                // If we could connect to it, so can others.
+                flags: if outgoing { FLAG_REACHABLE } else { 0 },
+                told: HashSet::new(),
+                last_sent: None,
+            },
+        );
+    }
+
+    fn peer_gone(&mut self, peer: usize) {
+        self.peers.remove(&peer);
+    }
+
+    fn tick(&mut self, peer: usize, now: Instant) -> Vec<Vec<u8>> {
+        match self.peers.get(&peer) {
+            Some(pex_peer) => match pex_peer.last_sent {
+                Some(last_sent) if now.duration_since(last_sent) < PEX_INTERVAL => {
+                    return Vec::new()
+                }
+                _ => {}
+            },
+            None => return Vec::new(),
+        }
+        let msg = self.message_for(peer);
+        if msg.added.is_empty() && msg.dropped.is_empty() {
+            return Vec::new();
+        }
+        let pex_peer = self.peers.get_mut(&peer).unwrap();
+        pex_peer.last_sent = Some(now);
+        for &(addr, _) in &msg.added {
+            pex_peer.told.insert(addr);
+        }
+        for addr in &msg.dropped {
+            pex_peer.told.remove(addr);
+        }
+        vec![msg.encode()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use testutil::read_torrent;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn pex() -> UtPex {
+        let b = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        UtPex::new(&mi.info, ConnectQueue::new()).expect("public torrent")
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let msg = PexMessage {
+            added: vec![(addr("10.0.0.1:6881"), FLAG_SEED), (addr("[::1]:6881"), 0)],
+            dropped: vec![addr("10.0.0.2:6882")],
+        };
+        let encoded = msg.encode();
+        assert!(encoded.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1"));
+        assert_eq!(PexMessage::decode(&encoded).unwrap(), msg);
+    }
+
+    #[test]
+    fn incoming_peers_are_queued() {
+        let queue = ConnectQueue::new();
+        let mut pex = pex();
+        pex.queue = queue.clone();
+        let msg = PexMessage {
+            added: vec![(addr("10.0.0.1:6881"), 0), (addr("10.0.0.3:6881"), 0)],
+            dropped: vec![],
+        };
+        pex.handle(0, &msg.encode()).unwrap();
+        pex.handle(1, &msg.encode()).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop(), Some(addr("10.0.0.1:6881")));
+    }
+
+    #[test]
+    fn at_most_once_a_minute() {
+        let mut pex = pex();
+        let start = Instant::now();
+        pex.peer_connected(0, addr("10.0.0.1:6881"), true);
+        assert!(pex.tick(0, start).is_empty());
+
+        // Connected to us, it's only passed on once we know its port.
+        pex.peer_connected(1, addr("10.0.0.2:51234"), false);
+        assert!(pex.tick(0, start).is_empty());
+        let handshake = ExtendedHandshake {
+            p: Some(6881),
+            ..ExtendedHandshake::default()
+        };
+        pex.peer_handshake(1, &handshake).unwrap();
+        let sent = pex.tick(0, start);
+        assert_eq!(
+            PexMessage::decode(&sent[0]).unwrap().added,
+            vec![(addr("10.0.0.2:6881"), 0)]
+        );
+
+        pex.peer_gone(1);
+        assert!(pex.tick(0, start + Duration::from_secs(30)).is_empty());
+        let sent = pex.tick(0, start + PEX_INTERVAL);
+        assert_eq!(
+            PexMessage::decode(&sent[0]).unwrap().dropped,
+            vec![addr("10.0.0.2:6881")]
+        );
+    }
+
+    #[test]
+    fn disabled_for_private_torrents() {
+        let b = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
+        let mut mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        if let Info::MiInfo(ref mut info) = mi.info {
+            info.private = Some(1);
+        }
+        assert!(UtPex::new(&mi.info, ConnectQueue::new()).is_none());
+    }
+}
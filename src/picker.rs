@@ -0,0 +1,312 @@
+//! Choosing which blocks to request.
+//!
+//! Each file has a priority, and each piece takes the highest priority of
+//! the files it overlaps, so a piece shared with a wanted file is still
+//! fetched when its other file is skipped.  Pieces with a deadline, which
+//! someone is waiting to read, come first, soonest first.  Then pieces
+//! we've started, so they get finished; then higher priorities, then the
+//! rarest pieces among our peers.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Instant;
+
+use bitfield::BitField;
+use peer::{Peer, MAX_BLOCK};
+use storage::Layout;
+
+/// How much we want a file.  The values are libtorrent's, so they carry
+/// over in resume files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip = 0,
+    Low = 1,
+    #[default]
+    Normal = 4,
+    High = 7,
+}
+
+impl Priority {
+    /// Maps libtorrent's eight levels onto ours.
+    pub fn from_libtorrent(level: u8) -> Priority {
+        match level {
+            0 => Priority::Skip,
+            1..=3 => Priority::Low,
+            4 => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+}
+
+pub struct PiecePicker {
+    priorities: Vec<Priority>,
+    /// How many connected peers have each piece.
+    availability: Vec<u32>,
+    deadlines: BTreeMap<u32, Instant>,
+    // The pieces we want, for checking interest a byte at a time.
+    wanted: BitField,
+    // The wanted pieces by priority, then rarity.  None when it has to be
+    // sorted again.
+    order: Option<Vec<u32>>,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> PiecePicker {
+        PiecePicker {
+            priorities: vec![Priority::Normal; num_pieces],
+            availability: vec![0; num_pieces],
+            deadlines: BTreeMap::new(),
+            wanted: BitField::full(num_pieces),
+            order: None,
+        }
+    }
+
+    /// Works out piece priorities from file priorities, one per file in
+    /// `layout`.  Files without an entry are normal.
+    pub fn set_file_priorities(&mut self, layout: &Layout, files: &[Priority]) {
+        for (piece, priority) in self.priorities.iter_mut().enumerate() {
+            *priority = layout
+                .piece_files(piece as u32)
+                .into_iter()
+                .map(|file| files.get(file).cloned().unwrap_or_default())
+                .max()
+                .unwrap_or(Priority::Skip);
+            self.wanted.set(piece, *priority != Priority::Skip);
+        }
+        self.order = None;
+    }
+
+    pub fn priority(&self, piece: u32) -> Priority {
+        self.priorities
+            .get(piece as usize)
+            .cloned()
+            .unwrap_or(Priority::Skip)
+    }
+
+    pub fn is_wanted(&self, piece: u32) -> bool {
+        self.priority(piece) != Priority::Skip
+    }
+
+    /// Whether a peer has anything we want and don't have.
+    pub fn is_interesting(&self, have: &BitField, peer: &BitField) -> bool {
+        peer.as_bytes()
+            .iter()
+            .zip(have.as_bytes())
+            .zip(self.wanted.as_bytes())
+            .any(|((&theirs, &ours), &wanted)| theirs & !ours & wanted != 0)
+    }
+
+    // Where a piece goes in `order`: higher priorities first, then the
+    // rarest.
+    fn rank(&self, piece: u32) -> (cmp::Reverse<Priority>, u32, u32) {
+        (cmp::Reverse(self.priority(piece)), self.availability(piece), piece)
+    }
+
+    fn sorted(&self) -> Vec<u32> {
+        let mut order: Vec<u32> = (0..self.priorities.len() as u32)
+            .filter(|&piece| self.is_wanted(piece))
+            .collect();
+        order.sort_by_key(|&piece| self.rank(piece));
+        order
+    }
+
+    pub fn peer_has(&mut self, piece: u32) {
+        let old = self.rank(piece);
+        match self.availability.get_mut(piece as usize) {
+            Some(count) => *count += 1,
+            None => return,
+        }
+        if !self.is_wanted(piece) {
+            return;
+        }
+        // Move the piece to its new place, rather than sorting everything
+        // again for every HAVE.
+        if let Some(mut order) = self.order.take() {
+            let rank = |p: u32| if p == piece { old } else { self.rank(p) };
+            if let Ok(from) = order.binary_search_by_key(&old, |&p| rank(p)) {
+                order.remove(from);
+                let new = self.rank(piece);
+                let to = order
+                    .binary_search_by_key(&new, |&p| self.rank(p))
+                    .unwrap_or_else(|to| to);
+                order.insert(to, piece);
+                self.order = Some(order);
+            }
+        }
+    }
+
+    /// Updates availability when a peer's bitfield changes wholesale, from
+    /// a `bitfield`, `have all` or `have none`, or when it goes away.
+    pub fn update_peer(&mut self, old: &BitField, new: &BitField) {
+        let mut changed = false;
+        for (piece, count) in self.availability.iter_mut().enumerate() {
+            match (old.get(piece), new.get(piece)) {
+                (false, true) => *count += 1,
+                (true, false) => *count = count.saturating_sub(1),
+                _ => continue,
+            }
+            changed = true;
+        }
+        if changed {
+            self.order = None;
+        }
+    }
+
+    /// Sets the pieces someone is waiting for, and when they need them by.
+    /// Of the pieces we want, these are fetched first.
+    pub fn set_deadlines(&mut self, deadlines: BTreeMap<u32, Instant>) {
+        self.deadlines = deadlines;
+    }
+
+    pub fn deadlines(&self) -> &BTreeMap<u32, Instant> {
+        &self.deadlines
+    }
+
+    pub fn availability(&self, piece: u32) -> u32 {
+        self.availability.get(piece as usize).cloned().unwrap_or(0)
+    }
+
+    /// Picks up to `max` blocks to request from `peer`, skipping blocks we
+    /// have (`partial`) or have asked anyone for (`requested`).
+    pub fn pick(
+        &mut self,
+        layout: &Layout,
+        have: &BitField,
+        partial: &BTreeMap<u32, BitField>,
+        requested: &HashSet<(u32, u32)>,
+        peer: &Peer,
+        max: usize,
+    ) -> Vec<(u32, u32, u32)> {
+        if self.order.is_none() {
+            self.order = Some(self.sorted());
+        }
+        let candidate = |piece: &u32| {
+            let piece = *piece;
+            self.is_wanted(piece) && !have.get(piece as usize)
+                && peer.bitfield.get(piece as usize) && peer.can_request(piece)
+        };
+        // Pieces with a deadline, soonest first, then those we've started,
+        // are few, so they're sorted as they come.  The rest are in order.
+        let mut urgent: Vec<u32> = self.deadlines.keys().cloned().filter(&candidate).collect();
+        urgent.sort_by_key(|piece| {
+            (self.deadlines[piece], !partial.contains_key(piece), self.rank(*piece))
+        });
+        let mut started: Vec<u32> = partial
+            .keys()
+            .cloned()
+            .filter(|piece| !self.deadlines.contains_key(piece))
+            .filter(&candidate)
+            .collect();
+        started.sort_by_key(|&piece| self.rank(piece));
+        let rest = self.order
+            .as_ref()
+            .unwrap()
+            .iter()
+            .cloned()
+            .filter(|piece| !self.deadlines.contains_key(piece) && !partial.contains_key(piece))
+            .filter(&candidate);
+        let mut blocks = Vec::new();
+        for piece in urgent.into_iter().chain(started).chain(rest) {
+            let size = layout.piece_size(piece);
+            let mut begin = 0;
+            while begin < size {
+                if blocks.len() >= max {
+                    return blocks;
+                }
+                let block = (begin / MAX_BLOCK) as usize;
+                let got = partial.get(&piece).map_or(false, |blocks| blocks.get(block));
+                if !got && !requested.contains(&(piece, begin)) {
+                    blocks.push((piece, begin, cmp::min(MAX_BLOCK, size - begin)));
+                }
+                begin += MAX_BLOCK;
+            }
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use peermsg::Handshake;
+    use testutil::read_torrent;
+
+    #[test]
+    fn priorities_and_rarest_first() {
+        let b = read_torrent("data/These Systems Are Failing.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let layout = Layout::new(&mi.info).unwrap();
+        let num_pieces = layout.num_pieces();
+        let mut picker = PiecePicker::new(num_pieces);
+
+        // Skip the html file and the first video.  Piece 0 is all theirs,
+        // but piece 122 is shared with the second video, so it's still
+        // wanted.
+        let mut files = vec![Priority::Normal; 7];
+        files[0] = Priority::Skip;
+        files[1] = Priority::Skip;
+        picker.set_file_priorities(&layout, &files);
+        assert!(!picker.is_wanted(0) && !picker.is_wanted(121));
+        assert!(picker.is_wanted(122) && picker.is_wanted(947));
+        files[6] = Priority::High;
+        picker.set_file_priorities(&layout, &files);
+        assert_eq!(picker.priority(947), Priority::High);
+
+        let handshake = Handshake {
+            reserved: [0; 8],
+            info_hash: vec![0; 20],
+            peer_id: vec![1; 20],
+        };
+        let mut peer = Peer::new("10.0.0.1:6881".parse().unwrap(), &handshake, num_pieces);
+        peer.peer_choking = false;
+        picker.update_peer(&BitField::new(num_pieces), &BitField::full(num_pieces));
+        peer.bitfield = BitField::full(num_pieces);
+        let have = BitField::new(num_pieces);
+        assert!(picker.is_interesting(&have, &peer.bitfield));
+
+        // High priority first; among those, the rarest.  Piece 779 is
+        // shared with a normal file, but takes the higher priority.
+        let none = HashSet::new();
+        let picked = picker.pick(&layout, &have, &BTreeMap::new(), &none, &peer, 1);
+        assert_eq!(picked, vec![(779, 0, MAX_BLOCK)]);
+        picker.peer_has(779);
+        let picked = picker.pick(&layout, &have, &BTreeMap::new(), &none, &peer, 1);
+        assert_eq!(picked, vec![(780, 0, MAX_BLOCK)]);
+        // HAVEs move pieces in the order without sorting it all again.
+        for &piece in &[780, 779, 3, 947, 780, 500, 122] {
+            picker.peer_has(piece);
+        }
+        assert_eq!(picker.order, Some(picker.sorted()));
+
+        // A piece we've started beats everything, and we skip the blocks
+        // we have or have asked for.
+        let mut blocks = BitField::new(64);
+        blocks.set(0, true);
+        let mut partial = BTreeMap::new();
+        partial.insert(500, blocks);
+        let mut requested = HashSet::new();
+        requested.insert((500, MAX_BLOCK));
+        let picked = picker.pick(&layout, &have, &partial, &requested, &peer, 2);
+        assert_eq!(
+            picked,
+            vec![(500, 2 * MAX_BLOCK, MAX_BLOCK), (500, 3 * MAX_BLOCK, MAX_BLOCK)]
+        );
+
+        // A piece someone is waiting to read beats everything, unless it's
+        // skipped.
+        let mut deadlines = BTreeMap::new();
+        let now = Instant::now();
+        deadlines.insert(700, now + ::std::time::Duration::from_secs(1));
+        deadlines.insert(300, now);
+        deadlines.insert(3, now);
+        picker.set_deadlines(deadlines);
+        let picked = picker.pick(&layout, &have, &partial, &requested, &peer, 2);
+        assert_eq!(picked, vec![(300, 0, MAX_BLOCK), (300, MAX_BLOCK, MAX_BLOCK)]);
+
+        // Nothing to want from a peer with only skipped pieces.
+        let mut bitfield = BitField::new(num_pieces);
+        bitfield.set(0, true);
+        assert!(!picker.is_interesting(&have, &bitfield));
+    }
+}
@@ -0,0 +1,189 @@
+//! Bandwidth limits.
+//!
+//! Each limit is a token bucket: tokens accrue at the limit's rate, up to a
+//! second's worth, and every payload byte sent or received spends one.
+//! Limiters are cheap handles onto shared state, so a limit can be changed
+//! from anywhere while the event loop runs.  Protocol overhead isn't
+//! throttled, so keepalives and `have`s always get through, but it's
+//! counted separately.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Bytes transferred through a limiter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transferred {
+    /// Piece data.
+    pub payload: u64,
+    /// Everything else: handshakes, message headers, `have`, keepalives.
+    pub overhead: u64,
+}
+
+struct Bucket {
+    // Bytes per second, or None for no limit.
+    limit: Option<u64>,
+    tokens: f64,
+    last: Option<Instant>,
+    transferred: Transferred,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let (Some(limit), Some(last)) = (self.limit, self.last) {
+            if now > last {
+                let elapsed = now.duration_since(last);
+                let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                self.tokens = (self.tokens + secs * limit as f64).min(limit as f64);
+            }
+        }
+        self.last = Some(now);
+    }
+
+    fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        match self.limit {
+            Some(_) => self.tokens as usize,
+            None => usize::MAX,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// A limiter allowing `limit` bytes per second, or anything with None.
+    pub fn new(limit: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            inner: Arc::new(Mutex::new(Bucket {
+                limit,
+                // Start full, so the first second isn't wasted.
+                tokens: limit.unwrap_or(0) as f64,
+                last: None,
+                transferred: Transferred::default(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(None)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.inner.lock().unwrap().limit
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        let mut bucket = self.inner.lock().unwrap();
+        if let Some(limit) = limit {
+            // Coming from no limit, start with a full bucket, as new
+            // limiters do.
+            let tokens = if bucket.limit.is_some() { bucket.tokens } else { limit as f64 };
+            bucket.tokens = tokens.min(limit as f64);
+        }
+        bucket.limit = limit;
+    }
+
+    /// How many payload bytes may go through right now.
+    pub fn available(&self, now: Instant) -> usize {
+        self.inner.lock().unwrap().available(now)
+    }
+
+    /// Spends tokens for payload that went through.
+    pub fn consume(&self, bytes: usize) {
+        let mut bucket = self.inner.lock().unwrap();
+        bucket.transferred.payload += bytes as u64;
+        if bucket.limit.is_some() {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// Counts protocol overhead, which isn't limited.
+    pub fn record_overhead(&self, bytes: usize) {
+        self.inner.lock().unwrap().transferred.overhead += bytes as u64;
+    }
+
+    pub fn transferred(&self) -> Transferred {
+        self.inner.lock().unwrap().transferred
+    }
+}
+
+/// How many bytes all of `limiters` allow right now, up to `wanted`.
+pub fn allowance(limiters: &[&RateLimiter], wanted: usize, now: Instant) -> usize {
+    limiters
+        .iter()
+        .fold(wanted, |allowed, limiter| cmp::min(allowed, limiter.available(now)))
+}
+
+/// Records bytes that went through each of `limiters`.
+pub fn record(limiters: &[&RateLimiter], payload: usize, overhead: usize) {
+    for limiter in limiters {
+        limiter.consume(payload);
+        limiter.record_overhead(overhead);
+    }
+}
+
+/// An upload and a download limit, for the session or one torrent.
+#[derive(Clone)]
+pub struct TransferLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl TransferLimits {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> TransferLimits {
+        TransferLimits {
+            upload: RateLimiter::new(upload),
+            download: RateLimiter::new(download),
+        }
+    }
+
+    pub fn unlimited() -> TransferLimits {
+        TransferLimits::new(None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(Some(1000));
+        assert_eq!(limiter.available(start), 1000);
+        limiter.consume(1000);
+        limiter.record_overhead(68);
+        assert_eq!(limiter.available(start), 0);
+        assert_eq!(limiter.available(start + Duration::from_millis(500)), 500);
+        // Tokens never pile up past a second's worth.
+        assert_eq!(limiter.available(start + Duration::from_secs(5)), 1000);
+        assert_eq!(
+            limiter.transferred(),
+            Transferred {
+                payload: 1000,
+                overhead: 68,
+            }
+        );
+    }
+
+    #[test]
+    fn global_and_torrent_limits_combine() {
+        let now = Instant::now();
+        let global = RateLimiter::new(Some(500));
+        let torrent = RateLimiter::unlimited();
+        assert_eq!(allowance(&[&global, &torrent], 800, now), 500);
+        record(&[&global, &torrent], 500, 0);
+        assert_eq!(allowance(&[&global, &torrent], 800, now), 0);
+
+        // Changing a limit at runtime takes effect straight away.
+        global.set_limit(None);
+        torrent.set_limit(Some(100));
+        assert_eq!(allowance(&[&global, &torrent], 800, now), 100);
+        assert_eq!(torrent.transferred().payload, 500);
+    }
+}
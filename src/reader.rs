@@ -0,0 +1,278 @@
+//! Reading a torrent's files while they download, as for playing media.
+//!
+//! A `TorrentFileReader` runs on its own thread, apart from the session.
+//! When it needs a piece it doesn't have, it sets deadlines on that piece
+//! and the ones after it, which the piece picker fetches first, and waits
+//! for the session to say the piece is in and checked.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use bitfield::BitField;
+use storage::{Storage, StorageFile};
+
+/// How far past the read position to ask for pieces.
+pub const DEFAULT_READ_AHEAD: u64 = 4 * 1024 * 1024;
+
+// Each piece further ahead is due this much later.
+const DEADLINE_STEP: Duration = Duration::from_millis(100);
+
+struct Shared {
+    have: BitField,
+    // Each reader's deadlines, by reader.
+    deadlines: HashMap<usize, Vec<(u32, Instant)>>,
+    next_reader: usize,
+    closed: bool,
+}
+
+/// The torrent's side of its readers: which pieces are ready, and which
+/// the readers want next.
+#[derive(Clone)]
+pub struct PieceWaiters {
+    inner: Arc<(Mutex<Shared>, Condvar)>,
+}
+
+impl PieceWaiters {
+    pub fn new(have: &BitField) -> PieceWaiters {
+        PieceWaiters {
+            inner: Arc::new((
+                Mutex::new(Shared {
+                    have: have.clone(),
+                    deadlines: HashMap::new(),
+                    next_reader: 0,
+                    closed: false,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Wakes readers waiting for a piece that has now been checked.
+    pub fn piece_done(&self, piece: u32) {
+        let (ref lock, ref cvar) = *self.inner;
+        lock.lock().unwrap().have.set(piece as usize, true);
+        cvar.notify_all();
+    }
+
+    /// The pieces readers are waiting for, with the earliest deadline for
+    /// each.
+    pub fn deadlines(&self) -> BTreeMap<u32, Instant> {
+        let shared = self.inner.0.lock().unwrap();
+        let mut deadlines = BTreeMap::new();
+        for &(piece, deadline) in shared.deadlines.values().flat_map(|d| d.iter()) {
+            if !shared.have.get(piece as usize) {
+                let earliest = deadlines.entry(piece).or_insert(deadline);
+                *earliest = cmp::min(*earliest, deadline);
+            }
+        }
+        deadlines
+    }
+
+    /// Fails every read from now on, as when the torrent goes away.
+    pub fn close(&self) {
+        let (ref lock, ref cvar) = *self.inner;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_all();
+    }
+}
+
+/// Reads one file of a torrent, blocking until the pieces it needs have
+/// been downloaded and checked.
+pub struct TorrentFileReader {
+    waiters: PieceWaiters,
+    id: usize,
+    storage: Arc<Storage>,
+    file: StorageFile,
+    pos: u64,
+    read_ahead: u64,
+    timeout: Option<Duration>,
+}
+
+impl TorrentFileReader {
+    /// A reader for `storage`'s file number `file`.
+    pub fn new(
+        waiters: &PieceWaiters,
+        storage: Arc<Storage>,
+        file: usize,
+    ) -> io::Result<TorrentFileReader> {
+        let file = storage
+            .layout()
+            .files()
+            .get(file)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))?;
+        let id = {
+            let mut shared = waiters.inner.0.lock().unwrap();
+            shared.next_reader += 1;
+            shared.next_reader
+        };
+        Ok(TorrentFileReader {
+            waiters: waiters.clone(),
+            id,
+            storage,
+            file,
+            pos: 0,
+            read_ahead: DEFAULT_READ_AHEAD,
+            timeout: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.length == 0
+    }
+
+    pub fn set_read_ahead(&mut self, bytes: u64) {
+        self.read_ahead = bytes;
+    }
+
+    /// How long a read may wait for a piece; None waits for ever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // The piece holding byte `pos` of the file, and where in it.
+    fn locate(&self, pos: u64) -> (u32, u32) {
+        let offset = self.file.offset + pos;
+        let piece_length = self.storage.layout().piece_length();
+        ((offset / piece_length) as u32, (offset % piece_length) as u32)
+    }
+
+    /// Asks for the pieces from the read position to the end of the
+    /// read-ahead window, soonest first.
+    fn set_deadlines(&self, now: Instant) {
+        let end = cmp::min(self.pos + cmp::max(self.read_ahead, 1), self.file.length);
+        let (first, _) = self.locate(self.pos);
+        let (last, _) = self.locate(end - 1);
+        let deadlines = (first..last + 1)
+            .map(|piece| (piece, now + DEADLINE_STEP * (piece - first)))
+            .collect();
+        let mut shared = self.waiters.inner.0.lock().unwrap();
+        shared.deadlines.insert(self.id, deadlines);
+    }
+
+    fn wait_for(&self, piece: u32) -> io::Result<()> {
+        let (ref lock, ref cvar) = *self.waiters.inner;
+        let started = Instant::now();
+        let mut shared = lock.lock().unwrap();
+        loop {
+            if shared.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "torrent was removed"));
+            }
+            if shared.have.get(piece as usize) {
+                return Ok(());
+            }
+            shared = match self.timeout {
+                Some(timeout) => {
+                    let waited = started.elapsed();
+                    if waited >= timeout {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "piece not ready"));
+                    }
+                    cvar.wait_timeout(shared, timeout - waited).unwrap().0
+                }
+                None => cvar.wait(shared).unwrap(),
+            };
+        }
+    }
+}
+
+impl Read for TorrentFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.file.length || buf.is_empty() {
+            return Ok(0);
+        }
+        self.set_deadlines(Instant::now());
+        let (piece, begin) = self.locate(self.pos);
+        self.wait_for(piece)?;
+        let in_piece = (self.storage.layout().piece_length() - begin as u64) as usize;
+        let in_file = (self.file.length - self.pos) as usize;
+        let len = cmp::min(buf.len(), cmp::min(in_piece, in_file));
+        let data = self.storage.read_block(piece, begin, len as u32)?;
+        buf[..len].copy_from_slice(&data);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for TorrentFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(delta) => self.file.length as i64 + delta,
+            SeekFrom::Current(delta) => self.pos as i64 + delta,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for TorrentFileReader {
+    fn drop(&mut self) {
+        self.waiters.inner.0.lock().unwrap().deadlines.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use std::thread;
+    use storage::FileStorage;
+    use testutil::{read_torrent, TempDir};
+
+    #[test]
+    fn read_blocks_until_pieces_are_in() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("reader");
+        let storage: Arc<Storage> = Arc::new(FileStorage::new(&mi.info, &dir).unwrap());
+        let waiters = PieceWaiters::new(&BitField::new(3));
+
+        let mut reader = TorrentFileReader::new(&waiters, storage.clone(), 0).unwrap();
+        assert_eq!(reader.len(), 40000);
+        reader.seek(SeekFrom::Start(32000)).unwrap();
+        let reading = thread::spawn(move || {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).map(|_| data)
+        });
+
+        // The reader wants the piece it's in, then the one after, and
+        // nothing past the end of its file.
+        let deadlines = loop {
+            let deadlines = waiters.deadlines();
+            if !deadlines.is_empty() {
+                break deadlines;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(deadlines.keys().cloned().collect::<Vec<_>>(), vec![0, 1]);
+        assert!(deadlines[&0] < deadlines[&1]);
+
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        storage.write_block(0, 0, &a[..32768]).unwrap();
+        storage.write_block(1, 0, &a[32768..]).unwrap();
+        waiters.piece_done(0);
+        waiters.piece_done(1);
+        let data = reading.join().unwrap().unwrap();
+        assert_eq!(data, &a[32000..]);
+        // Finished readers let go of their deadlines.
+        assert!(waiters.deadlines().is_empty());
+
+        let mut reader = TorrentFileReader::new(&waiters, storage, 2).unwrap();
+        reader.set_timeout(Some(Duration::from_millis(10)));
+        let err = reader.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        waiters.close();
+        reader.set_timeout(None);
+        assert_eq!(reader.read(&mut [0; 10]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,259 @@
+//! Fast resume: what we knew about a torrent when we last stopped, so a
+//! restart doesn't have to hash every piece again.
+//!
+//! The resume file is a bencoded dict.  Alongside the pieces we have, it
+//! records the size and modification time of every file, and on startup
+//! these are compared against the disk.  If anything has changed behind
+//! our back, the resume data is thrown away and the torrent rechecked.
+//!
+//! Where the files are kept is recorded too, when they've been moved or
+//! renamed since the torrent was added.  The torrent's info dict is never
+//! changed, so its info hash stays the same.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde_bencode::de::from_bytes;
+use serde_bencode::ser::to_bytes;
+use serde_bytes::ByteBuf;
+
+use bitfield::BitField;
+use compact;
+use metainfo::Sha1Hash;
+use peer::MAX_BLOCK;
+use picker::Priority;
+use storage::Storage;
+
+/// A file's size and modification time, in seconds since the epoch.  Files
+/// that don't exist are all zeros.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    // Sorted, as bencode wants.
+    pub mtime: u64,
+    pub size: u64,
+}
+
+// Keys in sorted order, as bencode wants.
+#[derive(Serialize, Deserialize)]
+struct RawPartial {
+    blocks: ByteBuf,
+    #[serde(rename = "num blocks")]
+    num_blocks: u64,
+    piece: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawResume {
+    downloaded: u64,
+    #[serde(rename = "file priority", default)]
+    file_priority: Vec<u8>,
+    files: Vec<FileState>,
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    // File indexes, and their paths.
+    #[serde(rename = "mapped files", default)]
+    mapped_files: Vec<(u64, String)>,
+    #[serde(rename = "num pieces")]
+    num_pieces: u64,
+    #[serde(default)]
+    partial: Vec<RawPartial>,
+    #[serde(default)]
+    peers: ByteBuf,
+    #[serde(default)]
+    peers6: ByteBuf,
+    pieces: ByteBuf,
+    #[serde(rename = "save path", default, skip_serializing_if = "Option::is_none")]
+    save_path: Option<String>,
+    uploaded: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: Sha1Hash,
+    /// Pieces we have, and have checked.
+    pub pieces: BitField,
+    /// Blocks we have of pieces we don't have all of yet.
+    pub partial: BTreeMap<u32, BitField>,
+    /// One for each file in the torrent's storage, padding included.
+    pub files: Vec<FileState>,
+    pub file_priority: Vec<Priority>,
+    /// The download directory, if the torrent has one.
+    pub save_path: Option<PathBuf>,
+    /// Files kept somewhere other than their path in the torrent, by index,
+    /// relative to the download directory.
+    pub renamed: BTreeMap<usize, PathBuf>,
+    /// Payload totals over the torrent's whole life.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for addr in &self.peers {
+            match *addr {
+                SocketAddr::V4(_) => compact::push_addr(&mut peers, addr),
+                SocketAddr::V6(_) => compact::push_addr(&mut peers6, addr),
+            }
+        }
+        let raw = RawResume {
+            downloaded: self.downloaded,
+            file_priority: self.file_priority
+                .iter()
+                .map(|&priority| priority as u8)
+                .collect(),
+            files: self.files.clone(),
+            info_hash: ByteBuf::from(self.info_hash.as_bytes().to_vec()),
+            mapped_files: self.renamed
+                .iter()
+                .map(|(&file, path)| (file as u64, path.to_string_lossy().into_owned()))
+                .collect(),
+            num_pieces: self.pieces.len() as u64,
+            partial: self.partial
+                .iter()
+                .map(|(&piece, blocks)| RawPartial {
+                    blocks: ByteBuf::from(blocks.as_bytes().to_vec()),
+                    num_blocks: blocks.len() as u64,
+                    piece,
+                })
+                .collect(),
+            peers: ByteBuf::from(peers),
+            peers6: ByteBuf::from(peers6),
+            pieces: ByteBuf::from(self.pieces.as_bytes().to_vec()),
+            save_path: self.save_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            uploaded: self.uploaded,
+        };
+        to_bytes(&raw).expect("resume data always serializes")
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<ResumeData> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let raw: RawResume = from_bytes(bytes).map_err(|_| invalid("bad resume data"))?;
+        let mut partial = BTreeMap::new();
+        for part in raw.partial {
+            let blocks = BitField::from_bytes(&part.blocks, part.num_blocks as usize)
+                .ok_or_else(|| invalid("bad block map in resume data"))?;
+            partial.insert(part.piece, blocks);
+        }
+        let mut peers = compact::read_v4_list(&raw.peers);
+        peers.extend(compact::read_v6_list(&raw.peers6));
+        Ok(ResumeData {
+            info_hash: Sha1Hash::new(raw.info_hash.to_vec())
+                .ok_or_else(|| invalid("info hash is not 20 bytes"))?,
+            pieces: BitField::from_bytes(&raw.pieces, raw.num_pieces as usize)
+                .ok_or_else(|| invalid("bad piece map in resume data"))?,
+            partial,
+            files: raw.files,
+            file_priority: raw.file_priority
+                .into_iter()
+                .map(Priority::from_libtorrent)
+                .collect(),
+            save_path: raw.save_path.map(PathBuf::from),
+            renamed: raw.mapped_files
+                .into_iter()
+                .map(|(file, path)| (file as usize, PathBuf::from(path)))
+                .collect(),
+            uploaded: raw.uploaded,
+            downloaded: raw.downloaded,
+            peers,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ResumeData> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        ResumeData::decode(&bytes)
+    }
+
+    /// Writes the data to a temporary file first, so a crash mid-write
+    /// leaves the old data intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)?.write_all(&self.encode())?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Whether the data can be trusted for this torrent: it has to be for
+    /// the same torrent, its block maps have to fit the torrent's pieces,
+    /// and no file may have changed since it was saved.
+    pub fn is_valid(&self, info_hash: &Sha1Hash, storage: &Storage) -> io::Result<bool> {
+        let layout = storage.layout();
+        let partial_fits = self.partial.iter().all(|(&piece, blocks)| {
+            (piece as usize) < layout.num_pieces()
+                && blocks.len() as u64
+                    == (layout.piece_size(piece) as u64 + MAX_BLOCK as u64 - 1) / MAX_BLOCK as u64
+        });
+        Ok(self.info_hash == *info_hash && self.pieces.len() == layout.num_pieces()
+            && partial_fits && self.files == storage.file_states()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use storage::FileStorage;
+    use testutil::{read_torrent, TempDir};
+
+    #[test]
+    fn roundtrip_and_validate() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("resume");
+        let storage = FileStorage::new(&mi.info, &dir).unwrap();
+        storage.write_block(2, 0, &[b'x'; 100]).unwrap();
+
+        let mut pieces = BitField::new(3);
+        pieces.set(2, true);
+        let mut blocks = BitField::new(2);
+        blocks.set(1, true);
+        let mut partial = BTreeMap::new();
+        partial.insert(0, blocks);
+        let resume = ResumeData {
+            info_hash: Sha1Hash::new(vec![1; 20]).unwrap(),
+            pieces,
+            partial,
+            files: storage.file_states().unwrap(),
+            file_priority: vec![Priority::High, Priority::Skip, Priority::Normal],
+            save_path: Some(dir.to_path_buf()),
+            renamed: vec![(2, PathBuf::from("hybrid-test/b-renamed.bin"))]
+                .into_iter()
+                .collect(),
+            uploaded: 1000,
+            downloaded: 16484,
+            peers: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:51413".parse().unwrap(),
+            ],
+        };
+        assert_eq!(resume.files[0], FileState::default());
+        assert_eq!(resume.files[2].size, 100);
+        let decoded = ResumeData::decode(&resume.encode()).unwrap();
+        assert_eq!(decoded, resume);
+        assert!(decoded.is_valid(&resume.info_hash, &storage).unwrap());
+        assert!(!decoded
+            .is_valid(&Sha1Hash::new(vec![2; 20]).unwrap(), &storage)
+            .unwrap());
+
+        // Block maps that don't fit the pieces would trip up the download.
+        let mut bad = decoded.clone();
+        bad.partial.insert(3, BitField::new(1));
+        assert!(!bad.is_valid(&resume.info_hash, &storage).unwrap());
+        let mut bad = decoded.clone();
+        bad.partial.insert(1, BitField::new(5));
+        assert!(!bad.is_valid(&resume.info_hash, &storage).unwrap());
+
+        // Touching a file behind our back invalidates the lot.
+        storage.write_block(0, 0, b"new").unwrap();
+        let valid = decoded.is_valid(&resume.info_hash, &storage).unwrap();
+        assert!(!valid);
+    }
+}
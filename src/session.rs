@@ -0,0 +1,1874 @@
+//! A session: every torrent we're running, sharing one listening socket,
+//! one mio `Poll`, the DHT and the global limits.
+//!
+//! Incoming connections are matched to a torrent by the info hash in their
+//! handshake.  Connections count against a global limit and a per-torrent
+//! one; past either, new connections are turned away.  Peers heard of from
+//! PEX and the DHT wait in each torrent's queue until there's room for them.
+//!
+//! Disk work goes to a `DiskPool`, and its results come back through the
+//! same `Poll` as the sockets.
+//!
+//! Each torrent has its own extensions (BEP 10).  Peers that support the
+//! extension protocol get our extended handshake once they're attached, and
+//! their extended messages go to the torrent's extensions.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+use rand::{self, Rng};
+
+use bitfield::BitField;
+use choker::{Choker, DEFAULT_UPLOAD_SLOTS};
+use connect::ConnectQueue;
+use dht::{Dht, DhtConfig, NodeId};
+use disk::{DiskJob, DiskPool, DiskResult, DEFAULT_DISK_QUEUE, DEFAULT_DISK_THREADS};
+use extension::ExtensionRegistry;
+use fastresume::FastResume;
+use metadata::UtMetadata;
+use metainfo::{OwnedMetaInfo, Sha1Hash};
+use peer::{Peer, MAX_BLOCK};
+use pex::UtPex;
+use peermsg::{self, Handshake, Message};
+use picker::{PiecePicker, Priority};
+use ratelimit::{self, RateLimiter, TransferLimits};
+use reader::{PieceWaiters, TorrentFileReader};
+use resume::ResumeData;
+use storage::{Block, CacheConfig, CacheStats, CachedStorage, FileStorage, Layout, Storage};
+use verify::PieceVerifier;
+
+// Setup some tokens to allow us to identify which event is
+// for which socket.
+const LISTENER: Token = Token(0);
+const DHT: Token = Token(1);
+const DISK: Token = Token(2);
+
+// How often timers run when nothing else is happening.
+const TICK: Duration = Duration::from_secs(1);
+
+// How soon to retry connections held back by rate limits.
+const THROTTLE_RETRY: Duration = Duration::from_millis(50);
+
+// How often each torrent asks the DHT for peers, and announces itself.
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Requests answered per connection each time round the loop, so one greedy
+// peer can't starve the rest.
+const BLOCKS_PER_TURN: usize = 4;
+
+// Requests we keep outstanding with each peer.
+const REQUESTS_PER_PEER: usize = 8;
+
+// Pieces a torrent has out with the disk at once while it's rechecked, so
+// a big recheck doesn't hold up every other torrent's disk work.
+const CHECKS_IN_FLIGHT: usize = 4;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+pub const DEFAULT_MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+
+pub struct SessionConfig {
+    pub listen: SocketAddr,
+    /// Connections across all torrents.
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// Limits for the whole session.  Each torrent has its own as well.
+    pub limits: TransferLimits,
+    /// Runs a DHT node on the listen port, if set.
+    pub dht: Option<DhtConfig>,
+    /// Puts a block cache in front of each torrent's files, if set.
+    pub cache: Option<CacheConfig>,
+    pub disk_threads: usize,
+    /// Disk jobs to have outstanding before we stop reading from peers.
+    pub disk_queue: usize,
+    /// Creates each wanted file at full size when its torrent is added.
+    pub preallocate: bool,
+}
+
+impl SessionConfig {
+    pub fn new(listen: SocketAddr) -> SessionConfig {
+        SessionConfig {
+            listen,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_torrent: DEFAULT_MAX_CONNECTIONS_PER_TORRENT,
+            limits: TransferLimits::unlimited(),
+            dht: None,
+            cache: Some(CacheConfig::default()),
+            disk_threads: DEFAULT_DISK_THREADS,
+            disk_queue: DEFAULT_DISK_QUEUE,
+            preallocate: false,
+        }
+    }
+}
+
+/// One torrent in a session.
+pub struct Torrent {
+    pub metainfo: OwnedMetaInfo,
+    pub info_hash: Sha1Hash,
+    /// The pieces we have.
+    pub have: BitField,
+    pub storage: Arc<Storage>,
+    // The same storage, when it's the session's cache.
+    cache: Option<Arc<CachedStorage>>,
+    pub limits: TransferLimits,
+    /// Blocks we have of pieces we don't have yet.
+    pub partial: BTreeMap<u32, BitField>,
+    file_priorities: Vec<Priority>,
+    picker: PiecePicker,
+    waiters: PieceWaiters,
+    /// Connected peers, and their connections' tokens, in the same order.
+    pub peers: Vec<Peer>,
+    tokens: Vec<Token>,
+    choker: Choker,
+    // Whether blocks have been freed up that any peer might take, so every
+    // peer's requests want topping up, not just those whose blocks came in.
+    repick: bool,
+    // Our extensions (BEP 10).  Each peer is known to them by the number
+    // of its connection's token.
+    extensions: ExtensionRegistry,
+    /// Peers heard of from PEX and the DHT, waiting for a connection.
+    pub queue: ConnectQueue,
+    dht_lookup: Option<Instant>,
+    verifier: Arc<PieceVerifier>,
+    // While the files are rechecked: the pieces still to go to the disk,
+    // and those gone that haven't come back.
+    unchecked: VecDeque<u32>,
+    checking: HashSet<u32>,
+    // While the files are moving, the disk jobs kept back until they're
+    // done, so no disk thread sits waiting for the move.
+    held: Option<Vec<DiskJob>>,
+    // Blocks sent to the disk and not yet written, by piece.
+    writing: HashMap<u32, usize>,
+    disk_error: Option<io::Error>,
+    // Payload totals from before this session, out of the resume data.
+    uploaded_before: u64,
+    downloaded_before: u64,
+}
+
+impl Torrent {
+    fn peer_index(&self, token: Token) -> Option<usize> {
+        self.tokens.iter().position(|&t| t == token)
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.have.all()
+    }
+
+    /// Whether the files are still being rechecked.  Nothing is downloaded
+    /// until they're done.
+    pub fn is_checking(&self) -> bool {
+        !self.unchecked.is_empty() || !self.checking.is_empty()
+    }
+
+    /// Whether the files are being moved.  Peers are left waiting until
+    /// they're done.
+    pub fn is_moving(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// Payload uploaded over the torrent's whole life.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded_before + self.limits.upload.transferred().payload
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded_before + self.limits.download.transferred().payload
+    }
+
+    /// The last thing that went wrong on disk, if anything has.
+    pub fn disk_error(&self) -> Option<&io::Error> {
+        self.disk_error.as_ref()
+    }
+
+    /// What to save so the torrent can pick up where it left off.  Pieces
+    /// with blocks still on their way to disk are left out.
+    pub fn resume_data(&self) -> io::Result<ResumeData> {
+        self.storage.flush()?;
+        let layout = self.storage.layout();
+        let renamed = (0..layout.files().len())
+            .filter_map(|file| {
+                let path = self.storage.file_path(file)?;
+                if path == layout.files()[file].path {
+                    None
+                } else {
+                    Some((file, path))
+                }
+            })
+            .collect();
+        Ok(ResumeData {
+            info_hash: self.info_hash.clone(),
+            pieces: self.have.clone(),
+            partial: self.partial
+                .iter()
+                .filter(|&(piece, _)| !self.writing.contains_key(piece))
+                .map(|(&piece, blocks)| (piece, blocks.clone()))
+                .collect(),
+            files: self.storage.file_states()?,
+            file_priority: self.file_priorities.clone(),
+            save_path: self.storage.path(),
+            renamed,
+            uploaded: self.uploaded(),
+            downloaded: self.downloaded(),
+            peers: self.peers.iter().map(|peer| peer.addr).collect(),
+        })
+    }
+
+    /// Gives one of the torrent's files, by its index in the storage
+    /// layout's files, a new path relative to the download directory.  The
+    /// info dict is left alone, so the info hash stays the same.
+    pub fn rename_file<P: AsRef<Path>>(&self, file: usize, path: P) -> io::Result<()> {
+        self.storage.rename_file(file, path.as_ref())
+    }
+
+    /// A reader for one of the torrent's files, by its index in the storage
+    /// layout's files.  Reads block until the pieces they need are in; a
+    /// skipped file becomes wanted again.
+    pub fn reader(&mut self, file: usize) -> io::Result<TorrentFileReader> {
+        if self.file_priorities.get(file) == Some(&Priority::Skip) {
+            self.set_file_priority(file, Priority::Normal);
+        }
+        TorrentFileReader::new(&self.waiters, self.storage.clone(), file)
+    }
+
+    /// How the session's cache is doing for this torrent, if it has one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    /// Sets how much we want a file, by its index in the storage layout's
+    /// files.  Skipped files are never written, unless they share a piece
+    /// with a wanted one.
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) {
+        if file >= self.file_priorities.len() {
+            return;
+        }
+        self.file_priorities[file] = priority;
+        self.picker
+            .set_file_priorities(self.storage.layout(), &self.file_priorities);
+        self.repick = true;
+    }
+
+    /// Updates our view of a peer, and stores any block it sent.
+    fn handle_message(
+        &mut self,
+        index: usize,
+        msg: &Message,
+        disk: &mut DiskPool,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let (old, new_piece) = {
+            let bitfield = &self.peers[index].bitfield;
+            match *msg {
+                Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                    (Some(bitfield.clone()), None)
+                }
+                Message::Have(piece) if (piece as usize) < bitfield.len() => {
+                    (None, if bitfield.get(piece as usize) { None } else { Some(piece) })
+                }
+                _ => (None, None),
+            }
+        };
+        let mut replies = self.peers[index].handle(msg)?;
+        match *msg {
+            // What we'd asked this peer for is up for grabs.
+            Message::Choke | Message::RejectRequest(..) => self.repick = true,
+            _ => {}
+        }
+        if let Message::Extended(id, ref payload) = *msg {
+            let peer = &mut self.peers[index];
+            if peer.extended {
+                let token = self.tokens[index].0;
+                replies.extend(self.extensions.handle(token, &mut peer.extensions, id, payload)?);
+            }
+        }
+        if let Some(old) = old {
+            self.picker.update_peer(&old, &self.peers[index].bitfield);
+        }
+        if let Some(piece) = new_piece {
+            self.picker.peer_has(piece);
+        }
+        if let Message::Piece(piece, begin, ref data) = *msg {
+            self.receive_block(disk, piece, begin, data);
+        }
+        Ok(replies)
+    }
+
+    /// Tells a peer whether we're interested, and if it will have us, tops
+    /// up our requests to it.
+    fn request_blocks(&mut self, index: usize) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        if self.is_checking() {
+            return messages;
+        }
+        let interested = self.picker.is_interesting(&self.have, &self.peers[index].bitfield);
+        if interested != self.peers[index].am_interested {
+            self.peers[index].am_interested = interested;
+            messages.push(if interested {
+                peermsg::interested()
+            } else {
+                peermsg::not_interested()
+            });
+        }
+        let wanted = REQUESTS_PER_PEER.saturating_sub(self.peers[index].pending.len());
+        if !interested || wanted == 0 {
+            return messages;
+        }
+        self.picker.set_deadlines(self.waiters.deadlines());
+        let requested: HashSet<(u32, u32)> = self.peers
+            .iter()
+            .flat_map(|peer| peer.pending.iter().map(|&(piece, begin, _)| (piece, begin)))
+            .collect();
+        let blocks = self.picker.pick(
+            self.storage.layout(),
+            &self.have,
+            &self.partial,
+            &requested,
+            &self.peers[index],
+            wanted,
+        );
+        for (piece, begin, length) in blocks {
+            messages.push(self.peers[index].request(piece, begin, length));
+        }
+        messages
+    }
+
+    /// Sends a block that came in off to be written.  Blocks we have, or
+    /// don't want, are dropped.
+    fn receive_block(&mut self, disk: &mut DiskPool, piece: u32, begin: u32, data: &[u8]) {
+        if piece as usize >= self.have.len() || self.have.get(piece as usize)
+            || !self.picker.is_wanted(piece) || self.is_checking()
+        {
+            return;
+        }
+        // Only whole, aligned blocks count.
+        let size = self.storage.layout().piece_size(piece);
+        let block = (begin / MAX_BLOCK) as usize;
+        let expected = cmp::min(MAX_BLOCK, size.saturating_sub(begin));
+        if begin % MAX_BLOCK != 0 || data.len() as u32 != expected {
+            return;
+        }
+        {
+            let num_blocks = ((size + MAX_BLOCK - 1) / MAX_BLOCK) as usize;
+            let blocks = self.partial
+                .entry(piece)
+                .or_insert_with(|| BitField::new(num_blocks));
+            if blocks.get(block) {
+                return;
+            }
+            blocks.set(block, true);
+        }
+        *self.writing.entry(piece).or_insert(0) += 1;
+        let job = DiskJob::Write {
+            info_hash: self.info_hash.as_bytes().to_vec(),
+            storage: self.storage.clone(),
+            piece,
+            begin,
+            data: data.to_vec(),
+        };
+        self.submit(disk, job);
+    }
+
+    /// Hears that a block is written.  A block that couldn't be is fetched
+    /// again; once a piece is all written, it's checked.
+    fn block_written(
+        &mut self,
+        disk: &mut DiskPool,
+        piece: u32,
+        begin: u32,
+        result: io::Result<()>,
+    ) {
+        let writing = self.writing.get(&piece).cloned().unwrap_or(1) - 1;
+        if writing == 0 {
+            self.writing.remove(&piece);
+        } else {
+            self.writing.insert(piece, writing);
+        }
+        if let Err(err) = result {
+            if let Some(blocks) = self.partial.get_mut(&piece) {
+                blocks.set((begin / MAX_BLOCK) as usize, false);
+            }
+            self.repick = true;
+            self.disk_error = Some(err);
+            return;
+        }
+        let written = self.partial.get(&piece).map_or(false, |blocks| blocks.all());
+        if writing == 0 && written {
+            let job = DiskJob::Check {
+                info_hash: self.info_hash.as_bytes().to_vec(),
+                storage: self.storage.clone(),
+                verifier: self.verifier.clone(),
+                piece,
+            };
+            self.submit(disk, job);
+        }
+    }
+
+    /// Asks the DHT for peers every so often, unless the torrent is
+    /// private.
+    fn find_peers(&mut self, dht: &mut Dht, port: Option<u16>, now: Instant) {
+        let due = self.dht_lookup
+            .map_or(true, |last| now.duration_since(last) >= DHT_LOOKUP_INTERVAL);
+        if !due || self.metainfo.info.is_private() {
+            return;
+        }
+        self.dht_lookup = Some(now);
+        if let Some(id) = NodeId::from_bytes(self.info_hash.as_bytes()) {
+            dht.get_peers(id, self.queue.clone(), port, now);
+        }
+    }
+
+    /// Hands a job to the disk, or keeps it back while the files are
+    /// moving.  A move holds back everything after it.
+    fn submit(&mut self, disk: &mut DiskPool, job: DiskJob) {
+        if let Some(ref mut held) = self.held {
+            held.push(job);
+            return;
+        }
+        if let DiskJob::Move { .. } = job {
+            self.held = Some(Vec::new());
+        }
+        disk.submit(job);
+    }
+
+    /// Hears that the files are done moving, and lets the jobs kept back
+    /// go to the disk.
+    fn moved(&mut self, disk: &mut DiskPool, result: io::Result<()>) {
+        if let Err(err) = result {
+            self.disk_error = Some(err);
+        }
+        for job in self.held.take().unwrap_or_default() {
+            self.submit(disk, job);
+        }
+    }
+
+    /// Hands the disk more pieces to recheck, while there's room.
+    fn submit_checks(&mut self, disk: &mut DiskPool) {
+        while self.checking.len() < CHECKS_IN_FLIGHT && !disk.is_full() && !self.is_moving() {
+            let piece = match self.unchecked.pop_front() {
+                Some(piece) => piece,
+                None => return,
+            };
+            self.checking.insert(piece);
+            disk.submit(DiskJob::Check {
+                info_hash: self.info_hash.as_bytes().to_vec(),
+                storage: self.storage.clone(),
+                verifier: self.verifier.clone(),
+                piece,
+            });
+        }
+    }
+
+    /// Keeps a piece that checked out, or fetches it again if it didn't.
+    /// Returns the messages for peers, as indexes into `peers` with the
+    /// messages for each: a HAVE for the new piece, and whatever it does to
+    /// our interest in them.
+    fn piece_checked(
+        &mut self,
+        piece: u32,
+        result: io::Result<bool>,
+    ) -> Vec<(usize, Vec<Vec<u8>>)> {
+        let rechecked = self.checking.remove(&piece);
+        self.partial.remove(&piece);
+        let good = match result {
+            Ok(good) => good,
+            Err(err) => {
+                self.disk_error = Some(err);
+                false
+            }
+        };
+        if good {
+            self.have.set(piece as usize, true);
+            self.waiters.piece_done(piece);
+        }
+        // The last piece of a recheck lets the download start.
+        let done_checking = rechecked && !self.is_checking();
+        if !good && !done_checking {
+            self.repick = true;
+            return Vec::new();
+        }
+        (0..self.peers.len())
+            .map(|index| {
+                let mut messages = Vec::new();
+                if good {
+                    messages.push(peermsg::have(piece));
+                }
+                messages.extend(self.request_blocks(index));
+                (index, messages)
+            })
+            .collect()
+    }
+}
+
+impl Drop for Torrent {
+    fn drop(&mut self) {
+        // Nothing will ever come in for readers still waiting.
+        self.waiters.close();
+    }
+}
+
+enum State {
+    /// Waiting for the peer's handshake.  For connections we made, ours has
+    /// gone out already, with this info hash, and theirs has to match.
+    New { outgoing: Option<Vec<u8>> },
+    /// Handshakes done; attached to the torrent with this info hash.
+    Connected(Vec<u8>),
+}
+
+/// A message on its way out.  A block goes out after its message's header,
+/// straight from wherever the storage shared it from.
+enum Outgoing {
+    Message(Vec<u8>),
+    Block(Vec<u8>, Block),
+}
+
+impl Outgoing {
+    // The message's overhead, and its piece data.
+    fn parts(&self) -> (&[u8], &[u8]) {
+        match *self {
+            Outgoing::Message(ref msg) => msg.split_at(msg.len() - peermsg::payload_len(msg)),
+            Outgoing::Block(ref header, ref block) => (header, block),
+        }
+    }
+}
+
+/// A connection, with what we've read but not handled, and what we've yet
+/// to send.
+struct Connection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: State,
+    incoming: Vec<u8>,
+    outgoing: VecDeque<Outgoing>,
+    // How much of the front outgoing message has been sent.
+    sent: usize,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, addr: SocketAddr, outgoing: Option<Vec<u8>>) -> Connection {
+        Connection {
+            stream,
+            addr,
+            state: State::New { outgoing },
+            incoming: Vec::new(),
+            outgoing: VecDeque::new(),
+            sent: 0,
+        }
+    }
+
+    /// Reads what the rate limits allow.  Returns false once the peer has
+    /// closed the connection, and sets `throttled` if the limits stopped us
+    /// before the socket ran dry.
+    fn fill(
+        &mut self,
+        buf: &mut [u8],
+        limits: &[&RateLimiter],
+        throttled: &mut bool,
+    ) -> io::Result<bool> {
+        // Loop to drain the event buffer, because we are edge polling
+        loop {
+            let allowed = ratelimit::allowance(limits, buf.len(), Instant::now());
+            if allowed == 0 {
+                *throttled = true;
+                return Ok(true);
+            }
+            match self.stream.read(&mut buf[..allowed]) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.incoming.extend(&buf[..n]),
+                // Socket is no longer ready; stop reading
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn send<I: IntoIterator<Item = Vec<u8>>>(&mut self, messages: I) {
+        self.outgoing.extend(messages.into_iter().map(Outgoing::Message));
+    }
+
+    /// Sends what the rate limits allow.  Only piece data is limited; the
+    /// rest of each message is counted as overhead.
+    fn flush(&mut self, limits: &[&RateLimiter], throttled: &mut bool) -> io::Result<()> {
+        while let Some(msg) = self.outgoing.pop_front() {
+            let written = {
+                let (header, payload) = msg.parts();
+                // The header goes out whole, and the payload as the limits
+                // allow.
+                let (buf, limited) = if self.sent < header.len() {
+                    (&header[self.sent..], false)
+                } else {
+                    let rest = &payload[self.sent - header.len()..];
+                    let allowed = ratelimit::allowance(limits, rest.len(), Instant::now());
+                    (&rest[..allowed], true)
+                };
+                if buf.is_empty() {
+                    *throttled = true;
+                    Ok(None)
+                } else {
+                    self.stream.write(buf).map(|n| {
+                        let (payload_sent, overhead) = if limited { (n, 0) } else { (0, n) };
+                        ratelimit::record(limits, payload_sent, overhead);
+                        self.sent += n;
+                        Some(self.sent == header.len() + payload.len())
+                    })
+                }
+            };
+            match written {
+                Ok(Some(true)) => self.sent = 0,
+                Ok(Some(false)) => self.outgoing.push_front(msg),
+                Ok(None) => {
+                    self.outgoing.push_front(msg);
+                    return Ok(());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.outgoing.push_front(msg);
+                    return Ok(());
+                }
+                Err(err) => {
+                    self.outgoing.push_front(msg);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Picks a peer id in the usual Azureus style: client and version, then
+/// random digits.
+fn generate_peer_id() -> Vec<u8> {
+    let mut id = b"-RB0010-".to_vec();
+    let mut rng = rand::thread_rng();
+    for _ in 0..12 {
+        id.push(b'0' + rng.gen_range(0, 10));
+    }
+    id
+}
+
+pub struct Session {
+    poll: Poll,
+    listener: TcpListener,
+    dht: Option<Dht>,
+    peer_id: Vec<u8>,
+    torrents: HashMap<Vec<u8>, Torrent>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    // Connections the rate limits held back, to try again shortly, since
+    // edge polling won't tell us about them again.
+    throttled: HashSet<Token>,
+    max_connections: usize,
+    max_connections_per_torrent: usize,
+    limits: TransferLimits,
+    cache: Option<CacheConfig>,
+    disk: DiskPool,
+    preallocate: bool,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> io::Result<Session> {
+        let listener = TcpListener::bind(&config.listen)?;
+        let poll = Poll::new()?;
+        poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge())?;
+        let dht = match config.dht {
+            Some(dht_config) => {
+                let mut dht = Dht::new(dht_config)?;
+                dht.register(&poll, DHT)?;
+                dht.bootstrap(Instant::now());
+                Some(dht)
+            }
+            None => None,
+        };
+        let disk = DiskPool::new(config.disk_threads, config.disk_queue);
+        disk.register(&poll, DISK)?;
+        Ok(Session {
+            poll,
+            listener,
+            dht,
+            peer_id: generate_peer_id(),
+            torrents: HashMap::new(),
+            connections: HashMap::new(),
+            next_token: 3,
+            throttled: HashSet::new(),
+            max_connections: config.max_connections,
+            max_connections_per_torrent: config.max_connections_per_torrent,
+            limits: config.limits,
+            cache: config.cache,
+            disk,
+            preallocate: config.preallocate,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn peer_id(&self) -> &[u8] {
+        &self.peer_id
+    }
+
+    /// The session-wide limits.  Change them through the handles at any
+    /// time.
+    pub fn limits(&self) -> &TransferLimits {
+        &self.limits
+    }
+
+    pub fn dht(&mut self) -> Option<&mut Dht> {
+        self.dht.as_mut()
+    }
+
+    /// Adds a torrent, with its data under `save_path`, and returns its
+    /// info hash.
+    pub fn add_torrent<P: AsRef<Path>>(
+        &mut self,
+        torrent: &[u8],
+        save_path: P,
+    ) -> io::Result<Sha1Hash> {
+        self.resume_torrent(torrent, save_path, None)
+    }
+
+    /// Adds a torrent we've run before.  If the resume data doesn't match
+    /// what's on disk, or there is none, every piece is checked.  Data that
+    /// was moved is looked for where the resume data says it went, rather
+    /// than under `save_path`.
+    pub fn resume_torrent<P: AsRef<Path>>(
+        &mut self,
+        torrent: &[u8],
+        save_path: P,
+        resume: Option<ResumeData>,
+    ) -> io::Result<Sha1Hash> {
+        let metainfo = OwnedMetaInfo::from_bytes(torrent)?;
+        let moved = resume
+            .as_ref()
+            .filter(|resume| resume.info_hash == metainfo.info_hash())
+            .and_then(|resume| resume.save_path.clone());
+        let save_path = moved.unwrap_or_else(|| save_path.as_ref().to_path_buf());
+        let storage = FileStorage::new(&metainfo.info, save_path)?;
+        self.add_file_storage(metainfo, storage, resume)
+    }
+
+    /// Adds a torrent from another client, with its libtorrent-style
+    /// `.fastresume` file.  Its pieces are trusted without a recheck, unless
+    /// the files on disk have changed since the resume file was written, or
+    /// without file sizes in it, aren't all there at full length.
+    pub fn import_fastresume(
+        &mut self,
+        torrent: &[u8],
+        fastresume: &[u8],
+    ) -> io::Result<Sha1Hash> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let metainfo = OwnedMetaInfo::from_bytes(torrent)?;
+        let fastresume = FastResume::decode(fastresume)?;
+        if fastresume.info_hash != metainfo.info_hash() {
+            return Err(invalid("resume file is for another torrent"));
+        }
+        let save_path = fastresume
+            .save_path
+            .clone()
+            .ok_or_else(|| invalid("resume file has no save path"))?;
+        let storage = FileStorage::new(&metainfo.info, save_path)?;
+        let resume = fastresume.to_resume_data(&storage)?;
+        self.add_file_storage(metainfo, storage, Some(resume))
+    }
+
+    // Adds a torrent kept on disk, behind the session's cache if it has
+    // one.
+    fn add_file_storage(
+        &mut self,
+        metainfo: OwnedMetaInfo,
+        storage: FileStorage,
+        resume: Option<ResumeData>,
+    ) -> io::Result<Sha1Hash> {
+        let (storage, cache): (Arc<Storage>, _) = match self.cache {
+            Some(config) => {
+                let cache = Arc::new(CachedStorage::new(Box::new(storage), config));
+                (cache.clone(), Some(cache))
+            }
+            None => (Arc::new(storage), None),
+        };
+        let info_hash = self.add_torrent_with_storage(metainfo, storage, resume)?;
+        self.torrents.get_mut(info_hash.as_bytes()).unwrap().cache = cache;
+        Ok(info_hash)
+    }
+
+    /// Adds a torrent whose data lives in a backend of our choosing.  The
+    /// backend has to be laid out from this torrent's info.  Files renamed
+    /// in the resume data are renamed in the backend too.
+    pub fn add_torrent_with_storage(
+        &mut self,
+        metainfo: OwnedMetaInfo,
+        storage: Arc<Storage>,
+        resume: Option<ResumeData>,
+    ) -> io::Result<Sha1Hash> {
+        let info_hash = metainfo.info_hash();
+        if self.torrents.contains_key(info_hash.as_bytes()) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "torrent already added"));
+        }
+        if *storage.layout() != Layout::new(&metainfo.info)? {
+            let msg = "storage is for another torrent";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        // Resume data for another torrent is no use at all.
+        let resume = resume.filter(|resume| resume.info_hash == info_hash);
+        if let Some(ref resume) = resume {
+            for (&file, path) in &resume.renamed {
+                storage.rename_file(file, path)?;
+            }
+        }
+        let verifier = PieceVerifier::new(&metainfo, storage.layout())?;
+        // Resume data we can't trust means checking every piece, on the
+        // disk threads; until then we have nothing.
+        let mut recheck = false;
+        let resume = match resume {
+            Some(resume) => if resume.is_valid(&info_hash, &*storage)? {
+                resume
+            } else {
+                recheck = true;
+                ResumeData {
+                    pieces: BitField::new(storage.layout().num_pieces()),
+                    partial: BTreeMap::new(),
+                    ..resume
+                }
+            },
+            None => ResumeData {
+                info_hash: info_hash.clone(),
+                pieces: BitField::new(storage.layout().num_pieces()),
+                partial: BTreeMap::new(),
+                files: Vec::new(),
+                file_priority: Vec::new(),
+                save_path: None,
+                renamed: BTreeMap::new(),
+                uploaded: 0,
+                downloaded: 0,
+                peers: Vec::new(),
+            },
+        };
+        let num_files = storage.layout().files().len();
+        let unchecked = if recheck {
+            (0..storage.layout().num_pieces() as u32).collect()
+        } else {
+            VecDeque::new()
+        };
+        let waiters = PieceWaiters::new(&resume.pieces);
+        let mut extensions = ExtensionRegistry::new(Some(self.local_addr()?.port()));
+        let info = metainfo.info_bytes().to_vec();
+        extensions.register(Box::new(UtMetadata::serving(info_hash.clone(), info)))?;
+        let queue = ConnectQueue::new();
+        if let Some(pex) = UtPex::new(&metainfo.info, queue.clone()) {
+            extensions.register(Box::new(pex))?;
+        }
+        let mut torrent = Torrent {
+            metainfo,
+            info_hash: info_hash.clone(),
+            have: resume.pieces,
+            file_priorities: vec![Priority::Normal; num_files],
+            waiters,
+            picker: PiecePicker::new(storage.layout().num_pieces()),
+            storage,
+            cache: None,
+            limits: TransferLimits::unlimited(),
+            partial: resume.partial,
+            peers: Vec::new(),
+            tokens: Vec::new(),
+            choker: Choker::new(DEFAULT_UPLOAD_SLOTS),
+            repick: false,
+            extensions,
+            queue,
+            dht_lookup: None,
+            verifier: Arc::new(verifier),
+            unchecked,
+            checking: HashSet::new(),
+            held: None,
+            writing: HashMap::new(),
+            disk_error: None,
+            uploaded_before: resume.uploaded,
+            downloaded_before: resume.downloaded,
+        };
+        for (file, &priority) in resume.file_priority.iter().take(num_files).enumerate() {
+            torrent.set_file_priority(file, priority);
+        }
+        if self.preallocate {
+            for file in 0..num_files {
+                if torrent.file_priorities[file] != Priority::Skip {
+                    self.disk.submit(DiskJob::Allocate {
+                        info_hash: info_hash.as_bytes().to_vec(),
+                        storage: torrent.storage.clone(),
+                        file,
+                    });
+                }
+            }
+        }
+        torrent.submit_checks(&mut self.disk);
+        self.torrents.insert(info_hash.as_bytes().to_vec(), torrent);
+        Ok(info_hash)
+    }
+
+    pub fn torrent(&self, info_hash: &[u8]) -> Option<&Torrent> {
+        self.torrents.get(info_hash)
+    }
+
+    pub fn torrent_mut(&mut self, info_hash: &[u8]) -> Option<&mut Torrent> {
+        self.torrents.get_mut(info_hash)
+    }
+
+    /// Drops a torrent and all its connections.
+    pub fn remove_torrent(&mut self, info_hash: &[u8]) -> Option<Torrent> {
+        let torrent = self.torrents.remove(info_hash)?;
+        for token in &torrent.tokens {
+            self.connections.remove(token);
+        }
+        Some(torrent)
+    }
+
+    /// Connections across all torrents, including those still handshaking.
+    pub fn num_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    fn has_room(&self, info_hash: Option<&[u8]>) -> bool {
+        if self.connections.len() >= self.max_connections {
+            return false;
+        }
+        let info_hash = match info_hash {
+            Some(info_hash) => info_hash,
+            None => return true,
+        };
+        let torrent = match self.torrents.get(info_hash) {
+            Some(torrent) => torrent,
+            None => return true,
+        };
+        // Connections we've made that haven't handshaken yet count too.
+        let connecting = self.connections
+            .values()
+            .filter(|conn| match conn.state {
+                State::New { outgoing: Some(ref sent) } => &sent[..] == info_hash,
+                _ => false,
+            })
+            .count();
+        torrent.peers.len() + connecting < self.max_connections_per_torrent
+    }
+
+    fn add_connection(&mut self, conn: Connection) -> io::Result<Token> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.register(
+            &conn.stream,
+            token,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        self.connections.insert(token, conn);
+        Ok(token)
+    }
+
+    /// Moves a torrent's data to a new download directory, copying it if
+    /// that's on another filesystem.  The move happens on the disk threads,
+    /// and the torrent carries on from there; if it fails, it shows up in
+    /// the torrent's `disk_error`.
+    pub fn move_storage<P: AsRef<Path>>(&mut self, info_hash: &[u8], path: P) -> io::Result<()> {
+        let torrent = match self.torrents.get_mut(info_hash) {
+            Some(torrent) => torrent,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such torrent")),
+        };
+        let job = DiskJob::Move {
+            info_hash: info_hash.to_vec(),
+            storage: torrent.storage.clone(),
+            path: path.as_ref().to_path_buf(),
+        };
+        torrent.submit(&mut self.disk, job);
+        Ok(())
+    }
+
+    /// Connects to a peer for a torrent, if the limits allow.
+    pub fn connect(&mut self, info_hash: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if !self.torrents.contains_key(info_hash) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such torrent"));
+        }
+        if !self.has_room(Some(info_hash)) {
+            return Err(io::Error::new(io::ErrorKind::Other, "connection limit reached"));
+        }
+        let stream = TcpStream::connect(&addr)?;
+        let mut conn = Connection::new(stream, addr, Some(info_hash.to_vec()));
+        conn.send(Some(peermsg::peer_handshake(info_hash, &self.peer_id)));
+        self.add_connection(conn)?;
+        Ok(())
+    }
+
+    /// Runs the session forever.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            self.poll_once(&mut events, TICK)?;
+        }
+    }
+
+    /// Waits up to `timeout` for something to happen, and handles it.
+    pub fn poll_once(&mut self, events: &mut Events, timeout: Duration) -> io::Result<()> {
+        let timeout = if self.throttled.is_empty() {
+            timeout
+        } else {
+            cmp::min(timeout, THROTTLE_RETRY)
+        };
+        self.poll.poll(events, Some(timeout))?;
+        let mut ready: Vec<Token> = self.throttled.drain().collect();
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => self.accept()?,
+                DHT => if let Some(ref mut dht) = self.dht {
+                    dht.ready(Instant::now())?;
+                },
+                DISK => ready.extend(self.handle_disk()),
+                token => ready.push(token),
+            }
+        }
+        let mut buf = [0; 16 * 1024];
+        for token in ready {
+            if self.service(token, &mut buf).is_err() {
+                // Whatever went wrong, it was this connection's problem.
+                self.close(token);
+            }
+        }
+        self.tick(Instant::now());
+        Ok(())
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    // Over the global limit, hang up straight away.  The
+                    // per-torrent limit waits for the handshake.
+                    if self.has_room(None) {
+                        self.add_connection(Connection::new(stream, addr, None))?;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        self.throttled.remove(&token);
+        if let Some(conn) = self.connections.remove(&token) {
+            // Whoever we heard of it from may mention it again later.
+            if let State::New { outgoing: Some(ref info_hash) } = conn.state {
+                if let Some(torrent) = self.torrents.get(info_hash) {
+                    torrent.queue.forget(&conn.addr);
+                }
+            }
+            if let State::Connected(ref info_hash) = conn.state {
+                if let Some(torrent) = self.torrents.get_mut(info_hash) {
+                    torrent.queue.forget(&conn.addr);
+                    if let Some(i) = torrent.peer_index(token) {
+                        let peer = torrent.peers.swap_remove(i);
+                        torrent.tokens.swap_remove(i);
+                        torrent.repick |= !peer.pending.is_empty();
+                        torrent.extensions.peer_gone(token.0);
+                        let gone = BitField::new(peer.bitfield.len());
+                        torrent.picker.update_peer(&peer.bitfield, &gone);
+                    }
+                }
+            }
+        }
+    }
+
+    // The limits that apply to a connection: the session's, and its
+    // torrent's once we know it.
+    fn connection_limits(
+        &self,
+        token: Token,
+    ) -> (RateLimiter, RateLimiter, Option<TransferLimits>) {
+        let torrent = self.connections.get(&token).and_then(|conn| match conn.state {
+            State::Connected(ref info_hash) => {
+                self.torrents.get(info_hash).map(|torrent| torrent.limits.clone())
+            }
+            State::New { .. } => None,
+        });
+        (self.limits.upload.clone(), self.limits.download.clone(), torrent)
+    }
+
+    /// Reads from a connection, unless the disk is behind, handles what
+    /// came in, and sends what we can.
+    fn service(&mut self, token: Token, buf: &mut [u8]) -> io::Result<()> {
+        if !self.connections.contains_key(&token) {
+            return Ok(());
+        }
+        let mut held = false;
+        let (upload, download, torrent_limits) = self.connection_limits(token);
+        let moving = match self.connections[&token].state {
+            State::Connected(ref info_hash) => self.torrents[info_hash].is_moving(),
+            State::New { .. } => false,
+        };
+        let open = if self.disk.is_full() || moving {
+            // Leave it in the socket until the disk catches up, or the
+            // torrent's files are where they're going.
+            held = true;
+            true
+        } else {
+            let conn = self.connections.get_mut(&token).unwrap();
+            let mut down = vec![&download];
+            if let Some(ref limits) = torrent_limits {
+                down.push(&limits.download);
+            }
+            conn.fill(buf, &down, &mut held)?
+        };
+        if !open {
+            self.close(token);
+            return Ok(());
+        }
+        self.handle_incoming(token)?;
+
+        // The torrent may only be known now, after the handshake.
+        let (_, _, torrent_limits) = self.connection_limits(token);
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let mut up = vec![&upload];
+            if let Some(ref limits) = torrent_limits {
+                up.push(&limits.upload);
+            }
+            conn.flush(&up, &mut held)?;
+        }
+        if held {
+            self.throttled.insert(token);
+        }
+        Ok(())
+    }
+
+    fn handle_incoming(&mut self, token: Token) -> io::Result<()> {
+        let handshake = {
+            let conn = &self.connections[&token];
+            match conn.state {
+                State::New { .. } => match Handshake::parse(&conn.incoming)? {
+                    Some(handshake) => Some(handshake),
+                    None => return Ok(()),
+                },
+                State::Connected(_) => None,
+            }
+        };
+        if let Some(handshake) = handshake {
+            self.attach(token, &handshake)?;
+        }
+
+        let info_hash = match self.connections[&token].state {
+            State::Connected(ref info_hash) => info_hash.clone(),
+            State::New { .. } => return Ok(()),
+        };
+        let conn = self.connections.get_mut(&token).unwrap();
+        let torrent = self.torrents.get_mut(&info_hash).expect("connected to a torrent");
+        let index = torrent.peer_index(token).expect("peer of its torrent");
+        let mut consumed = 0;
+        let (mut payload, mut overhead) = (0, 0);
+        while let Some((msg, len)) = peermsg::parse(&conn.incoming[consumed..])? {
+            consumed += len;
+            let replies = torrent.handle_message(index, &msg, &mut self.disk)?;
+            conn.send(replies);
+            if let Message::Piece(_, _, ref data) = msg {
+                payload += data.len();
+                overhead += len - data.len();
+            } else {
+                overhead += len;
+            }
+        }
+        conn.incoming.drain(..consumed);
+        conn.send(torrent.request_blocks(index));
+        ratelimit::record(&[&torrent.limits.download], payload, overhead);
+        ratelimit::record(&[&self.limits.download], payload, overhead);
+
+        let mut rejections = Vec::new();
+        let reads = torrent.peers[index].take_requests(
+            torrent.storage.layout(),
+            &torrent.have,
+            BLOCKS_PER_TURN,
+            &mut rejections,
+        );
+        conn.send(rejections);
+        for (piece, begin, length) in reads {
+            let job = DiskJob::Read {
+                token,
+                storage: torrent.storage.clone(),
+                piece,
+                begin,
+                length,
+            };
+            torrent.submit(&mut self.disk, job);
+        }
+        Ok(())
+    }
+
+    /// Deals with what the disk has finished, and returns the connections
+    /// with blocks to send.
+    fn handle_disk(&mut self) -> Vec<Token> {
+        let mut ready = Vec::new();
+        let now = Instant::now();
+        for result in self.disk.results() {
+            match result {
+                DiskResult::Written {
+                    info_hash,
+                    piece,
+                    begin,
+                    result,
+                } => if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.block_written(&mut self.disk, piece, begin, result);
+                },
+                DiskResult::Checked {
+                    info_hash,
+                    piece,
+                    result,
+                } => if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    let outgoing = torrent.piece_checked(piece, result);
+                    torrent.submit_checks(&mut self.disk);
+                    for (index, messages) in outgoing {
+                        let token = torrent.tokens[index];
+                        if let Some(conn) = self.connections.get_mut(&token) {
+                            conn.send(messages);
+                            ready.push(token);
+                        }
+                    }
+                },
+                DiskResult::Allocated { info_hash, result } => {
+                    if let (Some(torrent), Err(err)) = (self.torrents.get_mut(&info_hash), result)
+                    {
+                        torrent.disk_error = Some(err);
+                    }
+                }
+                DiskResult::Moved { info_hash, result } => {
+                    if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                        torrent.moved(&mut self.disk, result);
+                    }
+                }
+                DiskResult::Read {
+                    token,
+                    piece,
+                    begin,
+                    result,
+                } => match result {
+                    Ok(data) => if self.send_block(token, piece, begin, data, now) {
+                        ready.push(token);
+                    },
+                    Err(_) => self.close(token),
+                },
+            }
+        }
+        ready
+    }
+
+    // Queues a block we've read for a peer.  Returns false if the peer is
+    // gone, or doesn't get it after all.
+    fn send_block(
+        &mut self,
+        token: Token,
+        piece: u32,
+        begin: u32,
+        data: Block,
+        now: Instant,
+    ) -> bool {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return false,
+        };
+        let torrent = match conn.state {
+            State::Connected(ref info_hash) => self.torrents.get_mut(info_hash),
+            State::New { .. } => None,
+        };
+        let peer = torrent.and_then(|torrent| {
+            let index = torrent.peer_index(token)?;
+            Some(&mut torrent.peers[index])
+        });
+        match peer.and_then(|peer| peer.send_block(piece, begin, &data, now)) {
+            Some(header) => {
+                conn.outgoing.push_back(Outgoing::Block(header, data));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Matches a connection to its torrent, once its handshake is in.
+    fn attach(&mut self, token: Token, handshake: &Handshake) -> io::Result<()> {
+        let refuse = |msg| Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg));
+        if !self.torrents.contains_key(&handshake.info_hash) {
+            return refuse("unknown info hash");
+        }
+        let outgoing = match self.connections[&token].state {
+            State::New { ref outgoing } => outgoing.clone(),
+            State::Connected(_) => return Ok(()),
+        };
+        if outgoing.as_ref().map_or(false, |sent| *sent != handshake.info_hash) {
+            return refuse("peer answered for another torrent");
+        }
+        // This connection is already counted in the global total, so only
+        // the torrent's own limit can turn it away now.
+        let torrent = self.torrents.get_mut(&handshake.info_hash).unwrap();
+        if torrent.peers.len() >= self.max_connections_per_torrent {
+            return refuse("too many connections for this torrent");
+        }
+        let conn = self.connections.get_mut(&token).unwrap();
+        conn.incoming.drain(..68);
+        ratelimit::record(&[&torrent.limits.download, &self.limits.download], 0, 68);
+        if outgoing.is_none() {
+            conn.send(Some(peermsg::peer_handshake(&handshake.info_hash, &self.peer_id)));
+        }
+        let mut peer = Peer::new(conn.addr, handshake, torrent.have.len());
+        conn.send(peer.start(&torrent.have, torrent.info_hash.as_bytes()));
+        if peer.extended {
+            conn.send(Some(torrent.extensions.handshake(Some(conn.addr.ip()))));
+            torrent
+                .extensions
+                .peer_connected(token.0, conn.addr, outgoing.is_some());
+        }
+        conn.state = State::Connected(handshake.info_hash.clone());
+        torrent.peers.push(peer);
+        torrent.tokens.push(token);
+        Ok(())
+    }
+
+    fn tick(&mut self, now: Instant) {
+        if let Some(ref mut dht) = self.dht {
+            dht.tick(now);
+        }
+        let port = self.listener.local_addr().ok().map(|addr| addr.port());
+        for torrent in self.torrents.values_mut() {
+            if let Some(ref mut dht) = self.dht {
+                torrent.find_peers(dht, port, now);
+            }
+            torrent.submit_checks(&mut self.disk);
+            let seeding = torrent.is_seed();
+            let mut outgoing = torrent.choker.tick(&mut torrent.peers, seeding, now);
+            // Readers may be waiting on pieces we haven't asked for yet, or
+            // blocks may have been freed up.  Otherwise peers are topped up
+            // as their blocks come in.
+            let deadlines = torrent.waiters.deadlines();
+            let repick = torrent.repick || *torrent.picker.deadlines() != deadlines;
+            torrent.picker.set_deadlines(deadlines);
+            torrent.repick = false;
+            for index in 0..torrent.peers.len() {
+                let mut messages = if repick {
+                    torrent.request_blocks(index)
+                } else {
+                    Vec::new()
+                };
+                if torrent.peers[index].extended {
+                    let token = torrent.tokens[index].0;
+                    let remote = &torrent.peers[index].extensions;
+                    messages.extend(torrent.extensions.tick(token, remote, now));
+                }
+                outgoing.push((index, messages));
+            }
+            for (index, messages) in outgoing {
+                if messages.is_empty() {
+                    continue;
+                }
+                if let Some(conn) = self.connections.get_mut(&torrent.tokens[index]) {
+                    conn.send(messages);
+                    // Flushed next time round; make sure there is one.
+                    self.throttled.insert(torrent.tokens[index]);
+                }
+            }
+        }
+        // Connect to the peers we've heard of, while there's room.
+        let info_hashes: Vec<Vec<u8>> = self.torrents.keys().cloned().collect();
+        for info_hash in info_hashes {
+            while self.has_room(Some(&info_hash)) {
+                let addr = match self.torrents[&info_hash].queue.pop() {
+                    Some(addr) => addr,
+                    None => break,
+                };
+                if self.connect(&info_hash, addr).is_err() {
+                    self.torrents[&info_hash].queue.forget(&addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+    use std::path::PathBuf;
+    use extension::ExtendedHandshake;
+    use metadata::MetadataMessage;
+    use std::thread;
+    use storage::MemoryStorage;
+    use testutil::{read_torrent, TempDir};
+
+    fn pump(session: &mut Session, events: &mut Events) {
+        for _ in 0..5 {
+            session.poll_once(events, Duration::from_millis(20)).unwrap();
+        }
+    }
+
+    // Reads the next message off the wire, and its length there.
+    fn read_message(stream: &mut StdTcpStream) -> (Message, usize) {
+        let mut buf = Vec::new();
+        loop {
+            if let Some(parsed) = peermsg::parse(&buf).unwrap() {
+                return parsed;
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            buf.push(byte[0]);
+        }
+    }
+
+    // Reads our extended handshake, as sent after the opening messages.
+    fn read_extended_handshake(stream: &mut StdTcpStream) -> (ExtendedHandshake, usize) {
+        match read_message(stream) {
+            (Message::Extended(0, payload), len) => {
+                (ExtendedHandshake::decode(&payload).unwrap(), len)
+            }
+            (other, _) => panic!("Expected an extended handshake, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn route_handshakes_by_info_hash() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let arch = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
+        let mut config = SessionConfig::new("127.0.0.1:0".parse().unwrap());
+        config.max_connections = 2;
+        let mut session = Session::new(config).unwrap();
+        let dir = TempDir::new("session");
+        let hybrid_hash = session.add_torrent(&hybrid, &dir).unwrap();
+        let arch_hash = session.add_torrent(&arch, &dir).unwrap();
+        assert!(session.add_torrent(&arch, &dir).is_err());
+        session.torrent_mut(hybrid_hash.as_bytes()).unwrap().have.set(2, true);
+        let addr = session.local_addr().unwrap();
+        let mut events = Events::with_capacity(64);
+
+        let mut client = StdTcpStream::connect(addr).unwrap();
+        client
+            .write_all(&peermsg::peer_handshake(hybrid_hash.as_bytes(), &[b'c'; 20]))
+            .unwrap();
+        pump(&mut session, &mut events);
+        let mut reply = [0; 68 + 6];
+        client.read_exact(&mut reply).unwrap();
+        let handshake = Handshake::parse(&reply).unwrap().unwrap();
+        assert_eq!(handshake.info_hash, hybrid_hash.as_bytes());
+        assert_eq!(handshake.peer_id, session.peer_id());
+        // Then our bitfield: just the last of three pieces.
+        assert_eq!(&reply[68..], &[0, 0, 0, 2, 5, 0x20]);
+        assert_eq!(session.torrent(hybrid_hash.as_bytes()).unwrap().peers.len(), 1);
+        assert_eq!(session.torrent(arch_hash.as_bytes()).unwrap().peers.len(), 0);
+
+        // Nobody here by that info hash.
+        let mut stranger = StdTcpStream::connect(addr).unwrap();
+        stranger
+            .write_all(&peermsg::peer_handshake(&[9; 20], &[b's'; 20]))
+            .unwrap();
+        pump(&mut session, &mut events);
+        assert_eq!(stranger.read(&mut reply).unwrap(), 0);
+
+        // The global limit is two connections.
+        let _second = StdTcpStream::connect(addr).unwrap();
+        pump(&mut session, &mut events);
+        let mut third = StdTcpStream::connect(addr).unwrap();
+        pump(&mut session, &mut events);
+        assert_eq!(session.num_connections(), 2);
+        assert_eq!(third.read(&mut reply).unwrap(), 0);
+    }
+
+    #[test]
+    fn refuse_an_answer_for_another_torrent() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let arch = read_torrent("data/archlinux-2017.12.01-x86_64.iso.torrent");
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        let dir = TempDir::new("session-outgoing");
+        let hybrid_hash = session.add_torrent(&hybrid, &dir).unwrap();
+        let arch_hash = session.add_torrent(&arch, &dir).unwrap();
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let mut events = Events::with_capacity(64);
+        let mut answer = |session: &mut Session, info_hash: &[u8]| {
+            session
+                .connect(hybrid_hash.as_bytes(), listener.local_addr().unwrap())
+                .unwrap();
+            let (mut peer, _) = listener.accept().unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            pump(session, &mut events);
+            let mut handshake = [0; 68];
+            peer.read_exact(&mut handshake).unwrap();
+            assert_eq!(&handshake[28..48], hybrid_hash.as_bytes());
+            peer.write_all(&peermsg::peer_handshake(info_hash, &[b'p'; 20])).unwrap();
+            pump(session, &mut events);
+            peer
+        };
+
+        // We asked for one torrent, and the peer answered for the other.
+        let mut peer = answer(&mut session, arch_hash.as_bytes());
+        assert_eq!(peer.read(&mut [0; 16]).unwrap(), 0);
+        assert_eq!(session.torrent(arch_hash.as_bytes()).unwrap().peers.len(), 0);
+        assert_eq!(session.torrent(hybrid_hash.as_bytes()).unwrap().peers.len(), 0);
+
+        let _peer = answer(&mut session, hybrid_hash.as_bytes());
+        assert_eq!(session.torrent(hybrid_hash.as_bytes()).unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    fn connect_to_queued_peers_while_there_is_room() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let mut config = SessionConfig::new("127.0.0.1:0".parse().unwrap());
+        config.max_connections_per_torrent = 1;
+        let mut session = Session::new(config).unwrap();
+        let metainfo = OwnedMetaInfo::from_bytes(&hybrid).unwrap();
+        let storage = Arc::new(MemoryStorage::new(&metainfo.info).unwrap());
+        let info_hash = session
+            .add_torrent_with_storage(metainfo, storage, None)
+            .unwrap();
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let other = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let queue = session.torrent(info_hash.as_bytes()).unwrap().queue.clone();
+        queue.push(listener.local_addr().unwrap());
+        queue.push(other.local_addr().unwrap());
+        let mut events = Events::with_capacity(64);
+
+        // One goes out; the other waits, though the first hasn't answered.
+        pump(&mut session, &mut events);
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut handshake = [0; 68];
+        peer.read_exact(&mut handshake).unwrap();
+        assert_eq!(&handshake[28..48], info_hash.as_bytes());
+        assert_eq!(queue.len(), 1);
+
+        // Once it's gone, the next one gets its turn, and the first may be
+        // queued again.
+        drop(peer);
+        pump(&mut session, &mut events);
+        assert!(queue.is_empty());
+        other.accept().unwrap();
+        assert!(queue.push(listener.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn serve_blocks_through_the_disk_pool() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        let metainfo = OwnedMetaInfo::from_bytes(&hybrid).unwrap();
+        let info_length = metainfo.info_bytes().len();
+        let storage = Arc::new(MemoryStorage::new(&metainfo.info).unwrap());
+        storage.write_block(2, 0, &[b'x'; 100]).unwrap();
+        let info_hash = session
+            .add_torrent_with_storage(metainfo, storage, None)
+            .unwrap();
+        session.torrent_mut(info_hash.as_bytes()).unwrap().have.set(2, true);
+        let mut events = Events::with_capacity(64);
+
+        let mut client = StdTcpStream::connect(session.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+            .write_all(&peermsg::peer_handshake(info_hash.as_bytes(), &[b'c'; 20]))
+            .unwrap();
+        pump(&mut session, &mut events);
+        // Handshake, bitfield, and all three pieces allowed fast.
+        let mut reply = [0; 68 + 6 + 3 * 9];
+        client.read_exact(&mut reply).unwrap();
+        // Then the extended handshake, offering the metadata.
+        let (handshake, handshake_length) = read_extended_handshake(&mut client);
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&1));
+        assert_eq!(handshake.metadata_size, Some(info_length));
+        assert_eq!(handshake.p, Some(session.local_addr().unwrap().port()));
+        // All of it overhead, both ways, for the torrent and the session.
+        let sent = (reply.len() + handshake_length) as u64;
+        for limits in &[&session.torrent(info_hash.as_bytes()).unwrap().limits, session.limits()] {
+            let (down, up) = (limits.download.transferred(), limits.upload.transferred());
+            assert_eq!((down.payload, down.overhead), (0, 68));
+            assert_eq!((up.payload, up.overhead), (0, sent));
+        }
+        session.torrent_mut(info_hash.as_bytes()).unwrap().peers[0].unchoke();
+        client.write_all(&peermsg::request(2, 0, 100)).unwrap();
+        pump(&mut session, &mut events);
+        let mut piece = [0; 13 + 100];
+        client.read_exact(&mut piece).unwrap();
+        assert_eq!(&piece[..], &peermsg::piece(2, 0, &[b'x'; 100])[..]);
+
+        // Extended messages go to our extensions; ids we never gave out
+        // are ignored.
+        client.write_all(&peermsg::extended(0, b"d1:md11:ut_metadatai3eee")).unwrap();
+        client.write_all(&peermsg::extended(9, b"what")).unwrap();
+        let request = MetadataMessage::Request(0).encode();
+        client.write_all(&peermsg::extended(1, &request)).unwrap();
+        pump(&mut session, &mut events);
+        let info = session.torrent(info_hash.as_bytes()).unwrap().metainfo.info_bytes().to_vec();
+        match read_message(&mut client).0 {
+            Message::Extended(3, payload) => assert_eq!(
+                MetadataMessage::decode(&payload).unwrap(),
+                MetadataMessage::Data(0, info_length, info)
+            ),
+            other => panic!("Expected a ut_metadata message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn move_and_rename_files_while_seeding() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let dir = TempDir::new("session-move");
+        let moved = dir.join("elsewhere");
+        let renamed = PathBuf::from("hybrid-test/renamed/b.bin");
+        let resume = {
+            let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+                .unwrap();
+            let info_hash = session.add_torrent(&hybrid, dir.join("downloads")).unwrap();
+            {
+                let torrent = session.torrent_mut(info_hash.as_bytes()).unwrap();
+                torrent.storage.write_block(2, 0, &[b'x'; 100]).unwrap();
+                torrent.have.set(2, true);
+                assert!(torrent.rename_file(2, "../b.bin").is_err());
+                torrent.rename_file(2, &renamed).unwrap();
+            }
+            session.move_storage(info_hash.as_bytes(), &moved).unwrap();
+            {
+                // A block coming in mid-move waits for the move.
+                let torrent = session.torrents.get_mut(info_hash.as_bytes()).unwrap();
+                assert!(torrent.is_moving());
+                torrent.receive_block(&mut session.disk, 0, 0, &[b'y'; 16384]);
+                assert_eq!(torrent.held.as_ref().map(|held| held.len()), Some(1));
+            }
+            let mut events = Events::with_capacity(64);
+            while !session.disk.is_idle() {
+                session.poll_once(&mut events, Duration::from_millis(20)).unwrap();
+            }
+            {
+                let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+                assert!(torrent.disk_error().is_none() && !torrent.is_moving());
+                assert_eq!(torrent.storage.read_block(0, 0, 3).unwrap(), b"yyy");
+            }
+
+            // Still seeding, from the new place.
+            let mut client = StdTcpStream::connect(session.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client
+                .write_all(&peermsg::peer_handshake(info_hash.as_bytes(), &[b'c'; 20]))
+                .unwrap();
+            pump(&mut session, &mut events);
+            let mut reply = [0; 68 + 6 + 3 * 9];
+            client.read_exact(&mut reply).unwrap();
+            read_extended_handshake(&mut client);
+            session.torrent_mut(info_hash.as_bytes()).unwrap().peers[0].unchoke();
+            client.write_all(&peermsg::request(2, 0, 100)).unwrap();
+            pump(&mut session, &mut events);
+            let mut piece = [0; 13 + 100];
+            client.read_exact(&mut piece).unwrap();
+            assert_eq!(&piece[..], &peermsg::piece(2, 0, &[b'x'; 100])[..]);
+            session.torrent(info_hash.as_bytes()).unwrap().resume_data().unwrap()
+        };
+        let on_disk = moved.join(&renamed).exists() && !dir.join("downloads/hybrid-test").exists();
+        assert_eq!(resume.save_path, Some(moved.clone()));
+        assert_eq!(resume.renamed.get(&2), Some(&renamed));
+
+        // A restart finds the files where they went, with the same info hash.
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        let info_hash = session
+            .resume_torrent(&hybrid, dir.join("downloads"), Some(resume.clone()))
+            .unwrap();
+        let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+        let data = torrent.storage.read_block(2, 0, 100).unwrap();
+        let have = torrent.have.clone();
+        assert!(on_disk);
+        assert_eq!(info_hash, resume.info_hash);
+        assert_eq!(torrent.storage.path(), Some(moved));
+        assert_eq!(have, resume.pieces);
+        assert_eq!(data, vec![b'x'; 100]);
+    }
+
+    #[test]
+    fn trust_resume_data_only_while_files_are_unchanged() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let dir = TempDir::new("session-resume");
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let resume = {
+            let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+                .unwrap();
+            let info_hash = session.add_torrent(&hybrid, &dir).unwrap();
+            {
+                let torrent = session.torrents.get_mut(info_hash.as_bytes()).unwrap();
+                let disk = &mut session.disk;
+                torrent.receive_block(disk, 0, 0, &a[..16384]);
+                torrent.receive_block(disk, 0, 16384, &a[16384..32768]);
+                // Of piece 1, only the block that's all padding comes in.
+                torrent.receive_block(disk, 1, 16384, &[0; 16384]);
+            }
+            let mut events = Events::with_capacity(64);
+            while !session.disk.is_idle() {
+                session.poll_once(&mut events, Duration::from_millis(20)).unwrap();
+            }
+            let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+            assert!(torrent.have.get(0) && !torrent.have.get(1));
+            assert_eq!(torrent.partial.len(), 1);
+            torrent.resume_data().unwrap()
+        };
+
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        let info_hash = session
+            .resume_torrent(&hybrid, &dir, Some(resume.clone()))
+            .unwrap();
+        assert_eq!(session.torrent(info_hash.as_bytes()).unwrap().have, resume.pieces);
+        session.remove_torrent(info_hash.as_bytes());
+
+        // The rest of piece 1 turns up behind our back: recheck.
+        let metainfo = OwnedMetaInfo::from_bytes(&hybrid).unwrap();
+        let storage = FileStorage::new(&metainfo.info, &dir).unwrap();
+        storage.write_block(1, 0, &a[32768..]).unwrap();
+        session.resume_torrent(&hybrid, &dir, Some(resume.clone())).unwrap();
+        {
+            // Nothing is trusted until the disk threads have been through it.
+            let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+            assert!(torrent.is_checking() && torrent.have.none());
+        }
+        let mut events = Events::with_capacity(64);
+        while session.torrent(info_hash.as_bytes()).unwrap().is_checking() {
+            session.poll_once(&mut events, Duration::from_millis(20)).unwrap();
+        }
+        let (have, partial) = {
+            let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+            (torrent.have.clone(), torrent.partial.len())
+        };
+        session.remove_torrent(info_hash.as_bytes());
+
+        // Resume data for another torrent is ignored altogether.
+        let foreign = ResumeData {
+            info_hash: Sha1Hash::new(vec![9; 20]).unwrap(),
+            file_priority: vec![Priority::Skip; 3],
+            uploaded: 5,
+            ..resume
+        };
+        session.resume_torrent(&hybrid, &dir, Some(foreign)).unwrap();
+        let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+        assert!(have.get(0) && have.get(1) && !have.get(2));
+        assert_eq!(partial, 0);
+        assert_eq!(torrent.info_hash, info_hash);
+        assert!(torrent.have.none());
+        assert_eq!(torrent.uploaded(), 0);
+        assert_eq!(torrent.file_priorities(), &[Priority::Normal; 3][..]);
+    }
+
+    #[test]
+    fn import_a_libtorrent_resume_file() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let info_hash = OwnedMetaInfo::from_bytes(&hybrid).unwrap().info_hash();
+        let dir = TempDir::new("session-import");
+        let mut fastresume = b"d9:info-hash20:".to_vec();
+        fastresume.extend(info_hash.as_bytes());
+        fastresume.extend(b"6:pieces3:\x01\x00\x01");
+        let save_path = dir.to_str().unwrap();
+        fastresume.extend(format!("9:save_path{}:{}", save_path.len(), save_path).bytes());
+        fastresume.extend(b"14:total_uploadedi777ee");
+
+        // Nothing is on disk, so the pieces can't be there.
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        session.import_fastresume(&hybrid, &fastresume).unwrap();
+        let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+        assert!(torrent.have.none());
+        assert_eq!(torrent.uploaded(), 777);
+        assert_eq!(torrent.storage.path(), Some(dir.to_path_buf()));
+
+        // With every file at full length, they're trusted without a recheck.
+        let metainfo = OwnedMetaInfo::from_bytes(&hybrid).unwrap();
+        let storage = FileStorage::new(&metainfo.info, &dir).unwrap();
+        for file in 0..storage.layout().files().len() {
+            storage.allocate(file).unwrap();
+        }
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        session.import_fastresume(&hybrid, &fastresume).unwrap();
+        let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+        let have = torrent.have.clone();
+        assert!(have.get(0) && !have.get(1) && have.get(2));
+    }
+
+    #[test]
+    fn download_only_wanted_files() {
+        let hybrid = read_torrent("data/hybrid-test.torrent");
+        let dir = TempDir::new("session-download");
+        let mut session = Session::new(SessionConfig::new("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+        let info_hash = session.add_torrent(&hybrid, &dir).unwrap();
+        // Files are a.txt, its padding, and b.bin.
+        let mut reader = {
+            let torrent = session.torrent_mut(info_hash.as_bytes()).unwrap();
+            torrent.set_file_priority(2, Priority::Skip);
+            torrent.reader(0).unwrap()
+        };
+        reader.set_timeout(Some(Duration::from_secs(10)));
+        let streaming = thread::spawn(move || {
+            let mut a = Vec::new();
+            reader.read_to_end(&mut a).map(|_| a)
+        });
+        let mut events = Events::with_capacity(64);
+
+        // A seed: a.txt is a byte pattern, padded out to two pieces.
+        let mut data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        data.resize(65536, 0);
+        let mut seed = StdTcpStream::connect(session.local_addr().unwrap()).unwrap();
+        seed.write_all(&peermsg::peer_handshake(info_hash.as_bytes(), &[b's'; 20]))
+            .unwrap();
+        seed.write_all(&peermsg::bitfield(&BitField::full(3))).unwrap();
+        seed.write_all(&peermsg::unchoke()).unwrap();
+        seed.set_nonblocking(true).unwrap();
+
+        let mut incoming = Vec::new();
+        let mut handshake = true;
+        let mut requested = Vec::new();
+        let mut haves = Vec::new();
+        let mut interested = true;
+        for _ in 0..10 {
+            pump(&mut session, &mut events);
+            let mut buf = [0; 4096];
+            loop {
+                match seed.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => incoming.extend(&buf[..n]),
+                    Err(_) => break,
+                }
+            }
+            if handshake && incoming.len() >= 68 {
+                incoming.drain(..68);
+                handshake = false;
+            }
+            while let Some((msg, len)) = peermsg::parse(&incoming).unwrap() {
+                incoming.drain(..len);
+                match msg {
+                    Message::Request(piece, begin, length) => {
+                        requested.push(piece);
+                        let start = piece as usize * 32768 + begin as usize;
+                        let block = &data[start..start + length as usize];
+                        seed.write_all(&peermsg::piece(piece, begin, block)).unwrap();
+                    }
+                    Message::Have(piece) => haves.push(piece),
+                    Message::Interested => interested = true,
+                    Message::NotInterested => interested = false,
+                    _ => {}
+                }
+            }
+        }
+        let streamed = streaming.join().unwrap().unwrap();
+        let torrent = session.torrent(info_hash.as_bytes()).unwrap();
+        let have = torrent.have.clone();
+        let skipped = dir.join("hybrid-test/b.bin").exists();
+        let stats = torrent.cache_stats().unwrap();
+        assert_eq!(streamed, &data[..40000]);
+        assert!(!skipped);
+        // Whole pieces went to disk, and the reader was served from memory.
+        assert_eq!(stats.backend_writes, 2);
+        assert_eq!(stats.read_misses, 0);
+        assert_eq!(requested.len(), 4);
+        assert!(!requested.contains(&2));
+        // The seed heard about each piece, and that we want nothing more.
+        haves.sort();
+        assert_eq!(haves, vec![0, 1]);
+        assert!(!interested);
+        assert!(have.get(0) && have.get(1) && !have.get(2));
+        assert!(torrent.partial.is_empty());
+    }
+}
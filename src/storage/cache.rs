@@ -0,0 +1,481 @@
+//! A block cache in front of another backend.
+//!
+//! Blocks come in 16 KiB at a time, in whatever order peers send them.
+//! Rather than write each one where it lands, the cache holds them until
+//! their piece passes its hash check and writes the piece in one go.  Bad
+//! pieces are thrown away without touching the disk.
+//!
+//! Reads go the other way: the first block asked for from a piece reads
+//! the whole piece, since a peer that wants one block usually wants the
+//! rest.  Both halves are bounded, and the oldest pieces go first.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use resume::FileState;
+use super::{Layout, Storage};
+
+pub const DEFAULT_WRITE_CACHE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_READ_CACHE: usize = 32 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Bytes of unwritten blocks to hold.  Past this, the oldest pieces are
+    /// written out unchecked.
+    pub write_limit: usize,
+    /// Bytes of whole pieces to keep for reading.
+    pub read_limit: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            write_limit: DEFAULT_WRITE_CACHE,
+            read_limit: DEFAULT_READ_CACHE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads answered from memory.
+    pub read_hits: u64,
+    /// Reads that went to the backend.
+    pub read_misses: u64,
+    /// Blocks written to the cache.
+    pub blocks_written: u64,
+    /// Writes passed on to the backend.
+    pub backend_writes: u64,
+    /// Bytes waiting to be written.
+    pub dirty_bytes: usize,
+    /// Bytes of pieces kept for reading.
+    pub cached_bytes: usize,
+}
+
+impl CacheStats {
+    /// The share of reads answered from memory, from 0 to 1.
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.read_hits + self.read_misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.read_hits as f64 / reads as f64
+        }
+    }
+}
+
+/// A piece we have blocks of that the backend doesn't.
+struct DirtyPiece {
+    data: Vec<u8>,
+    // The ranges we have, as (start, end), in the order they came.
+    written: Vec<(usize, usize)>,
+}
+
+impl DirtyPiece {
+    /// The ranges written, sorted and merged, so each can go out as one
+    /// write.
+    fn runs(&self) -> Vec<(usize, usize)> {
+        let mut ranges = self.written.clone();
+        ranges.sort();
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match runs.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => runs.push((start, end)),
+            }
+        }
+        runs
+    }
+}
+
+struct Cache {
+    dirty: HashMap<u32, DirtyPiece>,
+    // Oldest first.
+    dirty_order: VecDeque<u32>,
+    clean: HashMap<u32, Vec<u8>>,
+    // Least recently used first.
+    clean_order: VecDeque<u32>,
+    // Bumped by every write, so a read-ahead that raced one isn't kept.
+    writes: u64,
+    stats: CacheStats,
+}
+
+/// The backend is only touched with the cache unlocked, so one slow read
+/// or write doesn't hold up every other disk job.
+pub struct CachedStorage {
+    inner: Box<Storage>,
+    config: CacheConfig,
+    cache: Mutex<Cache>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Box<Storage>, config: CacheConfig) -> CachedStorage {
+        CachedStorage {
+            inner,
+            config,
+            cache: Mutex::new(Cache {
+                dirty: HashMap::new(),
+                dirty_order: VecDeque::new(),
+                clean: HashMap::new(),
+                clean_order: VecDeque::new(),
+                writes: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats
+    }
+
+    /// Writes out a piece's blocks, if we're holding any, and with `keep`
+    /// keeps the piece for reading if we held all of it.
+    ///
+    /// The piece stays in the cache while it's written, so reads still
+    /// find it; if more blocks come in meanwhile, it stays dirty.
+    fn write_back(&self, piece: u32, keep: bool) -> io::Result<()> {
+        let (runs, data, count) = {
+            let cache = self.cache.lock().unwrap();
+            match cache.dirty.get(&piece) {
+                Some(dirty) => (dirty.runs(), dirty.data.clone(), dirty.written.len()),
+                None => return Ok(()),
+            }
+        };
+        for &(start, end) in &runs {
+            self.inner.write_block(piece, start as u32, &data[start..end])?;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        cache.stats.backend_writes += runs.len() as u64;
+        let unchanged = cache
+            .dirty
+            .get(&piece)
+            .map_or(false, |dirty| dirty.written.len() == count);
+        if unchanged {
+            cache.dirty.remove(&piece);
+            cache.dirty_order.retain(|&p| p != piece);
+            cache.stats.dirty_bytes -= data.len();
+            if keep && runs == [(0, data.len())] {
+                self.keep_clean(&mut cache, piece, data);
+            }
+        }
+        Ok(())
+    }
+
+    fn forget_clean(&self, cache: &mut Cache, piece: u32) {
+        if let Some(data) = cache.clean.remove(&piece) {
+            cache.clean_order.retain(|&p| p != piece);
+            cache.stats.cached_bytes -= data.len();
+        }
+    }
+
+    /// Keeps a whole piece for reading, making room as needed.
+    fn keep_clean(&self, cache: &mut Cache, piece: u32, data: Vec<u8>) {
+        self.forget_clean(cache, piece);
+        if data.len() > self.config.read_limit {
+            return;
+        }
+        while cache.stats.cached_bytes + data.len() > self.config.read_limit {
+            let oldest = cache.clean_order[0];
+            self.forget_clean(cache, oldest);
+        }
+        cache.stats.cached_bytes += data.len();
+        cache.clean.insert(piece, data);
+        cache.clean_order.push_back(piece);
+    }
+}
+
+impl Storage for CachedStorage {
+    fn layout(&self) -> &Layout {
+        self.inner.layout()
+    }
+
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        self.layout().spans(piece, begin, length)?;
+        let (from, to) = (begin as usize, begin as usize + length as usize);
+        let size = self.layout().piece_size(piece);
+        // What we hold of the block, to go on top of what's on disk.
+        let mut held = Vec::new();
+        let writes = {
+            let mut guard = self.cache.lock().unwrap();
+            let cache = &mut *guard;
+            if cache.clean.contains_key(&piece) {
+                cache.stats.read_hits += 1;
+                cache.clean_order.retain(|&p| p != piece);
+                cache.clean_order.push_back(piece);
+                return Ok(cache.clean[&piece][from..to].to_vec());
+            }
+            if let Some(dirty) = cache.dirty.get(&piece) {
+                let runs = dirty.runs();
+                if runs.iter().any(|&(start, end)| start <= from && to <= end) {
+                    cache.stats.read_hits += 1;
+                    return Ok(dirty.data[from..to].to_vec());
+                }
+                for (start, end) in runs {
+                    let (start, end) = (start.max(from), end.min(to));
+                    if start < end {
+                        held.push((start, dirty.data[start..end].to_vec()));
+                    }
+                }
+            }
+            cache.stats.read_misses += 1;
+            if cache.dirty.contains_key(&piece) || size as usize > self.config.read_limit {
+                None
+            } else {
+                Some(cache.writes)
+            }
+        };
+        let writes = match writes {
+            Some(writes) => writes,
+            None => {
+                // Part of it is only here, or it's too big to keep: read
+                // just what was asked for, with what we hold on top.
+                let mut data = self.inner.read_block(piece, begin, length)?;
+                for (start, block) in held {
+                    data[start - from..start - from + block.len()].copy_from_slice(&block);
+                }
+                return Ok(data);
+            }
+        };
+        let data = self.inner.read_block(piece, 0, size)?;
+        let block = data[from..to].to_vec();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.writes == writes {
+            self.keep_clean(&mut cache, piece, data);
+        }
+        Ok(block)
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        self.layout().spans(piece, begin, data.len() as u32)?;
+        let size = self.layout().piece_size(piece) as usize;
+        {
+            let mut cache = self.cache.lock().unwrap();
+            self.forget_clean(&mut cache, piece);
+            cache.writes += 1;
+            cache.stats.blocks_written += 1;
+            if size > self.config.write_limit {
+                cache.stats.backend_writes += 1;
+            }
+        }
+        if size > self.config.write_limit {
+            return self.inner.write_block(piece, begin, data);
+        }
+        loop {
+            let oldest = {
+                let mut guard = self.cache.lock().unwrap();
+                let cache = &mut *guard;
+                let fits = cache.stats.dirty_bytes + size <= self.config.write_limit;
+                if cache.dirty.contains_key(&piece) || fits {
+                    let Cache {
+                        ref mut dirty,
+                        ref mut dirty_order,
+                        ref mut stats,
+                        ..
+                    } = *cache;
+                    let dirty = dirty.entry(piece).or_insert_with(|| {
+                        dirty_order.push_back(piece);
+                        stats.dirty_bytes += size;
+                        DirtyPiece {
+                            data: vec![0; size],
+                            written: Vec::new(),
+                        }
+                    });
+                    let start = begin as usize;
+                    dirty.data[start..start + data.len()].copy_from_slice(data);
+                    dirty.written.push((start, start + data.len()));
+                    return Ok(());
+                }
+                cache.dirty_order[0]
+            };
+            self.write_back(oldest, false)?;
+        }
+    }
+
+    /// Writes out everything we're holding, checked or not, as when saving
+    /// resume data.
+    fn flush(&self) -> io::Result<()> {
+        let pieces: Vec<u32> = {
+            let cache = self.cache.lock().unwrap();
+            cache.dirty_order.iter().cloned().collect()
+        };
+        for piece in pieces {
+            self.write_back(piece, false)?;
+        }
+        self.inner.flush()
+    }
+
+    /// Good pieces are written out and kept for seeding; bad ones are
+    /// dropped.
+    fn piece_checked(&self, piece: u32, good: bool) -> io::Result<()> {
+        if good {
+            return self.write_back(piece, true);
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.dirty.remove(&piece).is_some() {
+            cache.dirty_order.retain(|&p| p != piece);
+            cache.stats.dirty_bytes -= self.layout().piece_size(piece) as usize;
+        }
+        self.forget_clean(&mut cache, piece);
+        Ok(())
+    }
+
+    fn allocate(&self, file: usize) -> io::Result<()> {
+        self.inner.allocate(file)
+    }
+
+    fn move_to(&self, path: &Path) -> io::Result<()> {
+        self.flush()?;
+        self.inner.move_to(path)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> io::Result<()> {
+        self.inner.rename_file(file, path)
+    }
+
+    fn file_path(&self, file: usize) -> Option<PathBuf> {
+        self.inner.file_path(file)
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.dirty.clear();
+            cache.dirty_order.clear();
+            cache.clean.clear();
+            cache.clean_order.clear();
+            cache.writes += 1;
+            cache.stats.dirty_bytes = 0;
+            cache.stats.cached_bytes = 0;
+        }
+        self.inner.delete()
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        self.inner.path()
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        self.inner.file_states()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use storage::MemoryStorage;
+    use testutil::read_torrent;
+
+    // Holds every read until the test lets it go.
+    struct Stalled {
+        inner: MemoryStorage,
+        barrier: Arc<Barrier>,
+    }
+
+    impl Storage for Stalled {
+        fn layout(&self) -> &Layout {
+            self.inner.layout()
+        }
+
+        fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+            self.barrier.wait();
+            self.barrier.wait();
+            self.inner.read_block(piece, begin, length)
+        }
+
+        fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+            self.inner.write_block(piece, begin, data)
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_to(&self, path: &Path) -> io::Result<()> {
+            self.inner.move_to(path)
+        }
+
+        fn delete(&self) -> io::Result<()> {
+            self.inner.delete()
+        }
+    }
+
+    #[test]
+    fn write_whole_pieces_and_read_ahead() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let backend = Arc::new(MemoryStorage::new(&mi.info).unwrap());
+        let config = CacheConfig {
+            write_limit: 32768,
+            read_limit: 65536,
+        };
+        let cache = CachedStorage::new(Box::new(backend.clone()), config);
+
+        // Blocks out of order stay in memory, and read back from there.
+        cache.write_block(0, 16384, &[2; 16384]).unwrap();
+        cache.write_block(0, 0, &[1; 16384]).unwrap();
+        assert_eq!(cache.read_block(0, 16380, 8).unwrap(), [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(backend.read_block(0, 0, 4).unwrap(), vec![0; 4]);
+        // Once checked, the piece goes out in one write.
+        cache.piece_checked(0, true).unwrap();
+        assert_eq!(backend.read_block(0, 16384, 4).unwrap(), vec![2; 4]);
+        let stats = cache.stats();
+        assert_eq!((stats.blocks_written, stats.backend_writes), (2, 1));
+        assert_eq!((stats.dirty_bytes, stats.cached_bytes), (0, 32768));
+
+        // A bad piece never reaches the backend.
+        cache.write_block(2, 0, &[9; 100]).unwrap();
+        cache.piece_checked(2, false).unwrap();
+        assert_eq!(cache.read_block(2, 0, 4).unwrap(), vec![0; 4]);
+
+        // Past the write limit, the oldest piece goes out unchecked.
+        cache.write_block(1, 0, &[3; 100]).unwrap();
+        cache.write_block(2, 0, &[4; 100]).unwrap();
+        assert_eq!(backend.read_block(1, 0, 4).unwrap(), vec![3; 4]);
+        assert_eq!(backend.read_block(2, 0, 4).unwrap(), vec![0; 4]);
+        cache.flush().unwrap();
+        assert_eq!(backend.read_block(2, 0, 4).unwrap(), vec![4; 4]);
+
+        // Reading one block reads the whole piece; the rest is a hit.
+        let before = cache.stats();
+        cache.read_block(1, 0, 16384).unwrap();
+        cache.read_block(1, 16384, 16384).unwrap();
+        let after = cache.stats();
+        assert_eq!(after.read_misses - before.read_misses, 1);
+        assert_eq!(after.read_hits - before.read_hits, 1);
+        assert_eq!(after.cached_bytes, 65536);
+        assert!(after.hit_rate() > 0.0);
+        // Piece 0, the least recently used, makes room for piece 2.
+        cache.read_block(2, 0, 100).unwrap();
+        assert!(!cache.cache.lock().unwrap().clean.contains_key(&0));
+    }
+
+    #[test]
+    fn backend_reads_dont_hold_the_cache() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let barrier = Arc::new(Barrier::new(2));
+        let backend = Stalled {
+            inner: MemoryStorage::new(&mi.info).unwrap(),
+            barrier: barrier.clone(),
+        };
+        let cache = Arc::new(CachedStorage::new(Box::new(backend), CacheConfig::default()));
+        let reader = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.read_block(1, 0, 4).unwrap())
+        };
+
+        // The read is stuck in the backend, and the cache still works.
+        barrier.wait();
+        cache.write_block(0, 0, &[5; 16384]).unwrap();
+        assert_eq!(cache.read_block(0, 0, 4).unwrap(), vec![5; 4]);
+        barrier.wait();
+        assert_eq!(reader.join().unwrap(), vec![0; 4]);
+        // A write went in while it read ahead, so the piece isn't kept.
+        assert_eq!(cache.stats().cached_bytes, 0);
+    }
+}
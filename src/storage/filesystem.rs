@@ -0,0 +1,180 @@
+//! Keeping a torrent's files on disk, under a download directory.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use metainfo::Info;
+use resume::FileState;
+use super::{DiskFiles, Layout, Storage};
+
+pub struct FileStorage {
+    layout: Layout,
+    // Writers lock this too, so nothing is read or written mid-move.
+    files: RwLock<DiskFiles>,
+}
+
+impl FileStorage {
+    /// Lays out a torrent's files under `base`.  Nothing is touched on
+    /// disk until the first write.
+    pub fn new<P: AsRef<Path>>(info: &Info, base: P) -> io::Result<FileStorage> {
+        let layout = Layout::new(info)?;
+        let files = DiskFiles::new(&layout, base.as_ref());
+        Ok(FileStorage {
+            layout,
+            files: RwLock::new(files),
+        })
+    }
+
+    pub fn base(&self) -> PathBuf {
+        self.files.read().unwrap().base.clone()
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Files not yet on disk, or too short, read as zeros.
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let spans = self.layout.spans(piece, begin, length)?;
+        let files = self.files.read().unwrap();
+        let mut data = vec![0; length as usize];
+        for span in spans {
+            let mut f = match File::open(files.path(span.file)) {
+                Ok(f) => f,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            f.seek(SeekFrom::Start(span.offset))?;
+            // A short file reads short; the rest stays zero.
+            let buf = &mut data[span.at..span.at + span.length];
+            let mut read = 0;
+            while read < buf.len() {
+                match f.read(&mut buf[read..])? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Creates files and directories as needed.
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = self.layout.spans(piece, begin, data.len() as u32)?;
+        let files = self.files.read().unwrap();
+        for span in spans {
+            let path = files.path(span.file);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            f.seek(SeekFrom::Start(span.offset))?;
+            f.write_all(&data[span.at..span.at + span.length])?;
+        }
+        Ok(())
+    }
+
+    /// Every write goes straight to its file, so there's nothing to do.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Creates the file at its full length.  Most filesystems leave the
+    /// unwritten parts sparse.
+    fn allocate(&self, file: usize) -> io::Result<()> {
+        let length = match self.layout.files().get(file) {
+            Some(layout_file) if !layout_file.padding => layout_file.length,
+            _ => return Ok(()),
+        };
+        let path = self.files.read().unwrap().path(file);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if f.metadata()?.len() < length {
+            f.set_len(length)?;
+        }
+        Ok(())
+    }
+
+    /// Renames each file into `path`, or copies it there if `path` is on
+    /// another filesystem.  Files not on disk yet are simply expected there
+    /// from now on.
+    fn move_to(&self, path: &Path) -> io::Result<()> {
+        self.files.write().unwrap().move_to(path)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> io::Result<()> {
+        self.files.write().unwrap().rename(file, path)
+    }
+
+    fn file_path(&self, file: usize) -> Option<PathBuf> {
+        let files = self.files.read().unwrap();
+        files.files.get(file).map(|file| file.path.clone())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.files.write().unwrap().delete()
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.base())
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        self.files.read().unwrap().states()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use testutil::{read_torrent, TempDir};
+
+    #[test]
+    fn pieces_across_files() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("storage");
+        let storage = FileStorage::new(&mi.info, &dir).unwrap();
+        assert!(storage.read_block(2, 0, 101).is_err());
+
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        storage.write_block(0, 0, &a[..32768]).unwrap();
+        storage.write_block(1, 0, &a[32768..]).unwrap();
+        storage.write_block(2, 0, &[b'x'; 100]).unwrap();
+        assert_eq!(storage.read_block(1, 7000, 232).unwrap(), &a[39768..]);
+        // Padding reads as zeros and never reaches the disk.
+        assert_eq!(storage.read_block(1, 7232, 16).unwrap(), vec![0; 16]);
+        assert_eq!(storage.read_block(2, 90, 10).unwrap(), vec![b'x'; 10]);
+        let on_disk = fs::read_dir(dir.join("hybrid-test")).unwrap().count();
+        assert_eq!(on_disk, 2);
+
+        let moved = dir.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert!(!dir.join("hybrid-test").exists());
+        assert_eq!(storage.read_block(2, 90, 10).unwrap(), vec![b'x'; 10]);
+        assert!(storage.rename_file(2, Path::new("/b.bin")).is_err());
+        assert!(storage.rename_file(1, Path::new("padding")).is_err());
+        storage.rename_file(2, Path::new("b.bin")).unwrap();
+        assert!(moved.join("b.bin").exists());
+        assert_eq!(storage.file_path(2), Some(PathBuf::from("b.bin")));
+        assert_eq!(storage.read_block(2, 90, 10).unwrap(), vec![b'x'; 10]);
+        storage.delete().unwrap();
+        let left = fs::read_dir(&moved).unwrap().count();
+        assert_eq!(left, 0);
+    }
+}
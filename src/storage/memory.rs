@@ -0,0 +1,139 @@
+//! Backends that never touch the disk: one that keeps pieces in memory,
+//! for tests, and one that throws them away, for benchmarks.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use metainfo::Info;
+use super::{Layout, Storage};
+
+/// Keeps each piece in memory once anything is written to it.
+pub struct MemoryStorage {
+    layout: Layout,
+    pieces: Mutex<HashMap<u32, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> io::Result<MemoryStorage> {
+        Ok(MemoryStorage {
+            layout: Layout::new(info)?,
+            pieces: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let spans = self.layout.spans(piece, begin, length)?;
+        let mut data = vec![0; length as usize];
+        if let Some(stored) = self.pieces.lock().unwrap().get(&piece) {
+            for span in spans {
+                let from = begin as usize + span.at;
+                data[span.at..span.at + span.length]
+                    .copy_from_slice(&stored[from..from + span.length]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = self.layout.spans(piece, begin, data.len() as u32)?;
+        let size = self.layout.piece_size(piece) as usize;
+        let mut pieces = self.pieces.lock().unwrap();
+        let stored = pieces.entry(piece).or_insert_with(|| vec![0; size]);
+        // Only the spans, so padding still reads as zeros.
+        for span in spans {
+            let to = begin as usize + span.at;
+            stored[to..to + span.length].copy_from_slice(&data[span.at..span.at + span.length]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// There's nowhere to move to; the pieces stay as they are.
+    fn move_to(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.pieces.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// Drops every write, and reads back zeros.
+pub struct NullStorage {
+    layout: Layout,
+}
+
+impl NullStorage {
+    pub fn new(info: &Info) -> io::Result<NullStorage> {
+        Ok(NullStorage {
+            layout: Layout::new(info)?,
+        })
+    }
+}
+
+impl Storage for NullStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        self.layout.spans(piece, begin, length)?;
+        Ok(vec![0; length as usize])
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        self.layout.spans(piece, begin, data.len() as u32).map(|_| ())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use testutil::read_torrent;
+
+    #[test]
+    fn memory_keeps_what_null_drops() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let memory = MemoryStorage::new(&mi.info).unwrap();
+        let null = NullStorage::new(&mi.info).unwrap();
+        let block = vec![7; 8000];
+        for storage in &[&memory as &Storage, &null] {
+            assert!(storage.write_block(2, 0, &[1; 101]).is_err());
+            storage.write_block(1, 0, &block).unwrap();
+            assert_eq!(storage.read_block(0, 0, 4).unwrap(), vec![0; 4]);
+        }
+        // The last 768 bytes were padding, which isn't kept.
+        let read = memory.read_block(1, 0, 8000).unwrap();
+        assert_eq!(&read[..7232], &block[..7232]);
+        assert_eq!(&read[7232..], &[0; 768][..]);
+        assert_eq!(null.read_block(1, 0, 8000).unwrap(), vec![0; 8000]);
+        memory.delete().unwrap();
+        assert_eq!(memory.read_block(1, 0, 4).unwrap(), vec![0; 4]);
+    }
+}
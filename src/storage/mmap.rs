@@ -0,0 +1,327 @@
+//! Keeping a torrent's files on disk, mapped into memory.
+//!
+//! Reads and writes are plain copies to and from the mappings, with no
+//! system call per block, which pays off when seeding many torrents from a
+//! machine with memory to spare.  A block for a peer that lies in one file
+//! isn't copied at all: it goes out as a range of the file's mapping, which
+//! stays mapped until the block is sent.
+//!
+//! Each file is mapped read-only the first time it's read, at the length it
+//! has on disk, and for writing, at its full length, the first time it's
+//! written.  Both mappings share the page cache, so reads see writes.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use memmap::{Mmap, MmapMut, MmapOptions};
+
+use metainfo::Info;
+use resume::FileState;
+use super::{Block, DiskFiles, Layout, Span, Storage};
+
+struct Mapped {
+    files: DiskFiles,
+    // One per file in the layout, for writing; None until the file is
+    // written, and for files that are empty or padding.
+    maps: Vec<Option<MmapMut>>,
+    // The same for reading, shared with blocks on their way to peers; None
+    // until the file is read, and for files that are empty, missing or
+    // padding.
+    views: Vec<Option<Arc<Mmap>>>,
+}
+
+impl Mapped {
+    /// Writes out and drops every mapping, before the files move.
+    fn unmap(&mut self) -> io::Result<()> {
+        for map in self.maps.iter_mut() {
+            if let Some(map) = map.take() {
+                map.flush()?;
+            }
+        }
+        for view in self.views.iter_mut() {
+            *view = None;
+        }
+        Ok(())
+    }
+}
+
+pub struct MmapStorage {
+    layout: Layout,
+    state: RwLock<Mapped>,
+}
+
+/// Maps one of the files for writing, creating it if need be and
+/// extending it to its full length first.
+fn map_file(files: &DiskFiles, file: usize) -> io::Result<Option<MmapMut>> {
+    let length = files.files[file].length;
+    if files.files[file].padding || length == 0 {
+        return Ok(None);
+    }
+    let path = files.path(file);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    if f.metadata()?.len() < length {
+        f.set_len(length)?;
+    }
+    // Safe as long as nothing else truncates the file while it's mapped.
+    let map = unsafe { MmapOptions::new().len(length as usize).map_mut(&f)? };
+    Ok(Some(map))
+}
+
+/// Maps one of the files for reading, as long as it is on disk.  A missing
+/// file stays unmapped.
+fn view_file(files: &DiskFiles, file: usize) -> io::Result<Option<Arc<Mmap>>> {
+    let length = files.files[file].length;
+    if files.files[file].padding || length == 0 {
+        return Ok(None);
+    }
+    let f = match File::open(files.path(file)) {
+        Ok(f) => f,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let len = cmp::min(f.metadata()?.len(), length);
+    if len == 0 {
+        return Ok(None);
+    }
+    // Safe as long as nothing else truncates the file while it's mapped.
+    let view = unsafe { MmapOptions::new().len(len as usize).map(&f)? };
+    Ok(Some(Arc::new(view)))
+}
+
+impl MmapStorage {
+    /// Lays out a torrent's files under `base`.  Nothing is touched on
+    /// disk until the first write.
+    pub fn new<P: AsRef<Path>>(info: &Info, base: P) -> io::Result<MmapStorage> {
+        let layout = Layout::new(info)?;
+        let files = DiskFiles::new(&layout, base.as_ref());
+        let maps = layout.files().iter().map(|_| None).collect();
+        let views = layout.files().iter().map(|_| None).collect();
+        Ok(MmapStorage {
+            layout,
+            state: RwLock::new(Mapped { files, maps, views }),
+        })
+    }
+
+    pub fn base(&self) -> PathBuf {
+        self.state.read().unwrap().files.base.clone()
+    }
+
+    // Maps the files under `spans` for reading, where they aren't yet.
+    fn view(&self, spans: &[Span]) -> io::Result<()> {
+        let unmapped = {
+            let state = self.state.read().unwrap();
+            spans.iter().any(|span| state.views[span.file].is_none())
+        };
+        if unmapped {
+            let mut state = self.state.write().unwrap();
+            let state = &mut *state;
+            for span in spans {
+                if state.views[span.file].is_none() {
+                    state.views[span.file] = view_file(&state.files, span.file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Copies the block out of the mappings.  Files not yet on disk, or
+    /// too short, read as zeros.
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let spans = self.layout.spans(piece, begin, length)?;
+        self.view(&spans)?;
+        let state = self.state.read().unwrap();
+        let mut data = vec![0; length as usize];
+        for span in spans {
+            let view = match state.views[span.file] {
+                Some(ref view) => view,
+                None => continue,
+            };
+            let from = span.offset as usize;
+            if from < view.len() {
+                let n = cmp::min(span.length, view.len() - from);
+                data[span.at..span.at + n].copy_from_slice(&view[from..from + n]);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Shares out the block as a range of its file's mapping, when it lies
+    /// in one file that's on disk that far; otherwise copies it.
+    fn read_shared(&self, piece: u32, begin: u32, length: u32) -> io::Result<Block> {
+        let spans = self.layout.spans(piece, begin, length)?;
+        if spans.len() == 1 {
+            self.view(&spans)?;
+            let state = self.state.read().unwrap();
+            let span = &spans[0];
+            let from = span.offset as usize;
+            if let Some(ref view) = state.views[span.file] {
+                if from + span.length <= view.len() {
+                    return Ok(Block::Shared(view.clone(), from..from + span.length));
+                }
+            }
+        }
+        self.read_block(piece, begin, length).map(Block::Owned)
+    }
+
+    /// Creates files at their full length as they're first written.
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = self.layout.spans(piece, begin, data.len() as u32)?;
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        for span in spans {
+            let end = span.offset as usize + span.length;
+            let short = state.maps[span.file]
+                .as_ref()
+                .map_or(true, |map| map.len() < end);
+            if short {
+                // The file may be longer now, so it's viewed again too.
+                state.maps[span.file] = None;
+                state.maps[span.file] = map_file(&state.files, span.file)?;
+                state.views[span.file] = None;
+            }
+            if let Some(ref mut map) = state.maps[span.file] {
+                let from = span.offset as usize;
+                map[from..end].copy_from_slice(&data[span.at..span.at + span.length]);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let state = self.state.read().unwrap();
+        for map in state.maps.iter().filter_map(|map| map.as_ref()) {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Creates the file at its full length, and maps all of it.
+    fn allocate(&self, file: usize) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        if file < state.maps.len() {
+            state.maps[file] = map_file(&state.files, file)?;
+            state.views[file] = None;
+        }
+        Ok(())
+    }
+
+    /// Writes out and drops every mapping, then moves the files; they're
+    /// mapped again from their new place as they're used.
+    fn move_to(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        state.unmap()?;
+        state.files.move_to(path)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        state.unmap()?;
+        state.files.rename(file, path)
+    }
+
+    fn file_path(&self, file: usize) -> Option<PathBuf> {
+        let state = self.state.read().unwrap();
+        state.files.files.get(file).map(|file| file.path.clone())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        for map in state.maps.iter_mut() {
+            *map = None;
+        }
+        for view in state.views.iter_mut() {
+            *view = None;
+        }
+        state.files.delete()
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.base())
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        self.state.read().unwrap().files.states()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use std::io::{Read, Write};
+    use testutil::{read_torrent, TempDir};
+
+    #[test]
+    fn pieces_span_mapped_files() {
+        let b = read_torrent("data/redox-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("mmap");
+        let storage = MmapStorage::new(&mi.info, &dir).unwrap();
+        let cookbook = dir.join("redox/redox/cookbook");
+        fs::create_dir_all(&cookbook).unwrap();
+        File::create(cookbook.join("setup.sh"))
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+
+        // Piece 57 ends the installer, then runs through five small files.
+        // setup.sh is mapped as it is, and the rest of it reads as zeros.
+        let short = storage.read_block(57, 378464, 658).unwrap();
+        assert_eq!(&short[..10], b"0123456789");
+        assert_eq!(&short[10..], &[0; 648][..]);
+
+        let a: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        storage.write_block(57, 378364, &a).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.read_block(57, 378364, 16384).unwrap(), a);
+        let mut setup = vec![];
+        File::open(cookbook.join("setup.sh"))
+            .unwrap()
+            .read_to_end(&mut setup)
+            .unwrap();
+        assert_eq!(setup, &a[100..758]);
+        let installer = dir.join("redox/redox/installer/target/debug/redox_installer");
+        assert_eq!(fs::metadata(&installer).unwrap().len(), 30262880);
+        assert!(!cookbook.join("libc-artifacts/usr/lib/libc.a").exists());
+
+        // A block in one file is shared out of its mapping, and outlives
+        // the move; one across files is copied.
+        let shared = storage.read_shared(57, 0, 16384).unwrap();
+        let copy = storage.read_block(57, 0, 16384).unwrap();
+        match shared {
+            Block::Shared(..) => assert_eq!(&shared[..], &copy[..]),
+            Block::Owned(_) => panic!("block in one file copied"),
+        }
+        match storage.read_shared(57, 378364, 16384).unwrap() {
+            Block::Owned(data) => assert_eq!(data, a),
+            Block::Shared(..) => panic!("block across files shared"),
+        }
+
+        let moved = dir.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert!(!installer.exists());
+        assert_eq!(&shared[..], &copy[..]);
+        assert_eq!(storage.read_block(57, 378364, 16384).unwrap(), a);
+        storage.delete().unwrap();
+        let left = fs::read_dir(&moved).unwrap().count();
+        assert_eq!(left, 0);
+    }
+}
@@ -0,0 +1,568 @@
+//! Where torrent data is kept.
+//!
+//! A torrent's pieces run across its files end to end, as if they were one
+//! long file.  `Layout` maps piece offsets back to files.  Padding files
+//! (BEP 47), and the gaps v2 torrents leave to start each file on a piece
+//! boundary, read as zeros and are never stored.
+//!
+//! Where the bytes actually go is up to a `Storage` backend: files on disk,
+//! read and written directly or mapped into memory, memory for tests,
+//! nowhere at all for benchmarks, or anything else that implements the
+//! trait.
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::ops::{Deref, Range};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use metainfo::Info;
+use resume::FileState;
+
+pub mod cache;
+pub mod filesystem;
+pub mod memory;
+pub mod mmap;
+
+pub use self::cache::{CacheConfig, CacheStats, CachedStorage};
+pub use self::filesystem::FileStorage;
+pub use self::memory::{MemoryStorage, NullStorage};
+pub use self::mmap::MmapStorage;
+
+/// One file of a torrent, and where it sits in the torrent's data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageFile {
+    /// Relative to the download directory.
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub padding: bool,
+}
+
+/// The part of a block that falls in one file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// Index into the layout's files.
+    pub file: usize,
+    /// Where the span starts in the file.
+    pub offset: u64,
+    /// Where the span starts in the block.
+    pub at: usize,
+    pub length: usize,
+}
+
+/// How a torrent's pieces map onto its files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Joins torrent path components, refusing any that would escape the
+/// download directory.
+fn safe_path<S: AsRef<str>>(components: &[S]) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in components {
+        let component = component.as_ref();
+        let mut parts = Path::new(component).components();
+        match (parts.next(), parts.next()) {
+            (Some(Component::Normal(_)), None) => path.push(component),
+            _ => return Err(invalid("bad path in torrent")),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        Err(invalid("empty path in torrent"))
+    } else {
+        Ok(path)
+    }
+}
+
+// Piece lengths we accept; sizes and offsets within a piece are u32s.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 1 << 31;
+
+impl Layout {
+    /// Fails for lengths no real torrent has: a piece length that isn't a
+    /// power of two from 16 KiB to 2 GiB, or files that add up to more
+    /// than we can count.
+    pub fn new(info: &Info) -> io::Result<Layout> {
+        let piece_length = info.piece_length();
+        if !piece_length.is_power_of_two()
+            || !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length)
+        {
+            return Err(invalid("bad piece length"));
+        }
+        let too_long = || invalid("torrent is too long");
+        let name = safe_path(&[info.name()])?;
+        let mut files = Vec::new();
+        let mut offset = 0;
+        match *info {
+            Info::MiInfo(ref info) => files.push(StorageFile {
+                path: name,
+                offset: 0,
+                length: info.length,
+                padding: false,
+            }),
+            Info::MiMultiInfo(ref info) => for file in &info.files {
+                files.push(StorageFile {
+                    path: name.join(safe_path(&file.path)?),
+                    offset,
+                    length: file.length,
+                    padding: file.is_padding(),
+                });
+                offset = offset.checked_add(file.length).ok_or_else(too_long)?;
+            },
+            Info::MiV2Info(ref info) => for file in &info.file_tree {
+                files.push(StorageFile {
+                    path: name.join(safe_path(&file.path)?),
+                    offset,
+                    length: file.length,
+                    padding: false,
+                });
+                // Every v2 file starts on a piece boundary.
+                let pieces = file.length
+                    .checked_add(piece_length - 1)
+                    .ok_or_else(too_long)? / piece_length;
+                offset = pieces
+                    .checked_mul(piece_length)
+                    .and_then(|length| offset.checked_add(length))
+                    .ok_or_else(too_long)?;
+            },
+        }
+        let total_length = match files.last() {
+            Some(file) => file.offset.checked_add(file.length).ok_or_else(too_long)?,
+            None => 0,
+        };
+        // Piece indexes are u32s too.
+        if total_length / piece_length > u32::MAX as u64 {
+            return Err(too_long());
+        }
+        Ok(Layout {
+            files,
+            piece_length,
+            total_length,
+        })
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    /// The files a piece overlaps, by index, not counting padding.
+    pub fn piece_files(&self, piece: u32) -> Vec<usize> {
+        let start = piece as u64 * self.piece_length;
+        let end = start + self.piece_size(piece) as u64;
+        (0..self.files.len())
+            .filter(|&i| {
+                let file = &self.files[i];
+                !file.padding && file.offset < end && file.offset + file.length > start
+            })
+            .collect()
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        ((self.total_length + self.piece_length - 1) / self.piece_length) as usize
+    }
+
+    /// The length of a piece; only the last one may be short.
+    pub fn piece_size(&self, piece: u32) -> u32 {
+        let start = piece as u64 * self.piece_length;
+        cmp::min(self.piece_length, self.total_length.saturating_sub(start)) as u32
+    }
+
+    /// Splits `length` bytes at `begin` in `piece` by file.  Padding and
+    /// gaps are left out; fails if the range runs past the piece.
+    pub fn spans(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<Span>> {
+        if begin as u64 + length as u64 > self.piece_size(piece) as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "range outside piece"));
+        }
+        let start = piece as u64 * self.piece_length + begin as u64;
+        let end = start + length as u64;
+        Ok((0..self.files.len())
+            .filter(|&i| {
+                let file = &self.files[i];
+                !file.padding && file.offset < end && file.offset + file.length > start
+            })
+            .map(|i| {
+                let file = &self.files[i];
+                let from = cmp::max(start, file.offset);
+                let to = cmp::min(end, file.offset + file.length);
+                Span {
+                    file: i,
+                    offset: from - file.offset,
+                    at: (from - start) as usize,
+                    length: (to - from) as usize,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Removes the directories `path` is in, up to `base`, as long as they're
+/// empty.
+fn remove_empty_dirs(base: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == base || !d.starts_with(base) || fs::remove_dir(d).is_err() {
+            return;
+        }
+        dir = d.parent();
+    }
+}
+
+// What a rename fails with across filesystems: EXDEV, or on Windows,
+// ERROR_NOT_SAME_DEVICE.
+#[cfg(not(windows))]
+const CROSS_DEVICE: i32 = 18;
+#[cfg(windows)]
+const CROSS_DEVICE: i32 = 17;
+
+/// Moves a file, making its directory if need be.  Across filesystems,
+/// where a rename can't work, the file is copied and the original deleted.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(ref err) if err.raw_os_error() == Some(CROSS_DEVICE) => {}
+        Err(err) => return Err(err),
+    }
+    if let Err(err) = fs::copy(from, to) {
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    fs::remove_file(from)
+}
+
+/// Where a backend keeps a layout's files on disk: under a download
+/// directory, each at its path in the layout until it's renamed.
+struct DiskFiles {
+    base: PathBuf,
+    files: Vec<StorageFile>,
+}
+
+impl DiskFiles {
+    fn new(layout: &Layout, base: &Path) -> DiskFiles {
+        DiskFiles {
+            base: base.to_path_buf(),
+            files: layout.files().to_vec(),
+        }
+    }
+
+    fn path(&self, file: usize) -> PathBuf {
+        self.base.join(&self.files[file].path)
+    }
+
+    /// Moves the files to under `to`.  Files not on disk yet are simply
+    /// expected there from now on.  Nothing is moved if anything is in the
+    /// way, and if a move fails part way, the files moved so far go back.
+    fn move_to(&mut self, to: &Path) -> io::Result<()> {
+        if to == self.base {
+            return Ok(());
+        }
+        let moves: Vec<(PathBuf, PathBuf)> = self.files
+            .iter()
+            .filter(|file| !file.padding)
+            .map(|file| (self.base.join(&file.path), to.join(&file.path)))
+            .filter(|&(ref from, _)| from.exists())
+            .collect();
+        if moves.iter().any(|&(_, ref dest)| dest.exists()) {
+            let msg = "a file is in the way at the new location";
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        }
+        for (i, &(ref from, ref dest)) in moves.iter().enumerate() {
+            if let Err(err) = move_file(from, dest) {
+                for &(ref from, ref dest) in &moves[..i] {
+                    let _ = move_file(dest, from);
+                    remove_empty_dirs(to, dest);
+                }
+                return Err(err);
+            }
+            remove_empty_dirs(&self.base, from);
+        }
+        self.base = to.to_path_buf();
+        Ok(())
+    }
+
+    /// Renames a file, relative to the download directory.  A file not on
+    /// disk yet is simply expected under its new name from now on.
+    fn rename(&mut self, file: usize, path: &Path) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        match self.files.get(file) {
+            Some(file) if !file.padding => {}
+            _ => return Err(invalid("no such file")),
+        }
+        let normal = path.components().all(|component| match component {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+        if !normal || path.as_os_str().is_empty() {
+            return Err(invalid("bad file name"));
+        }
+        if self.files[file].path == path {
+            return Ok(());
+        }
+        if self.files.iter().any(|other| other.path == path) {
+            return Err(invalid("another file has that name"));
+        }
+        let (from, to) = (self.path(file), self.base.join(path));
+        if from.exists() {
+            if to.exists() {
+                let msg = "a file is in the way";
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+            }
+            move_file(&from, &to)?;
+            remove_empty_dirs(&self.base, &from);
+        }
+        self.files[file].path = path.to_path_buf();
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        for i in (0..self.files.len()).filter(|&i| !self.files[i].padding) {
+            let path = self.path(i);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+            remove_empty_dirs(&self.base, &path);
+        }
+        Ok(())
+    }
+
+    /// The state of the files, as they are on disk now.
+    fn states(&self) -> io::Result<Vec<FileState>> {
+        let mut states = Vec::with_capacity(self.files.len());
+        for (i, file) in self.files.iter().enumerate() {
+            if file.padding {
+                states.push(FileState::default());
+                continue;
+            }
+            let meta = match fs::metadata(self.path(i)) {
+                Ok(meta) => meta,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    states.push(FileState::default());
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let mtime = meta.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0);
+            states.push(FileState {
+                size: meta.len(),
+                mtime,
+            });
+        }
+        Ok(states)
+    }
+}
+
+/// A block read to send to a peer: a copy of its own, or part of
+/// something the storage shares out, like a file's mapping.
+pub enum Block {
+    Owned(Vec<u8>),
+    Shared(Arc<AsRef<[u8]> + Send + Sync>, Range<usize>),
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Block::Owned(ref data) => data,
+            Block::Shared(ref data, ref range) => &(**data).as_ref()[range.clone()],
+        }
+    }
+}
+
+/// Somewhere to keep a torrent's pieces.  Backends are shared between the
+/// session and readers on other threads, so they take `&self` throughout.
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &Layout;
+
+    /// Reads part of a piece.  Anything never written reads as zeros.
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>>;
+
+    /// Reads a block to send to a peer.  Backends that can share out their
+    /// data, rather than copy it, do.
+    fn read_shared(&self, piece: u32, begin: u32, length: u32) -> io::Result<Block> {
+        self.read_block(piece, begin, length).map(Block::Owned)
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()>;
+
+    /// Makes sure everything written so far is stored.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Hears whether a piece passed its hash check, once all its blocks
+    /// are written.
+    fn piece_checked(&self, _piece: u32, _good: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets aside the full size of a file up front, where that means
+    /// anything.
+    fn allocate(&self, _file: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves the data to a new download directory.
+    fn move_to(&self, path: &Path) -> io::Result<()>;
+
+    /// Gives a file a new path, relative to the download directory, for
+    /// backends that have files.
+    fn rename_file(&self, _file: usize, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Where a file is kept, relative to the download directory: its path
+    /// in the layout, unless it's been renamed.
+    fn file_path(&self, _file: usize) -> Option<PathBuf> {
+        None
+    }
+
+    /// Deletes the data.
+    fn delete(&self) -> io::Result<()>;
+
+    /// The download directory, for backends that have one.
+    fn path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// The size and modification time of each file, for checking resume
+    /// data.  Backends without files report them all as missing, so their
+    /// resume data is taken on trust.
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        Ok(vec![FileState::default(); self.layout().files().len()])
+    }
+}
+
+/// A shared backend is still a backend.
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn layout(&self) -> &Layout {
+        (**self).layout()
+    }
+
+    fn read_block(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        (**self).read_block(piece, begin, length)
+    }
+
+    fn read_shared(&self, piece: u32, begin: u32, length: u32) -> io::Result<Block> {
+        (**self).read_shared(piece, begin, length)
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        (**self).write_block(piece, begin, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn piece_checked(&self, piece: u32, good: bool) -> io::Result<()> {
+        (**self).piece_checked(piece, good)
+    }
+
+    fn allocate(&self, file: usize) -> io::Result<()> {
+        (**self).allocate(file)
+    }
+
+    fn move_to(&self, path: &Path) -> io::Result<()> {
+        (**self).move_to(path)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> io::Result<()> {
+        (**self).rename_file(file, path)
+    }
+
+    fn file_path(&self, file: usize) -> Option<PathBuf> {
+        (**self).file_path(file)
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        (**self).delete()
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        (**self).path()
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        (**self).file_states()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::MetaInfo;
+    use testutil::read_torrent;
+
+    #[test]
+    fn spans_skip_padding() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let layout = Layout::new(&mi.info).unwrap();
+        // a.txt is padded out to two pieces, and b.bin fills the third.
+        assert_eq!(layout.num_pieces(), 3);
+        assert_eq!(layout.piece_size(2), 100);
+        assert_eq!(layout.piece_files(1), vec![0]);
+        assert!(layout.spans(2, 0, 101).is_err());
+        assert_eq!(
+            layout.spans(1, 7000, 1000).unwrap(),
+            vec![Span {
+                file: 0,
+                offset: 39768,
+                at: 0,
+                length: 232,
+            }]
+        );
+        assert_eq!(layout.spans(1, 7232, 16).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn refuse_impossible_lengths() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let multi = match mi.info {
+            Info::MiMultiInfo(ref info) => info.clone(),
+            ref other => panic!("Expected multi-file info, got {:?}", other),
+        };
+        for &piece_length in &[0, 1000, 8192, 3 << 20, 1 << 32] {
+            let mut info = multi.clone();
+            info.piece_length = piece_length;
+            assert!(Layout::new(&Info::MiMultiInfo(info)).is_err());
+        }
+        let mut info = multi.clone();
+        info.files[2].length = u64::MAX;
+        assert!(Layout::new(&Info::MiMultiInfo(info)).is_err());
+        // Short of overflowing, but more than 2^32 pieces.
+        let mut info = multi;
+        info.files[2].length = 1 << 50;
+        assert!(Layout::new(&Info::MiMultiInfo(info)).is_err());
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_download_directory() {
+        assert!(safe_path(&["a", "b"]).is_ok());
+        assert!(safe_path(&["..", "etc"]).is_err());
+        assert!(safe_path(&["/etc"]).is_err());
+        assert!(safe_path(&["a/b"]).is_err());
+        assert!(safe_path::<&str>(&[]).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the tests.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reads one of the test torrents, by its path from the crate root.
+pub fn read_torrent(path: &str) -> Vec<u8> {
+    let mut b = vec![];
+    File::open(path).unwrap().read_to_end(&mut b).expect("read");
+    b
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory under the system's temp dir that no other test, or run,
+/// shares.  It isn't created, and it's removed, with everything in it,
+/// when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let name = format!("rottenbrit-{}-{}-{}", name, process::id(), n);
+        TempDir(env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,151 @@
+//! Checking pieces on disk against the torrent's hashes.
+//!
+//! v1 and hybrid torrents are checked against the SHA-1 `pieces`.  v2-only
+//! torrents have no such list, so each piece is checked against its file's
+//! merkle tree instead.
+
+use std::cmp;
+use std::io;
+
+use sha1::Sha1;
+
+use bitfield::BitField;
+use merkle::{FileHashes, PieceCheck};
+use metainfo::{Info, MetaInfo, Sha1Hash};
+use storage::{Layout, Storage};
+
+enum Hashes {
+    V1(Vec<Sha1Hash>),
+    /// Each file's tree, with the piece it starts at and its index in the
+    /// layout.
+    V2(Vec<(u32, usize, FileHashes)>),
+}
+
+pub struct PieceVerifier {
+    hashes: Hashes,
+    piece_length: u64,
+}
+
+impl PieceVerifier {
+    /// Fails if a v2 file's piece layer doesn't match its root.
+    pub fn new(metainfo: &MetaInfo, layout: &Layout) -> io::Result<PieceVerifier> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let piece_length = metainfo.info.piece_length();
+        let hashes = match metainfo.info {
+            Info::MiInfo(ref info) => Hashes::V1(info.pieces.clone()),
+            Info::MiMultiInfo(ref info) => Hashes::V1(info.pieces.clone()),
+            Info::MiV2Info(_) => {
+                let mut files = Vec::new();
+                // v2 files are laid out in file tree order.
+                let tree = metainfo.info.file_tree().unwrap_or(&[]);
+                for (index, (file, stored)) in tree.iter().zip(layout.files()).enumerate() {
+                    let root = match file.pieces_root {
+                        Some(ref root) => root,
+                        None => continue,
+                    };
+                    let layer = metainfo.piece_layer(root).unwrap_or(&[]);
+                    let hashes = FileHashes::new(root.clone(), file.length, piece_length, layer)
+                        .ok_or_else(|| invalid("piece layer does not match pieces root"))?;
+                    files.push(((stored.offset / piece_length) as u32, index, hashes));
+                }
+                Hashes::V2(files)
+            }
+        };
+        Ok(PieceVerifier {
+            hashes,
+            piece_length,
+        })
+    }
+
+    /// Reads a piece back from storage and checks it.
+    pub fn check(&self, storage: &Storage, piece: u32) -> io::Result<bool> {
+        match self.hashes {
+            Hashes::V1(ref pieces) => {
+                let expected = match pieces.get(piece as usize) {
+                    Some(expected) => expected,
+                    None => return Ok(false),
+                };
+                let data = storage.read_block(piece, 0, storage.layout().piece_size(piece))?;
+                let mut sha = Sha1::new();
+                sha.update(&data);
+                Ok(sha.digest().bytes() == expected.as_bytes())
+            }
+            Hashes::V2(ref files) => {
+                let found = files.iter().find(|&&(first, _, ref hashes)| {
+                    piece >= first && ((piece - first) as usize) < hashes.piece_count()
+                });
+                let (first, index, hashes) = match found {
+                    Some(&(first, index, ref hashes)) => (first, index, hashes),
+                    // Pieces of empty files have nothing to check.
+                    None => return Ok(false),
+                };
+                // The file's last piece stops at the end of the file, not
+                // at the end of the piece.  An empty file can start at the
+                // same offset as this one, so go by index.
+                let file = &storage.layout().files()[index];
+                let start = (piece - first) as u64 * self.piece_length;
+                let length = cmp::min(self.piece_length, file.length - start) as u32;
+                let data = storage.read_block(piece, 0, length)?;
+                Ok(hashes.check_piece((piece - first) as usize, &data) == PieceCheck::Valid)
+            }
+        }
+    }
+
+    /// Checks every piece, and returns those that are good.
+    pub fn recheck(&self, storage: &Storage) -> io::Result<BitField> {
+        let num_pieces = storage.layout().num_pieces();
+        let mut have = BitField::new(num_pieces);
+        for piece in 0..num_pieces {
+            if self.check(storage, piece as u32)? {
+                have.set(piece, true);
+            }
+        }
+        Ok(have)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::{FileStorage, MemoryStorage};
+    use testutil::{read_torrent, TempDir};
+
+    #[test]
+    fn recheck_finds_good_pieces() {
+        let b = read_torrent("data/hybrid-test.torrent");
+        let mi = MetaInfo::from_bytes(&b).expect("deserialize");
+        let dir = TempDir::new("verify");
+        let storage = FileStorage::new(&mi.info, &dir).unwrap();
+        let verifier = PieceVerifier::new(&mi, storage.layout()).unwrap();
+        assert!(verifier.recheck(&storage).unwrap().none());
+
+        // The test torrent's files hold a repeating byte pattern.
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        storage.write_block(0, 0, &a[..32768]).unwrap();
+        storage.write_block(1, 0, &a[32768..]).unwrap();
+        let have = verifier.recheck(&storage).unwrap();
+        assert!(have.get(0) && have.get(1) && !have.get(2));
+    }
+
+    #[test]
+    fn recheck_v2_only_with_an_empty_file() {
+        // The v2 test torrent, with an empty file that shares a.txt's
+        // offset ahead of it.
+        let b = read_torrent("data/v2-test.torrent");
+        let at = b.windows(5).position(|w| w == b"a.txt").unwrap() - 2;
+        let mut b2 = b[..at].to_vec();
+        b2.extend(&b"7:a-emptyd0:d6:lengthi0eee"[..]);
+        b2.extend(&b[at..]);
+        let mi = MetaInfo::from_bytes(&b2).expect("deserialize");
+        let storage = MemoryStorage::new(&mi.info).unwrap();
+        assert_eq!(storage.layout().files()[0].length, 0);
+        assert_eq!(storage.layout().files()[1].offset, 0);
+        let verifier = PieceVerifier::new(&mi, storage.layout()).unwrap();
+
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        storage.write_block(0, 0, &a[..32768]).unwrap();
+        storage.write_block(1, 0, &a[32768..]).unwrap();
+        let have = verifier.recheck(&storage).unwrap();
+        assert!(have.get(0) && have.get(1) && !have.get(2));
+    }
+}